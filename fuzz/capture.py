@@ -0,0 +1,181 @@
+#!/usr/bin/env python3
+# Captures seeds for the decrypt_stream fuzz target from real tcp sessions.
+#
+# Each session runs a real client against a real service through a local proxy that frames the
+# traffic the way box-tunnel does: every read from a plaintext connection becomes one packet, and
+# a half-close becomes the final header. The packets are sealed with the key and nonce of the
+# decrypt_stream target and sent over a loopback connection, and the sizes in which they arrive
+# there become the read schedule of the seed.
+#
+# Streams of an actual box-tunnel can not be used as seeds directly, since their keys are derived
+# per connection. This produces streams with the same framing under the key and nonce of the
+# target instead.
+#
+# Usage: python3 capture.py corpus/decrypt_stream
+
+import ctypes
+import ctypes.util
+import http.client
+import http.server
+import os
+import shutil
+import socket
+import sys
+import tempfile
+import threading
+
+sodium = ctypes.CDLL(ctypes.util.find_library('sodium'))
+assert sodium.sodium_init() >= 0
+
+MAX_PACKET_SIZE = 4096
+# The key and nonce hardcoded in fuzz_targets/decrypt_stream.rs.
+KEY = bytes(range(1, 33))
+NONCE = bytes(range(100, 124))
+
+
+def secretbox(msg, nonce, key):
+    out = ctypes.create_string_buffer(len(msg) + 16)
+    assert sodium.crypto_secretbox_easy(out, msg, ctypes.c_ulonglong(len(msg)), nonce, key) == 0
+    return out.raw
+
+
+def inc(nonce, by=1):
+    n = int.from_bytes(nonce, 'big') + by
+    return (n % (1 << 192)).to_bytes(24, 'big')
+
+
+class Sealer:
+    def __init__(self):
+        self.nonce = NONCE
+
+    def packet(self, plain):
+        body = secretbox(plain, inc(self.nonce), KEY)
+        header = secretbox(len(plain).to_bytes(2, 'big') + body[:16], self.nonce, KEY)
+        self.nonce = inc(self.nonce, 2)
+        return header + body[16:]
+
+    def goodbye(self):
+        return secretbox(bytes(18), self.nonce, KEY)
+
+
+def loopback():
+    listener = socket.socket()
+    listener.bind(('127.0.0.1', 0))
+    listener.listen(1)
+    sender = socket.create_connection(listener.getsockname())
+    receiver, _ = listener.accept()
+    listener.close()
+    return sender, receiver
+
+
+# Forward one direction of a session, sealing every read from `source` into `tunnel`.
+def forward(source, sink, tunnel):
+    sealer = Sealer()
+    while True:
+        data = source.recv(MAX_PACKET_SIZE)
+        if not data:
+            break
+        tunnel.sendall(sealer.packet(data))
+        sink.sendall(data)
+    tunnel.sendall(sealer.goodbye())
+    tunnel.close()
+    try:
+        sink.shutdown(socket.SHUT_WR)
+    except OSError:
+        pass
+
+
+# Receive a box stream, returning it together with the sizes in which it arrived.
+def receive(tunnel, result):
+    stream, sizes = b'', []
+    while True:
+        data = tunnel.recv(65536)
+        if not data:
+            break
+        stream += data
+        sizes.append(len(data))
+    result.append((stream, sizes))
+
+
+# Run `session` against the service at `address` through the proxy, and return the captured
+# streams of the requests and the responses.
+def capture(address, session):
+    listener = socket.socket()
+    listener.bind(('127.0.0.1', 0))
+    listener.listen(1)
+
+    captured = {'requests': [], 'responses': []}
+
+    def proxy():
+        client, _ = listener.accept()
+        service = socket.create_connection(address)
+        threads = []
+        for name, source, sink in [('requests', client, service), ('responses', service, client)]:
+            sender, receiver = loopback()
+            threads.append(threading.Thread(target=forward, args=(source, sink, sender)))
+            threads.append(threading.Thread(target=receive, args=(receiver, captured[name])))
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+        client.close()
+        service.close()
+
+    thread = threading.Thread(target=proxy)
+    thread.start()
+    session(listener.getsockname())
+    thread.join()
+    listener.close()
+    return captured['requests'][0], captured['responses'][0]
+
+
+def seed(stream, sizes):
+    # Read sizes the target can not limit to are passed through unlimited.
+    schedule = bytes(min(size, 255) for size in sizes[:255])
+    return bytes([len(schedule)]) + schedule + stream
+
+
+def http_session(method, path):
+    def session(address):
+        connection = http.client.HTTPConnection(*address)
+        connection.request(method, path)
+        connection.getresponse().read()
+        connection.close()
+    return session
+
+
+def main(out):
+    root = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+    served = tempfile.mkdtemp()
+    for name in ['README.md', 'LICENSE', 'src/decryptor.rs']:
+        shutil.copy(os.path.join(root, name), served)
+
+    class Handler(http.server.SimpleHTTPRequestHandler):
+        def __init__(self, *args, **kwargs):
+            super().__init__(*args, directory=served, **kwargs)
+
+        def log_message(self, *args):
+            pass
+
+    server = http.server.ThreadingHTTPServer(('127.0.0.1', 0), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+
+    sessions = [
+        ('http-get', http_session('GET', '/README.md')),
+        ('http-get-large', http_session('GET', '/decryptor.rs')),
+        ('http-head', http_session('HEAD', '/LICENSE')),
+        ('http-listing', http_session('GET', '/')),
+        ('http-not-found', http_session('GET', '/missing')),
+    ]
+    for name, session in sessions:
+        for direction, captured in zip(['request', 'response'],
+                                       capture(server.server_address, session)):
+            with open(os.path.join(out, 'session-%s-%s' % (name, direction)), 'wb') as f:
+                f.write(seed(*captured))
+
+    server.shutdown()
+    shutil.rmtree(served)
+
+
+if __name__ == '__main__':
+    main(sys.argv[1])
@@ -0,0 +1,137 @@
+//! Compares the packet primitives of the `crypto` module, which are
+//! implemented in C by box-stream-c, to the protocol composed in Rust from
+//! `sodiumoxide::crypto::secretbox`: sealing arbitrary packets must produce the
+//! same bytes, and both implementations must agree on opening the packet, as
+//! well as a copy with an arbitrary bit flipped.
+//!
+//! sodiumoxide wraps the same libsodium that box-stream-c uses, so this only
+//! checks how packets are composed, not the cipher itself.
+//!
+//! The input is the key (32 bytes), the nonce (24 bytes), the index of the bit
+//! to flip as a big-endian u16, and the plaintext (truncated to 4096 bytes).
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate box_stream;
+extern crate sodiumoxide;
+
+use std::cmp::min;
+
+use box_stream::{INVALID_LENGTH, UNAUTHENTICATED_HEADER, UNAUTHENTICATED_PACKET};
+use box_stream::crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_USIZE, PlainHeader, decrypt_header,
+                         decrypt_packet, encrypt_packet, final_header, packet_nonce};
+use sodiumoxide::crypto::secretbox;
+
+// Interpret `nonce` as a big-endian integer and increment it, wrapping around.
+fn increment(nonce: &secretbox::Nonce) -> secretbox::Nonce {
+    let mut result = nonce.0;
+    for byte in result.iter_mut().rev() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+    secretbox::Nonce(result)
+}
+
+fn seal_rust(plain: &[u8], key: &secretbox::Key, nonce: &secretbox::Nonce) -> Vec<u8> {
+    let body = secretbox::seal(plain, &increment(nonce), key);
+
+    let mut header = vec![(plain.len() >> 8) as u8, plain.len() as u8];
+    header.extend_from_slice(&body[..secretbox::MACBYTES]);
+
+    let mut cypher = secretbox::seal(&header, nonce, key);
+    cypher.extend_from_slice(&body[secretbox::MACBYTES..]);
+    cypher
+}
+
+fn open_rust(cypher: &[u8],
+             key: &secretbox::Key,
+             nonce: &secretbox::Nonce)
+             -> Result<Vec<u8>, &'static str> {
+    let header = secretbox::open(&cypher[..CYPHER_HEADER_SIZE], nonce, key)
+        .map_err(|_| UNAUTHENTICATED_HEADER)?;
+    let len = (header[0] as usize) << 8 | header[1] as usize;
+    if len != cypher.len() - CYPHER_HEADER_SIZE {
+        return Err(INVALID_LENGTH);
+    }
+
+    let mut body = header[2..].to_vec();
+    body.extend_from_slice(&cypher[CYPHER_HEADER_SIZE..]);
+    secretbox::open(&body, &increment(nonce), key).map_err(|_| UNAUTHENTICATED_PACKET)
+}
+
+fn open_c(cypher: &[u8],
+          key: &secretbox::Key,
+          nonce: &secretbox::Nonce)
+          -> Result<Vec<u8>, &'static str> {
+    let mut nonce = nonce.0;
+    let mut plain_header = PlainHeader::new();
+    if !unsafe {
+            decrypt_header(&mut plain_header,
+                           &*(cypher.as_ptr() as *const [u8; CYPHER_HEADER_SIZE]),
+                           &key.0,
+                           &mut nonce)
+        } {
+        return Err(UNAUTHENTICATED_HEADER);
+    }
+
+    let len = plain_header.get_packet_len() as usize;
+    if len != cypher.len() - CYPHER_HEADER_SIZE {
+        return Err(INVALID_LENGTH);
+    }
+
+    let mut plain = vec![0; len];
+    if unsafe {
+           decrypt_packet(plain.as_mut_ptr(),
+                          cypher[CYPHER_HEADER_SIZE..].as_ptr(),
+                          &plain_header,
+                          &key.0,
+                          &mut nonce)
+       } {
+        Ok(plain)
+    } else {
+        Err(UNAUTHENTICATED_PACKET)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 58 {
+        return;
+    }
+
+    let key = secretbox::Key::from_slice(&data[..32]).unwrap();
+    let nonce = secretbox::Nonce::from_slice(&data[32..56]).unwrap();
+    let flip = (data[56] as usize) << 8 | data[57] as usize;
+    let plain = &data[58..min(data.len(), 58 + MAX_PACKET_USIZE)];
+
+    let mut cypher = vec![0; CYPHER_HEADER_SIZE + plain.len()];
+    let mut next_nonce = nonce.0;
+    unsafe {
+        encrypt_packet(cypher.as_mut_ptr(),
+                       plain.as_ptr(),
+                       plain.len() as u16,
+                       &key.0,
+                       &mut next_nonce);
+    }
+    assert_eq!(cypher, seal_rust(plain, &key, &nonce));
+    assert_eq!(secretbox::Nonce(next_nonce), increment(&increment(&nonce)));
+    assert_eq!(next_nonce, packet_nonce(&nonce.0, 1));
+
+    let mut goodbye = [0; CYPHER_HEADER_SIZE];
+    unsafe {
+        final_header(&mut goodbye, &key.0, &next_nonce);
+    }
+    assert_eq!(&goodbye[..],
+               &secretbox::seal(&[0; 18], &secretbox::Nonce(next_nonce), &key)[..]);
+
+    assert_eq!(open_c(&cypher, &key, &nonce), Ok(plain.to_vec()));
+    assert_eq!(open_rust(&cypher, &key, &nonce), Ok(plain.to_vec()));
+
+    let flip = flip % (cypher.len() * 8);
+    cypher[flip / 8] ^= 1 << (flip % 8);
+    let opened = open_c(&cypher, &key, &nonce);
+    assert!(opened.is_err());
+    assert_eq!(opened, open_rust(&cypher, &key, &nonce));
+});
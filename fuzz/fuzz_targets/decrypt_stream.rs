@@ -0,0 +1,65 @@
+//! Feeds an arbitrary box stream into a `BoxReader`, delivered by an inner
+//! reader that returns arbitrarily sized chunks and spurious `Pending`s, and
+//! checks that the result agrees with `open_all`.
+//!
+//! The input is a byte `n`, followed by `n` bytes that each control one read
+//! of the inner reader, followed by the box stream.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate box_stream;
+extern crate futures;
+extern crate sodiumoxide;
+
+use std::cmp::min;
+use std::io::Cursor;
+
+use box_stream::*;
+use box_stream::testing::{Fault, FaultyReader};
+use futures::executor::block_on;
+use futures::io::AsyncReadExt;
+use sodiumoxide::crypto::secretbox;
+
+// The key and nonce of the vector corpus, so that the seeds authenticate.
+const KEY: [u8; 32] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21,
+                       22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32];
+const NONCE: [u8; 24] = [100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111, 112, 113,
+                         114, 115, 116, 117, 118, 119, 120, 121, 122, 123];
+
+fn fault(control: u8) -> Fault {
+    match control {
+        0 => Fault::Pending,
+        255 => Fault::Unlimited,
+        n => Fault::Limited(n as usize),
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+
+    let split = min(1 + data[0] as usize, data.len());
+    let schedule = data[1..split].iter().cloned().map(fault).collect();
+    let cypher = &data[split..];
+
+    let key = secretbox::Key(KEY);
+    let nonce = secretbox::Nonce(NONCE);
+
+    let reader = BoxReader::new(FaultyReader::new(Cursor::new(cypher.to_vec()), schedule),
+                                key.clone(),
+                                nonce.clone());
+    let read = block_on(reader.read_to_end(Vec::new()));
+
+    match (read, open_all(cypher, &key, &nonce)) {
+        (Ok((_, plain)), Ok(expected)) => assert_eq!(plain, expected),
+        (Ok((reader, _)), Err(e)) => {
+            // A BoxReader stops at the final header without looking at the data after it.
+            assert_eq!(e.to_string(), TRAILING_DATA);
+            assert!(reader.get_ref().get_ref().position() < cypher.len() as u64);
+        }
+        (Err(e), Ok(_)) => panic!("BoxReader rejected a stream accepted by open_all: {}", e),
+        (Err(e), Err(expected)) => assert_eq!(e.to_string(), expected.to_string()),
+    }
+});
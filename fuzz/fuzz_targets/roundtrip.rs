@@ -0,0 +1,75 @@
+//! Writes arbitrary data through a `BoxWriter` and reads it back with a
+//! `BoxReader`, both wrapping inner readers and writers that process
+//! arbitrarily sized chunks and return spurious `Pending`s.
+//!
+//! The input is the initial nonce (24 bytes), one byte each controlling the
+//! reads and writes of the inner writer and reader, and then the writes, each
+//! prefixed with its length as a big-endian u16.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate box_stream;
+extern crate futures;
+extern crate sodiumoxide;
+
+use std::cmp::min;
+use std::io::Cursor;
+
+use box_stream::*;
+use box_stream::crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_USIZE};
+use box_stream::testing::{Fault, FaultyReader, FaultyWriter};
+use futures::executor::block_on;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use sodiumoxide::crypto::secretbox;
+
+fn schedule(control: u8) -> Vec<Fault> {
+    (0..64)
+        .map(|i| match control.wrapping_mul(i) % 8 {
+                 0 => Fault::Pending,
+                 n => Fault::Limited(control as usize * n as usize + 1),
+             })
+        .collect()
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 26 {
+        return;
+    }
+
+    let key = secretbox::Key([42; 32]);
+    let nonce = secretbox::Nonce::from_slice(&data[..24]).unwrap();
+
+    let mut writes = Vec::new();
+    let mut rest = &data[26..];
+    while rest.len() >= 2 {
+        let len = min((rest[0] as usize) << 8 | rest[1] as usize, rest.len() - 2);
+        writes.push(rest[2..2 + len].to_vec());
+        rest = &rest[2 + len..];
+    }
+
+    let mut writer = BoxWriter::new(FaultyWriter::new(Cursor::new(Vec::new()),
+                                                      schedule(data[24])),
+                                    key.clone(),
+                                    nonce.clone());
+    for write in writes.iter() {
+        writer = block_on(writer.write_all(write.clone())).unwrap().0;
+    }
+    let writer = block_on(writer.close()).unwrap();
+    let cypher = writer.into_inner().into_inner().into_inner();
+
+    let plain: Vec<u8> = writes.iter().flat_map(|write| write.iter().cloned()).collect();
+    let packets: usize = writes
+        .iter()
+        .map(|write| (write.len() + MAX_PACKET_USIZE - 1) / MAX_PACKET_USIZE)
+        .sum();
+    assert_eq!(cypher.len(), plain.len() + (packets + 1) * CYPHER_HEADER_SIZE);
+
+    let reader = BoxReader::new(FaultyReader::new(Cursor::new(cypher.clone()),
+                                                  schedule(data[25])),
+                                key.clone(),
+                                nonce.clone());
+    let (_, read) = block_on(reader.read_to_end(Vec::new())).unwrap();
+    assert_eq!(read, plain);
+    assert_eq!(open_all(&cypher, &key, &nonce).unwrap(), plain);
+});
@@ -0,0 +1,354 @@
+//! Encrypt and decrypt box streams from the command line.
+//!
+//! Run `box-stream help` for usage.
+
+extern crate box_stream;
+extern crate sodiumoxide;
+
+#[path = "../common/mod.rs"]
+mod common;
+mod pcap;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::process::exit;
+
+use box_stream::crypto::CYPHER_HEADER_SIZE;
+use box_stream::{Inspected, SyncBoxReader, SyncBoxWriter, INVALID_LENGTH, TRAILING_DATA,
+                 UNAUTHENTICATED_EOF, inspect};
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+
+use common::*;
+use pcap::Flow;
+
+const USAGE: &'static str = "\
+usage: box-stream encrypt [options]
+       box-stream decrypt [options]
+       box-stream inspect [options] [--pcap <path> [--flow <flow>]] [--preview <bytes>]
+
+Encrypts plaintext into a box stream, decrypts and authenticates a box stream, or
+prints every header and packet of a box stream for debugging.
+
+options:
+    --key <key>           the key, as hex or base64
+    --key-file <path>     read the key from a file (raw, hex or base64)
+    --key-env <var>       read the key from an environment variable (hex or base64)
+    --nonce <nonce>       the initial nonce, as hex or base64
+    --nonce-file <path>   read the nonce from a file (raw, hex or base64)
+    --nonce-env <var>     read the nonce from an environment variable (hex or base64)
+    --in <path>           read from a file instead of stdin
+    --out <path>          write to a file instead of stdout
+
+A key is required. When encrypting without a nonce, a random one is used and
+printed to stderr as hex.
+
+inspect options:
+    --pcap <path>         read the box stream from a tcp flow in a pcap or pcapng
+                          capture instead, the flows in the capture are listed if
+                          no flow is given
+    --flow <flow>         the flow to inspect, e.g. 10.0.0.1:8008-10.0.0.2:51234
+    --preview <bytes>     how much of each packet body to print (default 32)
+
+Decrypted data is written as soon as it has been authenticated, so on failure the
+output contains the plaintext preceding the failure.
+
+exit codes:
+    0    success
+    1    invalid arguments
+    2    i/o error
+    3    data failed to authenticate
+    4    box stream ended without a final header";
+
+struct Options {
+    key: Option<Secret>,
+    nonce: Option<Secret>,
+    input: Option<String>,
+    output: Option<String>,
+    pcap: Option<String>,
+    flow: Option<Flow>,
+    preview: usize,
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_else(|| usage_error("missing command", USAGE));
+    let args: Vec<String> = args.collect();
+
+    match command.as_str() {
+        "encrypt" => encrypt(parse_options(&args, false)),
+        "decrypt" => decrypt(parse_options(&args, false)),
+        "inspect" => inspect_stream(parse_options(&args, true)),
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => usage_error(&format!("unknown command {}", command), USAGE),
+    }
+}
+
+fn parse_options(args: &[String], inspect: bool) -> Options {
+    let mut options = Options {
+        key: None,
+        nonce: None,
+        input: None,
+        output: None,
+        pcap: None,
+        flow: None,
+        preview: 32,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next()
+            .unwrap_or_else(|| usage_error(&format!("missing value for {}", arg), USAGE))
+            .clone();
+
+        match arg.as_str() {
+            "--key" => options.key = Some(Secret::Value(value)),
+            "--key-file" => options.key = Some(Secret::File(value)),
+            "--key-env" => options.key = Some(Secret::Env(value)),
+            "--nonce" => options.nonce = Some(Secret::Value(value)),
+            "--nonce-file" => options.nonce = Some(Secret::File(value)),
+            "--nonce-env" => options.nonce = Some(Secret::Env(value)),
+            "--in" => options.input = Some(value),
+            "--out" => options.output = Some(value),
+            "--pcap" if inspect => options.pcap = Some(value),
+            "--flow" if inspect => {
+                options.flow = Some(Flow::parse(&value)
+                                        .unwrap_or_else(|| {
+                                                            usage_error(&format!("invalid flow {}",
+                                                                                 value),
+                                                                        USAGE)
+                                                        }))
+            }
+            "--preview" if inspect => {
+                options.preview = value
+                    .parse()
+                    .unwrap_or_else(|_| usage_error("invalid preview length", USAGE))
+            }
+            _ => usage_error(&format!("unknown option {}", arg), USAGE),
+        }
+    }
+
+    options
+}
+
+fn load_key(options: &Options) -> secretbox::Key {
+    let key = options
+        .key
+        .as_ref()
+        .unwrap_or_else(|| usage_error("missing key", USAGE))
+        .load("key", secretbox::KEYBYTES)
+        .unwrap_or_else(|e| usage_error(&e, USAGE));
+    secretbox::Key::from_slice(&key).unwrap()
+}
+
+fn load_nonce(nonce: &Secret) -> secretbox::Nonce {
+    let nonce = nonce
+        .load("nonce", secretbox::NONCEBYTES)
+        .unwrap_or_else(|e| usage_error(&e, USAGE));
+    secretbox::Nonce::from_slice(&nonce).unwrap()
+}
+
+fn open_input(options: &Options) -> Box<Read> {
+    match options.input {
+        Some(ref path) => Box::new(File::open(path).unwrap_or_else(|e| exit_with(&e))),
+        None => Box::new(io::stdin()),
+    }
+}
+
+fn open_output(options: &Options) -> Box<Write> {
+    match options.output {
+        Some(ref path) => Box::new(File::create(path).unwrap_or_else(|e| exit_with(&e))),
+        None => Box::new(io::stdout()),
+    }
+}
+
+fn encrypt(options: Options) {
+    let key = load_key(&options);
+    let nonce = match options.nonce {
+        Some(ref nonce) => load_nonce(nonce),
+        None => {
+            let nonce = secretbox::gen_nonce();
+            eprintln!("nonce: {}", encode_hex(&nonce.0));
+            nonce
+        }
+    };
+
+    let mut input = open_input(&options);
+    let mut writer = SyncBoxWriter::new(open_output(&options), key, nonce);
+
+    if let Err(e) = copy(&mut input, &mut writer).and_then(|_| writer.close()) {
+        exit_with(&e);
+    }
+}
+
+fn decrypt(options: Options) {
+    let key = load_key(&options);
+    let nonce = load_nonce(options
+                               .nonce
+                               .as_ref()
+                               .unwrap_or_else(|| usage_error("missing nonce", USAGE)));
+
+    let mut reader = SyncBoxReader::new(open_input(&options), key, nonce);
+    let mut output = open_output(&options);
+
+    let result = copy(&mut reader, &mut output)
+        .and_then(|_| {
+            let mut trailing = [0u8; 1];
+            if reader.get_mut().read(&mut trailing)? != 0 {
+                return Err(Error::new(ErrorKind::InvalidData, TRAILING_DATA));
+            }
+            Ok(())
+        })
+        .and_then(|_| output.flush());
+
+    if let Err(e) = result {
+        let _ = output.flush();
+        exit_with(&e);
+    }
+}
+
+fn inspect_stream(options: Options) {
+    let key = load_key(&options);
+    let nonce = load_nonce(options
+                               .nonce
+                               .as_ref()
+                               .unwrap_or_else(|| usage_error("missing nonce", USAGE)));
+
+    let cypher = match options.pcap {
+        Some(ref path) => read_flow(path, options.flow.as_ref()),
+        None => {
+            let mut cypher = Vec::new();
+            if let Err(e) = open_input(&options).read_to_end(&mut cypher) {
+                exit_with(&e);
+            }
+            cypher
+        }
+    };
+
+    let mut exit_code = 0;
+    for part in inspect(&cypher, &key, &nonce) {
+        match part {
+            Inspected::Packet {
+                offset,
+                nonce,
+                header_mac,
+                packet_mac,
+                body,
+            } => {
+                println!("{:08x}  header  nonce={} len={} header-mac={} packet-mac={}  ok",
+                         offset,
+                         encode_hex(&nonce.0),
+                         body.len(),
+                         encode_hex(&header_mac),
+                         encode_hex(&packet_mac));
+                println!("{:08x}  body    {}  ok",
+                         offset + CYPHER_HEADER_SIZE as u64,
+                         preview(&body, options.preview));
+            }
+            Inspected::FinalHeader { offset, nonce } => {
+                println!("{:08x}  goodbye nonce={}  ok", offset, encode_hex(&nonce.0));
+            }
+            Inspected::Failure {
+                offset,
+                nonce,
+                length,
+                reason,
+            } => {
+                let part = if length.is_some() && reason != INVALID_LENGTH {
+                    "body"
+                } else {
+                    "header"
+                };
+                let length = length
+                    .map(|len| format!(" len={}", len))
+                    .unwrap_or_default();
+                println!("{:08x}  {:<7} nonce={}{}  FAILED: {}",
+                         offset,
+                         part,
+                         encode_hex(&nonce.0),
+                         length,
+                         reason);
+                exit_code = if reason == UNAUTHENTICATED_EOF {
+                    EXIT_TRUNCATED
+                } else {
+                    EXIT_UNAUTHENTICATED
+                };
+            }
+            Inspected::TrailingData { offset, len } => {
+                println!("{:08x}  FAILED: {} ({} bytes)", offset, TRAILING_DATA, len);
+                exit_code = EXIT_UNAUTHENTICATED;
+            }
+        }
+    }
+
+    exit(exit_code);
+}
+
+// Reassemble a flow of a capture, or list the flows of the capture if none is given.
+fn read_flow(path: &str, flow: Option<&Flow>) -> Vec<u8> {
+    let mut capture = Vec::new();
+    if let Err(e) = File::open(path).and_then(|mut file| file.read_to_end(&mut capture)) {
+        exit_with(&e);
+    }
+
+    let flow = match flow {
+        Some(flow) => flow,
+        None => {
+            let flows = pcap::flows(&capture).unwrap_or_else(|e| usage_error(&e, USAGE));
+            eprintln!("flows in {} (payload bytes):", path);
+            for (flow, len) in flows {
+                eprintln!("    {}  {}", flow, len);
+            }
+            usage_error("missing flow", USAGE);
+        }
+    };
+
+    let stream = pcap::reassemble(&capture, flow).unwrap_or_else(|e| usage_error(&e, USAGE));
+    if let Some(gap) = stream.gap {
+        eprintln!("warning: segments missing from the flow at offset {:08x}, \
+                   inspecting the data before them",
+                  gap);
+    }
+    stream.data
+}
+
+// Returns up to `len` bytes of `body`, escaping anything that is not printable ascii.
+fn preview(body: &[u8], len: usize) -> String {
+    let mut preview = String::from("\"");
+    for &byte in body.iter().take(len) {
+        if byte >= 0x20 && byte < 0x7f && byte != b'"' && byte != b'\\' {
+            preview.push(byte as char);
+        } else {
+            preview.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    preview.push('"');
+    if body.len() > len {
+        preview.push_str("...");
+    }
+    preview
+}
+
+// Like `io::copy`, but zeroes its buffer afterwards.
+fn copy<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<(), Error> {
+    let mut buffer = [0u8; 4096];
+    let result = copy_with(reader, writer, &mut buffer);
+    memzero(&mut buffer);
+    result
+}
+
+fn copy_with<R: Read, W: Write>(reader: &mut R,
+                                writer: &mut W,
+                                buffer: &mut [u8])
+                                -> Result<(), Error> {
+    loop {
+        let read = match reader.read(buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read])?;
+    }
+}
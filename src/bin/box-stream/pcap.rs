@@ -0,0 +1,474 @@
+// Extraction of the payload of one direction of a tcp connection from pcap and pcapng captures.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// One direction of a tcp connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Flow {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+impl Flow {
+    /// Parse a flow of the form `source-destination`, e.g.
+    /// `10.0.0.1:8008-10.0.0.2:51234` or `[::1]:8008-[::1]:51234`.
+    pub fn parse(text: &str) -> Option<Flow> {
+        let mut parts = text.splitn(2, '-');
+        let source = parts.next()?.parse().ok()?;
+        let destination = parts.next()?.parse().ok()?;
+        Some(Flow {
+                 source,
+                 destination,
+             })
+    }
+}
+
+impl fmt::Display for Flow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.source, self.destination)
+    }
+}
+
+/// A tcp segment carrying data.
+struct Segment {
+    seq: u32,
+    syn: bool,
+    payload: Vec<u8>,
+}
+
+/// The reassembled payload of a flow.
+pub struct Stream {
+    pub data: Vec<u8>,
+    /// The offset of the first missing byte, if segments are missing.
+    pub gap: Option<usize>,
+}
+
+/// Returns all flows in the capture, with the number of payload bytes of each.
+pub fn flows(capture: &[u8]) -> Result<BTreeMap<Flow, usize>, String> {
+    let mut flows = BTreeMap::new();
+    for (flow, segment) in segments(capture)? {
+        *flows.entry(flow).or_insert(0) += segment.payload.len();
+    }
+    Ok(flows)
+}
+
+/// Reassemble the payload of `flow` from the capture.
+pub fn reassemble(capture: &[u8], flow: &Flow) -> Result<Stream, String> {
+    let segments: Vec<Segment> = segments(capture)?
+        .into_iter()
+        .filter(|&(f, _)| f == *flow)
+        .map(|(_, segment)| segment)
+        .collect();
+
+    // Data starts after the syn, or else at the first captured segment.
+    let base = match segments.iter().find(|segment| segment.syn) {
+        Some(syn) => syn.seq.wrapping_add(1),
+        None => segments.first().map(|segment| segment.seq).unwrap_or(0),
+    };
+
+    let mut ordered: Vec<(u32, &[u8])> = segments
+        .iter()
+        .filter(|segment| !segment.payload.is_empty())
+        .map(|segment| (segment.seq.wrapping_sub(base), &segment.payload[..]))
+        // Segments from before the base can not be placed in the stream.
+        .filter(|&(offset, _)| offset < 1 << 31)
+        .collect();
+    ordered.sort_by_key(|&(offset, _)| offset);
+
+    let mut data = Vec::new();
+    for (offset, payload) in ordered {
+        let offset = offset as usize;
+        if offset > data.len() {
+            return Ok(Stream {
+                          gap: Some(data.len()),
+                          data,
+                      });
+        }
+        if offset + payload.len() > data.len() {
+            let skip = data.len() - offset;
+            data.extend_from_slice(&payload[skip..]);
+        }
+    }
+
+    Ok(Stream { data, gap: None })
+}
+
+// Returns all tcp segments in the capture, in capture order.
+fn segments(capture: &[u8]) -> Result<Vec<(Flow, Segment)>, String> {
+    let mut segments = Vec::new();
+    for (link_type, frame) in frames(capture)? {
+        if let Some(segment) = parse_link(link_type, frame) {
+            segments.push(segment);
+        }
+    }
+    Ok(segments)
+}
+
+// Returns the link type and data of all frames in a pcap or pcapng capture.
+fn frames(capture: &[u8]) -> Result<Vec<(u32, &[u8])>, String> {
+    if capture.len() < 4 {
+        return Err("capture too short".to_string());
+    }
+
+    // Magic numbers of pcap with microsecond and nanosecond timestamps, and of pcapng.
+    match read_u32(capture, 0, true).unwrap() {
+        0xd4c3b2a1 | 0x4d3cb2a1 => pcap_frames(capture, false),
+        0xa1b2c3d4 | 0xa1b23c4d => pcap_frames(capture, true),
+        0x0a0d0d0a => pcapng_frames(capture),
+        _ => Err("not a pcap or pcapng capture".to_string()),
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(if big_endian {
+             (bytes[0] as u16) << 8 | bytes[1] as u16
+         } else {
+             (bytes[1] as u16) << 8 | bytes[0] as u16
+         })
+}
+
+fn read_u32(data: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let high = read_u16(data, offset, big_endian)? as u32;
+    let low = read_u16(data, offset + 2, big_endian)? as u32;
+    Some(if big_endian {
+             high << 16 | low
+         } else {
+             low << 16 | high
+         })
+}
+
+fn truncated() -> String {
+    "truncated capture".to_string()
+}
+
+fn pcap_frames(capture: &[u8], big_endian: bool) -> Result<Vec<(u32, &[u8])>, String> {
+    let link_type = read_u32(capture, 20, big_endian).ok_or_else(truncated)?;
+    let mut frames = Vec::new();
+    let mut offset = 24;
+
+    while offset < capture.len() {
+        let len = read_u32(capture, offset + 8, big_endian).ok_or_else(truncated)? as usize;
+        let frame = capture
+            .get(offset + 16..offset + 16 + len)
+            .ok_or_else(truncated)?;
+        frames.push((link_type, frame));
+        offset += 16 + len;
+    }
+
+    Ok(frames)
+}
+
+fn pcapng_frames(capture: &[u8]) -> Result<Vec<(u32, &[u8])>, String> {
+    let mut frames = Vec::new();
+    let mut big_endian = false;
+    let mut link_types = Vec::new();
+    let mut offset = 0;
+
+    while offset < capture.len() {
+        let block_type = read_u32(capture, offset, big_endian).ok_or_else(truncated)?;
+
+        if block_type == 0x0a0d0d0a {
+            // Section header block, determines the byte order of the section.
+            big_endian = match read_u32(capture, offset + 8, true) {
+                Some(0x1a2b3c4d) => true,
+                Some(0x4d3c2b1a) => false,
+                _ => return Err("invalid pcapng section header".to_string()),
+            };
+            link_types.clear();
+        }
+
+        let len = read_u32(capture, offset + 4, big_endian).ok_or_else(truncated)? as usize;
+        if len < 12 {
+            return Err("invalid pcapng block length".to_string());
+        }
+        let block = capture.get(offset..offset + len).ok_or_else(truncated)?;
+
+        match block_type {
+            // Interface description block.
+            1 => link_types.push(read_u16(block, 8, big_endian).ok_or_else(truncated)? as u32),
+            // Enhanced packet block.
+            6 => {
+                let interface = read_u32(block, 8, big_endian).ok_or_else(truncated)? as usize;
+                let captured = read_u32(block, 20, big_endian).ok_or_else(truncated)? as usize;
+                let link_type = *link_types
+                                     .get(interface)
+                                     .ok_or_else(|| "unknown pcapng interface".to_string())?;
+                frames.push((link_type, block.get(28..28 + captured).ok_or_else(truncated)?));
+            }
+            // Simple packet block.
+            3 => {
+                let link_type = *link_types
+                                     .get(0)
+                                     .ok_or_else(|| "unknown pcapng interface".to_string())?;
+                if len < 16 {
+                    return Err("invalid pcapng simple packet block length".to_string());
+                }
+                let original = read_u32(block, 8, big_endian).ok_or_else(truncated)? as usize;
+                let captured = original.min(len - 16);
+                frames.push((link_type, block.get(12..12 + captured).ok_or_else(truncated)?));
+            }
+            _ => {}
+        }
+
+        offset += len;
+    }
+
+    Ok(frames)
+}
+
+// Parses a frame of the given link type, returning its tcp segment if it has one.
+fn parse_link(link_type: u32, frame: &[u8]) -> Option<(Flow, Segment)> {
+    match link_type {
+        // BSD loopback, the address family is in host byte order.
+        0 => parse_ip(frame.get(4..)?),
+        // Ethernet, possibly with vlan tags.
+        1 => {
+            let mut offset = 12;
+            while read_u16(frame, offset, true)? == 0x8100 {
+                offset += 4;
+            }
+            parse_ip(frame.get(offset + 2..)?)
+        }
+        // Raw ip.
+        101 => parse_ip(frame),
+        // Linux cooked capture.
+        113 => parse_ip(frame.get(16..)?),
+        _ => None,
+    }
+}
+
+fn parse_ip(packet: &[u8]) -> Option<(Flow, Segment)> {
+    match packet.get(0)? >> 4 {
+        4 => {
+            let header_len = ((packet[0] & 0x0f) as usize) * 4;
+            let total_len = read_u16(packet, 2, true)? as usize;
+            let fragment = read_u16(packet, 6, true)? & 0x3fff;
+            if *packet.get(9)? != 6 || fragment != 0 {
+                return None;
+            }
+
+            let source = ipv4(packet.get(12..16)?);
+            let destination = ipv4(packet.get(16..20)?);
+            parse_tcp(source, destination, packet.get(header_len..total_len)?)
+        }
+        6 => {
+            let payload_len = read_u16(packet, 4, true)? as usize;
+            if *packet.get(6)? != 6 {
+                return None;
+            }
+
+            let source = ipv6(packet.get(8..24)?);
+            let destination = ipv6(packet.get(24..40)?);
+            parse_tcp(source, destination, packet.get(40..40 + payload_len)?)
+        }
+        _ => None,
+    }
+}
+
+fn ipv4(bytes: &[u8]) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+}
+
+fn ipv6(bytes: &[u8]) -> IpAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(bytes);
+    IpAddr::V6(Ipv6Addr::from(octets))
+}
+
+fn parse_tcp(source: IpAddr, destination: IpAddr, segment: &[u8]) -> Option<(Flow, Segment)> {
+    let header_len = ((*segment.get(12)? >> 4) as usize) * 4;
+    let flow = Flow {
+        source: SocketAddr::new(source, read_u16(segment, 0, true)?),
+        destination: SocketAddr::new(destination, read_u16(segment, 2, true)?),
+    };
+
+    Some((flow,
+          Segment {
+              seq: read_u32(segment, 4, true)?,
+              syn: segment.get(13)? & 0x02 != 0,
+              payload: segment.get(header_len..)?.to_vec(),
+          }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: [u8; 4] = [10, 0, 0, 1];
+    const DESTINATION: [u8; 4] = [10, 0, 0, 2];
+
+    fn flow() -> Flow {
+        Flow::parse("10.0.0.1:8008-10.0.0.2:51234").unwrap()
+    }
+
+    fn push_u16(out: &mut Vec<u8>, n: u16, big_endian: bool) {
+        if big_endian {
+            out.extend_from_slice(&[(n >> 8) as u8, n as u8]);
+        } else {
+            out.extend_from_slice(&[n as u8, (n >> 8) as u8]);
+        }
+    }
+
+    fn push_u32(out: &mut Vec<u8>, n: u32, big_endian: bool) {
+        if big_endian {
+            push_u16(out, (n >> 16) as u16, true);
+            push_u16(out, n as u16, true);
+        } else {
+            push_u16(out, n as u16, false);
+            push_u16(out, (n >> 16) as u16, false);
+        }
+    }
+
+    // A raw ipv4 packet carrying a tcp segment from SOURCE:8008 to DESTINATION:51234.
+    fn segment(seq: u32, syn: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        push_u16(&mut packet, (40 + payload.len()) as u16, true);
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        packet.extend_from_slice(&SOURCE);
+        packet.extend_from_slice(&DESTINATION);
+
+        push_u16(&mut packet, 8008, true);
+        push_u16(&mut packet, 51234, true);
+        push_u32(&mut packet, seq, true);
+        push_u32(&mut packet, 0, true);
+        packet.push(5 << 4);
+        packet.push(if syn { 0x02 } else { 0x18 });
+        packet.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    // A pcap capture of raw ip packets.
+    fn pcap(packets: &[Vec<u8>], big_endian: bool) -> Vec<u8> {
+        let mut capture = Vec::new();
+        push_u32(&mut capture, 0xa1b2c3d4, big_endian);
+        push_u16(&mut capture, 2, big_endian);
+        push_u16(&mut capture, 4, big_endian);
+        push_u32(&mut capture, 0, big_endian);
+        push_u32(&mut capture, 0, big_endian);
+        push_u32(&mut capture, 65535, big_endian);
+        push_u32(&mut capture, 101, big_endian);
+
+        for packet in packets {
+            push_u32(&mut capture, 0, big_endian);
+            push_u32(&mut capture, 0, big_endian);
+            push_u32(&mut capture, packet.len() as u32, big_endian);
+            push_u32(&mut capture, packet.len() as u32, big_endian);
+            capture.extend_from_slice(packet);
+        }
+        capture
+    }
+
+    fn pcapng_block(capture: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+        let padding = (4 - body.len() % 4) % 4;
+        let len = (12 + body.len() + padding) as u32;
+        push_u32(capture, block_type, false);
+        push_u32(capture, len, false);
+        capture.extend_from_slice(body);
+        capture.extend_from_slice(&[0; 3][..padding]);
+        push_u32(capture, len, false);
+    }
+
+    // A little-endian pcapng section with one raw ip interface.
+    fn pcapng_section() -> Vec<u8> {
+        let mut capture = Vec::new();
+        let mut section = Vec::new();
+        push_u32(&mut section, 0x1a2b3c4d, false);
+        push_u16(&mut section, 1, false);
+        push_u16(&mut section, 0, false);
+        section.extend_from_slice(&[0xff; 8]);
+        pcapng_block(&mut capture, 0x0a0d0d0a, &section);
+
+        let mut interface = Vec::new();
+        push_u16(&mut interface, 101, false);
+        push_u16(&mut interface, 0, false);
+        push_u32(&mut interface, 65535, false);
+        pcapng_block(&mut capture, 1, &interface);
+        capture
+    }
+
+    fn enhanced_packet_block(capture: &mut Vec<u8>, packet: &[u8]) {
+        let mut body = Vec::new();
+        push_u32(&mut body, 0, false);
+        push_u32(&mut body, 0, false);
+        push_u32(&mut body, 0, false);
+        push_u32(&mut body, packet.len() as u32, false);
+        push_u32(&mut body, packet.len() as u32, false);
+        body.extend_from_slice(packet);
+        pcapng_block(capture, 6, &body);
+    }
+
+    fn simple_packet_block(capture: &mut Vec<u8>, packet: &[u8]) {
+        let mut body = Vec::new();
+        push_u32(&mut body, packet.len() as u32, false);
+        body.extend_from_slice(packet);
+        pcapng_block(capture, 3, &body);
+    }
+
+    #[test]
+    fn pcap_byte_orders() {
+        for &big_endian in &[false, true] {
+            let capture = pcap(&[segment(99, true, b""),
+                                 segment(100, false, b"hello"),
+                                 segment(105, false, b" world")],
+                               big_endian);
+
+            let flows = flows(&capture).unwrap();
+            assert_eq!(flows.into_iter().collect::<Vec<_>>(), vec![(flow(), 11)]);
+
+            let stream = reassemble(&capture, &flow()).unwrap();
+            assert_eq!(stream.data, b"hello world".to_vec());
+            assert_eq!(stream.gap, None);
+        }
+    }
+
+    #[test]
+    fn pcapng_enhanced_and_simple_packets() {
+        let mut capture = pcapng_section();
+        enhanced_packet_block(&mut capture, &segment(100, false, b"hello"));
+        simple_packet_block(&mut capture, &segment(105, false, b" world"));
+
+        let stream = reassemble(&capture, &flow()).unwrap();
+        assert_eq!(stream.data, b"hello world".to_vec());
+        assert_eq!(stream.gap, None);
+    }
+
+    #[test]
+    fn pcapng_short_simple_packet_block() {
+        let mut capture = pcapng_section();
+        push_u32(&mut capture, 3, false);
+        push_u32(&mut capture, 12, false);
+        push_u32(&mut capture, 12, false);
+
+        assert!(reassemble(&capture, &flow()).is_err());
+    }
+
+    #[test]
+    fn out_of_order_and_overlapping_segments() {
+        let capture = pcap(&[segment(99, true, b""),
+                             segment(106, false, b"world"),
+                             segment(100, false, b"hello "),
+                             segment(103, false, b"lo wo"),
+                             segment(100, false, b"hello ")],
+                           false);
+
+        let stream = reassemble(&capture, &flow()).unwrap();
+        assert_eq!(stream.data, b"hello world".to_vec());
+        assert_eq!(stream.gap, None);
+    }
+
+    #[test]
+    fn gap() {
+        let capture = pcap(&[segment(99, true, b""),
+                             segment(100, false, b"hello"),
+                             segment(110, false, b"later")],
+                           false);
+
+        let stream = reassemble(&capture, &flow()).unwrap();
+        assert_eq!(stream.data, b"hello".to_vec());
+        assert_eq!(stream.gap, Some(5));
+    }
+}
@@ -0,0 +1,103 @@
+//! Wrap plaintext tcp services in box streams, using a pre-shared key.
+//!
+//! Run `box-tunnel help` for usage.
+
+extern crate box_stream;
+extern crate sodiumoxide;
+
+mod common;
+
+use std::env;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::time::Duration;
+
+use box_stream::tunnel::{Config, Role, run};
+use sodiumoxide::crypto::secretbox;
+
+use common::*;
+
+const USAGE: &'static str = "\
+usage: box-tunnel client --listen <addr> --connect <addr> [options]
+       box-tunnel server --listen <addr> --connect <addr> [options]
+
+A tunnel client accepts plaintext connections on its listen address and forwards
+them, encrypted, to a tunnel server. The tunnel server decrypts them and
+forwards them to its connect address.
+
+options:
+    --key <key>                the pre-shared key, as hex or base64
+    --key-file <path>          read the key from a file (raw, hex or base64)
+    --key-env <var>            read the key from an environment variable
+    --max-connections <n>      how many connections to forward at once (default 64)
+    --handshake-timeout <secs> how long to wait for the other tunnel endpoint to
+                               start a connection (default 10)
+
+A key is required, and client and server must use the same key.";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_else(|| usage_error("missing command", USAGE));
+    let role = match command.as_str() {
+        "client" => Role::Client,
+        "server" => Role::Server,
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            return;
+        }
+        _ => usage_error(&format!("unknown command {}", command), USAGE),
+    };
+
+    let mut listen = None;
+    let mut connect = None;
+    let mut key = None;
+    let mut max_connections = 64;
+    let mut handshake_timeout = 10;
+
+    while let Some(arg) = args.next() {
+        let value = args.next()
+            .unwrap_or_else(|| usage_error(&format!("missing value for {}", arg), USAGE));
+
+        match arg.as_str() {
+            "--listen" => listen = Some(value),
+            "--connect" => connect = Some(value),
+            "--key" => key = Some(Secret::Value(value)),
+            "--key-file" => key = Some(Secret::File(value)),
+            "--key-env" => key = Some(Secret::Env(value)),
+            "--max-connections" => {
+                max_connections = value
+                    .parse()
+                    .unwrap_or_else(|_| usage_error("invalid connection limit", USAGE))
+            }
+            "--handshake-timeout" => {
+                handshake_timeout = match value.parse() {
+                    Ok(secs) if secs > 0 => secs,
+                    _ => usage_error("invalid handshake timeout", USAGE),
+                }
+            }
+            _ => usage_error(&format!("unknown option {}", arg), USAGE),
+        }
+    }
+
+    let listen = listen.unwrap_or_else(|| usage_error("missing listen address", USAGE));
+    let connect = connect.unwrap_or_else(|| usage_error("missing connect address", USAGE));
+    let key = key.unwrap_or_else(|| usage_error("missing key", USAGE))
+        .load("key", secretbox::KEYBYTES)
+        .unwrap_or_else(|e| usage_error(&e, USAGE));
+
+    let connect = connect
+        .to_socket_addrs()
+        .unwrap_or_else(|e| exit_with(&e))
+        .next()
+        .unwrap_or_else(|| usage_error("connect address does not resolve", USAGE));
+    let listener = TcpListener::bind(listen.as_str()).unwrap_or_else(|e| exit_with(&e));
+
+    let config = Config {
+        role,
+        key: secretbox::Key::from_slice(&key).unwrap(),
+        connect,
+        max_connections,
+        handshake_timeout: Duration::from_secs(handshake_timeout),
+    };
+
+    exit_with(&run(listener, config));
+}
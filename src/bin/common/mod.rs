@@ -0,0 +1,190 @@
+// Helpers shared by the command-line tools: parsing of keys and nonces, and exit codes.
+
+// Not every tool uses every helper.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::process::exit;
+
+use box_stream::{INVALID_LENGTH, TRAILING_DATA, UNAUTHENTICATED_EOF, UNAUTHENTICATED_HEADER,
+                 UNAUTHENTICATED_PACKET};
+
+/// Exit code for invalid command-line arguments.
+pub const EXIT_USAGE: i32 = 1;
+/// Exit code for errors of the underlying files, pipes or sockets.
+pub const EXIT_IO: i32 = 2;
+/// Exit code for data that failed to authenticate.
+pub const EXIT_UNAUTHENTICATED: i32 = 3;
+/// Exit code for box streams that ended without a final header.
+pub const EXIT_TRUNCATED: i32 = 4;
+
+/// Print `message` and exit with `EXIT_USAGE`.
+pub fn usage_error(message: &str, usage: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, usage);
+    exit(EXIT_USAGE)
+}
+
+/// Print `err` and exit with the exit code matching it.
+pub fn exit_with(err: &Error) -> ! {
+    eprintln!("error: {}", err);
+    exit(exit_code(err))
+}
+
+/// Returns the exit code for an error produced while reading or writing a box
+/// stream.
+pub fn exit_code(err: &Error) -> i32 {
+    // The errors of box streams carry one of the error value constants, errors of the operating
+    // system carry none.
+    let value = match err.get_ref() {
+        Some(inner) => inner.to_string(),
+        None => return EXIT_IO,
+    };
+
+    match (err.kind(), value.as_str()) {
+        (ErrorKind::UnexpectedEof, UNAUTHENTICATED_EOF) => EXIT_TRUNCATED,
+        (ErrorKind::InvalidData, UNAUTHENTICATED_HEADER) |
+        (ErrorKind::InvalidData, UNAUTHENTICATED_PACKET) |
+        (ErrorKind::InvalidData, INVALID_LENGTH) |
+        (ErrorKind::InvalidData, TRAILING_DATA) => EXIT_UNAUTHENTICATED,
+        _ => EXIT_IO,
+    }
+}
+
+/// Where to get a key or nonce from.
+pub enum Secret {
+    /// Given directly as hex or base64.
+    Value(String),
+    /// A file containing either the raw bytes, or hex or base64.
+    File(String),
+    /// An environment variable containing hex or base64.
+    Env(String),
+}
+
+impl Secret {
+    /// Load the secret, which must be `len` bytes long. `name` is used in error
+    /// messages.
+    pub fn load(&self, name: &str, len: usize) -> Result<Vec<u8>, String> {
+        let text = match *self {
+            Secret::Value(ref value) => value.clone(),
+            Secret::File(ref path) => {
+                let contents = fs::read(path)
+                    .map_err(|e| format!("could not read {} file {}: {}", name, path, e))?;
+                if contents.len() == len {
+                    return Ok(contents);
+                }
+                String::from_utf8(contents)
+                    .map_err(|_| format!("{} file {} is neither raw, hex nor base64", name, path))?
+            }
+            Secret::Env(ref var) => {
+                env::var(var)
+                    .map_err(|e| format!("could not read {} from ${}: {}", name, var, e))?
+            }
+        };
+
+        decode(text.trim())
+            .and_then(|bytes| if bytes.len() == len { Some(bytes) } else { None })
+            .ok_or_else(|| format!("{} must be {} bytes, encoded as hex or base64", name, len))
+    }
+}
+
+/// Decode hex or base64 (standard or url-safe alphabet, padding optional).
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    decode_hex(text).or_else(|| decode_base64(text))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.bytes().all(|b| (b as char).is_digit(16)) {
+        return None;
+    }
+    (0..text.len() / 2)
+        .map(|i| u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok())
+        .collect()
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_right_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+
+    for b in text.bytes() {
+        let value = match b {
+            b'A'...b'Z' => b - b'A',
+            b'a'...b'z' => b - b'a' + 26,
+            b'0'...b'9' => b - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    if bits >= 6 { None } else { Some(out) }
+}
+
+/// Encode `bytes` as lowercase hex.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_hex_and_base64() {
+        let hello = b"hello".to_vec();
+        assert_eq!(decode("68656c6c6f"), Some(hello.clone()));
+        assert_eq!(decode("68656C6C6F"), Some(hello.clone()));
+        assert_eq!(decode("aGVsbG8="), Some(hello.clone()));
+        assert_eq!(decode("aGVsbG8"), Some(hello));
+
+        // 0xfb 0xff uses the two characters in which the alphabets differ.
+        assert_eq!(decode("+/8="), Some(vec![0xfb, 0xff]));
+        assert_eq!(decode("+/8"), Some(vec![0xfb, 0xff]));
+        assert_eq!(decode("-_8="), Some(vec![0xfb, 0xff]));
+        assert_eq!(decode("-_8"), Some(vec![0xfb, 0xff]));
+
+        assert_eq!(decode(""), Some(vec![]));
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert_eq!(decode("not base64!"), None);
+        assert_eq!(decode("ab*d"), None);
+        // A single base64 character does not encode a whole byte.
+        assert_eq!(decode("a"), None);
+    }
+
+    #[test]
+    fn exit_codes() {
+        let codes = [EXIT_USAGE, EXIT_IO, EXIT_UNAUTHENTICATED, EXIT_TRUNCATED];
+        for (i, code) in codes.iter().enumerate() {
+            assert!(*code != 0);
+            assert!(!codes[i + 1..].contains(code));
+        }
+
+        assert_eq!(exit_code(&Error::new(ErrorKind::UnexpectedEof, UNAUTHENTICATED_EOF)),
+                   EXIT_TRUNCATED);
+        for value in &[UNAUTHENTICATED_HEADER,
+                       UNAUTHENTICATED_PACKET,
+                       INVALID_LENGTH,
+                       TRAILING_DATA] {
+            assert_eq!(exit_code(&Error::new(ErrorKind::InvalidData, *value)),
+                       EXIT_UNAUTHENTICATED);
+        }
+
+        assert_eq!(exit_code(&Error::from(ErrorKind::NotFound)), EXIT_IO);
+        assert_eq!(exit_code(&Error::from_raw_os_error(2)), EXIT_IO);
+        // Only the errors of box streams count, not other errors with the same message.
+        assert_eq!(exit_code(&Error::new(ErrorKind::Other, UNAUTHENTICATED_PACKET)),
+                   EXIT_IO);
+    }
+}
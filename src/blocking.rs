@@ -0,0 +1,107 @@
+// Helpers shared by the blocking readers and writers.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::Arc;
+
+use futures_core::Poll;
+use futures_core::Async::{Pending, Ready};
+use futures_core::task::{Context, LocalMap, Wake, Waker};
+use futures_io::{AsyncRead, AsyncWrite};
+use sodiumoxide::utils::memzero;
+
+use crypto::MAX_PACKET_USIZE;
+use decryptor::UNAUTHENTICATED_EOF;
+
+// Holds the plaintext of a single packet.
+pub(crate) struct Buffer(pub [u8; MAX_PACKET_USIZE]);
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer([0; MAX_PACKET_USIZE])
+    }
+}
+
+/// Zero buffered data on dropping.
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        memzero(&mut self.0);
+    }
+}
+
+// Like `read_exact`, but signals a premature end of the inner reader with `UNAUTHENTICATED_EOF`.
+pub(crate) fn read_exact_authenticated<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    r.read_exact(buf)
+        .map_err(|e| if e.kind() == ErrorKind::UnexpectedEof {
+                     Error::new(ErrorKind::UnexpectedEof, UNAUTHENTICATED_EOF)
+                 } else {
+                     e
+                 })
+}
+
+// Fill `buf`, returning `false` if the reader ends before.
+pub(crate) fn read_until_eof<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool, Error> {
+    match read_exact_authenticated(r, buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Adapts a blocking reader or writer to `AsyncRead` and `AsyncWrite`, so that it can drive the
+// asynchronous state machines. Neither ever returns `Pending`.
+pub(crate) struct Blocking<R>(pub R);
+
+impl<R: Read> AsyncRead for Blocking<R> {
+    fn poll_read(&mut self, _: &mut Context, buf: &mut [u8]) -> Poll<usize, Error> {
+        loop {
+            match self.0.read(buf) {
+                Ok(read) => return Ok(Ready(read)),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<W: Write> AsyncWrite for Blocking<W> {
+    fn poll_write(&mut self, _: &mut Context, buf: &[u8]) -> Poll<usize, Error> {
+        loop {
+            match self.0.write(buf) {
+                Ok(written) => return Ok(Ready(written)),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn poll_flush(&mut self, _: &mut Context) -> Poll<(), Error> {
+        self.0.flush().map(Ready)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.poll_flush(cx)
+    }
+}
+
+struct NoopWake;
+
+impl Wake for NoopWake {
+    fn wake(_: &Arc<NoopWake>) {}
+}
+
+// Poll `f` until it is ready. Only for state machines that wait on nothing but `Blocking` io,
+// which never returns `Pending`, so nothing ever needs to be woken.
+pub(crate) fn poll_blocking<T, E, F>(mut f: F) -> Result<T, E>
+    where F: FnMut(&mut Context) -> Poll<T, E>
+{
+    let mut map = LocalMap::new();
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut cx = Context::without_spawn(&mut map, &waker);
+
+    loop {
+        match f(&mut cx)? {
+            Ready(item) => return Ok(item),
+            Pending => {}
+        }
+    }
+}
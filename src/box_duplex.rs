@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use futures_core::Poll;
 use futures_core::task::Context;
 use futures_io::{Error, AsyncRead, AsyncWrite};
@@ -5,6 +7,11 @@ use sodiumoxide::crypto::secretbox;
 
 use encryptor::*;
 use decryptor::*;
+use duplex_keys::DuplexKeys;
+use observer::{PacketObserver, share};
+use rate_limit::{Counting, RateLimiter, Shaper};
+use stats::{Stats, StatsRegistry};
+use timeout::{Deadlines, Timeouts, Timer};
 
 /// Wraps a duplex stream, encrypting all writes and decrypting all reads.
 pub struct BoxDuplex<S> {
@@ -37,6 +44,22 @@ impl<S> BoxDuplex<S> {
         }
     }
 
+    /// Create a new duplex stream, wrapping `inner` and using the supplied
+    /// `keys` for encryption and decryption.
+    pub fn from_keys(inner: S, keys: DuplexKeys) -> BoxDuplex<S> {
+        let DuplexKeys {
+            encryption_key,
+            decryption_key,
+            encryption_nonce,
+            decryption_nonce,
+        } = keys;
+        BoxDuplex::new(inner,
+                       encryption_key,
+                       decryption_key,
+                       encryption_nonce,
+                       decryption_nonce)
+    }
+
     /// Gets a reference to the underlying stream.
     pub fn get_ref(&self) -> &S {
         &self.inner
@@ -50,6 +73,62 @@ impl<S> BoxDuplex<S> {
         &mut self.inner
     }
 
+    /// Call `observer` on every packet of this stream, in both directions, see
+    /// `PacketObserver`.
+    pub fn set_observer<O: PacketObserver + Send + 'static>(&mut self, observer: O) {
+        let observer = share(observer);
+        self.decryptor.observer = Some(observer.clone());
+        self.encryptor.observer = Some(observer);
+    }
+
+    /// Returns a snapshot of the traffic statistics of this stream.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            read: self.decryptor.recorder.stats(),
+            written: self.encryptor.recorder.stats(),
+        }
+    }
+
+    /// Report the traffic of this stream to `registry` from now on.
+    /// A stream reports to at most one registry, later calls have no effect.
+    pub fn register(&mut self, registry: &Arc<StatsRegistry>) {
+        self.decryptor.recorder.register(registry.clone(), true);
+        self.encryptor.recorder.register(registry.clone(), false);
+    }
+
+    /// Emit the tracing events of this stream in a child span of `span`, e.g. a
+    /// span identifying the connection. By default, the parent is the span
+    /// that was current when this stream was created.
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: ::tracing::Span) {
+        self.decryptor.set_parent_span(&span);
+        self.encryptor.set_parent_span(&span);
+    }
+
+    /// Enforce `timeouts` on this stream from now on, using `timer` to wait for
+    /// them. The `idle_read` deadline applies to reads, the `close` deadline to
+    /// closing, and the `packet` deadline to both directions independently.
+    /// See `Timeouts`.
+    pub fn set_timeouts<T: Timer + Send + Sync + 'static>(&mut self, timeouts: Timeouts, timer: T) {
+        let timer = Arc::new(timer);
+        self.decryptor.deadlines = Some(Deadlines::new(timeouts, timer.clone()));
+        self.encryptor.deadlines = Some(Deadlines::new(timeouts, timer));
+    }
+
+    /// Limit reads to the rate of `limiter` from now on, counting the bytes
+    /// selected by `counting`. See `BoxReader::set_rate_limiter`.
+    pub fn set_read_rate_limiter(&mut self, limiter: RateLimiter, counting: Counting) {
+        self.decryptor.shaper = Some(Shaper::new(limiter, counting));
+    }
+
+    /// Limit writes to the rate of `limiter` from now on, counting the bytes
+    /// selected by `counting`. Pass clones of the same limiter to this and
+    /// `set_read_rate_limiter` to limit both directions together. See
+    /// `BoxWriter::set_rate_limiter`.
+    pub fn set_write_rate_limiter(&mut self, limiter: RateLimiter, counting: Counting) {
+        self.encryptor.shaper = Some(Shaper::new(limiter, counting));
+    }
+
     /// Unwraps this `BoxDuplex`, returning the underlying stream.
     pub fn into_inner(self) -> S {
         self.inner
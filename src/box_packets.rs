@@ -0,0 +1,202 @@
+// Implementation of BoxPacketStream and BoxPacketSink, which treat each packet as one message.
+
+use bytes::Bytes;
+use futures_core::{Poll, Stream};
+use futures_core::Async::{Pending, Ready};
+use futures_core::task::Context;
+use futures_io::{Error, ErrorKind, AsyncRead, AsyncWrite};
+use futures_sink::Sink;
+use sodiumoxide::crypto::secretbox;
+
+use crypto::MAX_PACKET_USIZE;
+use decryptor::Decryptor;
+use encryptor::Encryptor;
+use stats::Stats;
+
+/// The error value signaling an attempt to send a message longer than
+/// `MAX_PACKET_SIZE` as a single packet.
+pub const MESSAGE_TOO_LONG: &'static str = "message exceeds maximum packet size";
+
+/// The error value signaling an attempt to send an empty message, which can
+/// not be encoded as a packet.
+pub const EMPTY_MESSAGE: &'static str = "message is empty";
+
+/// Wraps a reader, yielding the plaintext of each authenticated packet as one
+/// item. The stream ends with the final header.
+///
+/// Each packet is copied out of the decryption buffer (which is zeroed right
+/// after) into its own `Bytes`.
+///
+/// # Errors
+/// Produces the same errors as `BoxReader`.
+pub struct BoxPacketStream<R> {
+    inner: R,
+    key: secretbox::Key,
+    nonce: secretbox::Nonce,
+    decryptor: Decryptor,
+    done: bool,
+}
+
+impl<R> BoxPacketStream<R> {
+    /// Create a new stream, wrapping `inner` and using `key` and `nonce` for
+    /// decryption.
+    pub fn new(inner: R, key: secretbox::Key, nonce: secretbox::Nonce) -> BoxPacketStream<R> {
+        BoxPacketStream {
+            inner,
+            key,
+            nonce,
+            decryptor: Decryptor::new(),
+            done: false,
+        }
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying reader.
+    ///
+    /// It is inadvisable to directly read from the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns a snapshot of the traffic statistics of this stream.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            read: self.decryptor.recorder.stats(),
+            ..Stats::default()
+        }
+    }
+
+    /// Unwraps this `BoxPacketStream`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead> Stream for BoxPacketStream<R> {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Bytes>, Error> {
+        if self.done {
+            return Ok(Ready(None));
+        }
+
+        let packet = try_ready!(self.decryptor
+                                    .poll_packet(cx, &mut self.inner, &self.key, &mut self.nonce));
+        if packet.is_none() {
+            self.done = true;
+        }
+        Ok(Ready(packet.map(Bytes::from)))
+    }
+}
+
+/// Wraps a writer, encrypting each item into exactly one packet. Closing the
+/// sink writes the final header.
+///
+/// # Errors
+/// In addition to propagating all errors from the wrapped writer, sending an
+/// item errors with `ErrorKind::InvalidInput` and `MESSAGE_TOO_LONG` if it is
+/// longer than `MAX_PACKET_SIZE`, or with `EMPTY_MESSAGE` if it is empty.
+pub struct BoxPacketSink<W> {
+    inner: W,
+    key: secretbox::Key,
+    nonce: secretbox::Nonce,
+    encryptor: Encryptor,
+    // The item passed to `start_send` which has not been sealed yet.
+    pending: Option<Bytes>,
+}
+
+impl<W> BoxPacketSink<W> {
+    /// Create a new sink, wrapping `inner` and using `key` and `nonce` for
+    /// encryption.
+    pub fn new(inner: W, key: secretbox::Key, nonce: secretbox::Nonce) -> BoxPacketSink<W> {
+        BoxPacketSink {
+            inner,
+            key,
+            nonce,
+            encryptor: Encryptor::new(),
+            pending: None,
+        }
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    ///
+    /// It is inadvisable to directly write to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns a snapshot of the traffic statistics of this sink.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            written: self.encryptor.recorder.stats(),
+            ..Stats::default()
+        }
+    }
+
+    /// Unwraps this `BoxPacketSink`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite> BoxPacketSink<W> {
+    // Seal the pending item, once the previous packet has been written.
+    fn poll_seal(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        if let Some(item) = self.pending.take() {
+            match self.encryptor
+                      .poll_write(cx, &item, &mut self.inner, &self.key, &mut self.nonce)? {
+                Ready(written) => debug_assert!(written == item.len()),
+                Pending => {
+                    self.pending = Some(item);
+                    return Ok(Pending);
+                }
+            }
+        }
+
+        Ok(Ready(()))
+    }
+}
+
+impl<W: AsyncWrite> Sink for BoxPacketSink<W> {
+    type SinkItem = Bytes;
+    type SinkError = Error;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.poll_seal(cx)
+    }
+
+    fn start_send(&mut self, item: Bytes) -> Result<(), Error> {
+        if item.len() > MAX_PACKET_USIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, MESSAGE_TOO_LONG));
+        }
+        if item.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, EMPTY_MESSAGE));
+        }
+
+        debug_assert!(self.pending.is_none());
+        self.pending = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        try_ready!(self.poll_seal(cx));
+        self.encryptor
+            .poll_flush(cx, &mut self.inner, &self.key, &mut self.nonce)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        try_ready!(self.poll_seal(cx));
+        self.encryptor
+            .poll_close(cx, &mut self.inner, &self.key, &mut self.nonce)
+    }
+}
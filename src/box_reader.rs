@@ -1,11 +1,17 @@
 // Implementation of BoxReader, a wrapper for Readers that decrypts all reads.
 
+use std::sync::Arc;
+
 use futures_core::Poll;
 use futures_core::task::Context;
 use futures_io::{Error, AsyncRead};
 use sodiumoxide::crypto::secretbox;
 
 use decryptor::*;
+use observer::{PacketObserver, share};
+use rate_limit::{Counting, RateLimiter, Shaper};
+use stats::{Stats, StatsRegistry};
+use timeout::{Deadlines, Timeouts, Timer};
 
 /// Wraps a reader, decrypting all reads.
 pub struct BoxReader<R> {
@@ -39,6 +45,46 @@ impl<R> BoxReader<R> {
         &mut self.inner
     }
 
+    /// Call `observer` on every packet of this reader, see `PacketObserver`.
+    pub fn set_observer<O: PacketObserver + Send + 'static>(&mut self, observer: O) {
+        self.decryptor.observer = Some(share(observer));
+    }
+
+    /// Returns a snapshot of the traffic statistics of this reader.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            read: self.decryptor.recorder.stats(),
+            ..Stats::default()
+        }
+    }
+
+    /// Report the traffic of this reader to `registry` from now on.
+    /// A reader reports to at most one registry, later calls have no effect.
+    pub fn register(&mut self, registry: &Arc<StatsRegistry>) {
+        self.decryptor.recorder.register(registry.clone(), true);
+    }
+
+    /// Emit the tracing events of this reader in a child span of `span`, e.g. a
+    /// span identifying the connection. By default, the parent is the span
+    /// that was current when this reader was created.
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: ::tracing::Span) {
+        self.decryptor.set_parent_span(&span);
+    }
+
+    /// Enforce the `idle_read` and `packet` deadlines of `timeouts` on all
+    /// reads from now on, using `timer` to wait for them. See `Timeouts`.
+    pub fn set_timeouts<T: Timer + Send + Sync + 'static>(&mut self, timeouts: Timeouts, timer: T) {
+        self.decryptor.deadlines = Some(Deadlines::new(timeouts, Arc::new(timer)));
+    }
+
+    /// Limit reads to the rate of `limiter` from now on, counting the bytes
+    /// selected by `counting`. The next packet is only read from the wrapped
+    /// reader once the limiter has tokens left. See `RateLimiter`.
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter, counting: Counting) {
+        self.decryptor.shaper = Some(Shaper::new(limiter, counting));
+    }
+
     /// Unwraps this `BoxReader`, returning the underlying reader.
     pub fn into_inner(self) -> R {
         self.inner
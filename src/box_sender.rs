@@ -0,0 +1,258 @@
+// Implementation of BoxSender, a handle for sending on a BoxWriter from many tasks.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures_channel::mpsc::{channel, Receiver, Sender};
+use futures_channel::oneshot;
+use futures_core::{Future, Poll, Stream};
+use futures_core::Async::{Pending, Ready};
+use futures_core::task::Context;
+use futures_io::{Error, ErrorKind, AsyncWrite};
+use futures_sink::Sink;
+
+use box_writer::BoxWriter;
+use crypto::MAX_PACKET_USIZE;
+
+/// The error value signaling that the `BoxSenderTask` stopped, because the
+/// stream was closed or writing failed, before a message could be sent.
+pub const SENDER_CLOSED: &'static str = "box sender closed";
+
+/// Split `writer` into a handle for sending messages, and the task which writes
+/// them. The queue between them holds up to `capacity` messages, plus one for
+/// each clone of the handle.
+pub fn box_sender<W>(writer: BoxWriter<W>, capacity: usize) -> (BoxSender, BoxSenderTask<W>) {
+    let (tx, rx) = channel(capacity);
+
+    (BoxSender {
+         tx,
+         closed: Arc::new(AtomicBool::new(false)),
+     },
+     BoxSenderTask {
+         writer,
+         rx,
+         current: None,
+         closing: false,
+         remaining: VecDeque::new(),
+         closed: Vec::new(),
+     })
+}
+
+// Messages from the handles to the task.
+enum Message {
+    Data(Vec<u8>),
+    // Close the stream, then notify the sender.
+    Close(oneshot::Sender<()>),
+}
+
+/// Sink of messages to be written to a `BoxWriter` by a `BoxSenderTask`. Can
+/// be cloned freely.
+///
+/// Messages are written in their entirety and in the order in which they
+/// entered the queue, so each packet contains the bytes of a single message.
+/// Messages longer than the maximum packet size span several consecutive
+/// packets. Sending waits while the queue is full.
+///
+/// Every message that has been accepted by `start_send` is written before the
+/// stream is closed, unless writing fails.
+#[derive(Clone)]
+pub struct BoxSender {
+    tx: Sender<Message>,
+    // Set once any handle started closing the stream.
+    closed: Arc<AtomicBool>,
+}
+
+impl BoxSender {
+    /// Close the stream once all messages queued so far have been written,
+    /// resolving once the final header has been written and flushed. From the
+    /// moment this is called, all handles reject further messages with
+    /// `SENDER_CLOSED`.
+    pub fn close(&self) -> BoxSenderClose {
+        self.closed.store(true, Ordering::SeqCst);
+        let (done_tx, done_rx) = oneshot::channel();
+        BoxSenderClose {
+            tx: self.tx.clone(),
+            message: Some(Message::Close(done_tx)),
+            done: done_rx,
+        }
+    }
+}
+
+impl Sink for BoxSender {
+    type SinkItem = Vec<u8>;
+    type SinkError = Error;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(closed());
+        }
+        self.tx.poll_ready(cx).map_err(|_| closed())
+    }
+
+    fn start_send(&mut self, message: Vec<u8>) -> Result<(), Error> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(closed());
+        }
+        self.tx
+            .start_send(Message::Data(message))
+            .map_err(|_| closed())
+    }
+
+    /// Messages are written by the `BoxSenderTask`, so this does not wait for
+    /// them to be written.
+    fn poll_flush(&mut self, _: &mut Context) -> Poll<(), Error> {
+        Ok(Ready(()))
+    }
+
+    /// Only this handle is closed, use `BoxSender::close` to close the stream.
+    fn poll_close(&mut self, _: &mut Context) -> Poll<(), Error> {
+        Ok(Ready(()))
+    }
+}
+
+/// Future returned by `BoxSender::close`.
+///
+/// # Errors
+/// Errors with `ErrorKind::BrokenPipe` and `SENDER_CLOSED` if the stream was
+/// not closed by this future, either because writing failed or because it
+/// was closed before.
+pub struct BoxSenderClose {
+    tx: Sender<Message>,
+    message: Option<Message>,
+    done: oneshot::Receiver<()>,
+}
+
+impl Future for BoxSenderClose {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        if self.message.is_some() {
+            try_ready!(self.tx.poll_ready(cx).map_err(|_| closed()));
+            self.tx
+                .start_send(self.message.take().unwrap())
+                .map_err(|_| closed())?;
+        }
+
+        self.done.poll(cx).map_err(|_| closed())
+    }
+}
+
+/// Future which writes all messages sent via the `BoxSender`s of a `BoxWriter`.
+///
+/// Resolves once the stream has been closed, either by `BoxSender::close`, or
+/// after all handles have been dropped and their messages have been written.
+/// Once writing fails, this errors and all handles are closed.
+pub struct BoxSenderTask<W> {
+    writer: BoxWriter<W>,
+    rx: Receiver<Message>,
+    // The message being written, and how many of its bytes have been written.
+    current: Option<(Vec<u8>, usize)>,
+    closing: bool,
+    // Messages that were still queued when closing began, and are written before the final
+    // header.
+    remaining: VecDeque<Vec<u8>>,
+    // Notified once the stream has been closed.
+    closed: Vec<oneshot::Sender<()>>,
+}
+
+impl<W> BoxSenderTask<W> {
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &BoxWriter<W> {
+        &self.writer
+    }
+
+    /// Unwraps this task, returning the underlying writer. Messages which have
+    /// not been written are discarded.
+    pub fn into_inner(self) -> BoxWriter<W> {
+        self.writer
+    }
+
+    // Take the next message from the queue.
+    fn poll_message(&mut self, cx: &mut Context) -> Poll<Option<Message>, Error> {
+        match self.rx.poll_next(cx) {
+            Ok(polled) => Ok(polled),
+            Err(never) => match never {},
+        }
+    }
+
+    // Stop accepting messages. Those that are still queued are written before closing.
+    fn start_closing(&mut self, cx: &mut Context) {
+        self.closing = true;
+        self.rx.close();
+        while let Ok(Ready(Some(message))) = self.poll_message(cx) {
+            match message {
+                Message::Data(message) => self.remaining.push_back(message),
+                Message::Close(done) => self.closed.push(done),
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite> BoxSenderTask<W> {
+    fn poll_messages(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        loop {
+            if let Some((message, mut offset)) = self.current.take() {
+                while offset < message.len() {
+                    // Hand the writer at most one packet at a time.
+                    let end = min(offset + MAX_PACKET_USIZE, message.len());
+                    match self.writer.poll_write(cx, &message[offset..end])? {
+                        Ready(0) => {
+                            return Err(Error::new(ErrorKind::WriteZero, "failed to write message"))
+                        }
+                        Ready(written) => offset += written,
+                        Pending => {
+                            self.current = Some((message, offset));
+                            return Ok(Pending);
+                        }
+                    }
+                }
+            }
+
+            if self.closing {
+                if let Some(message) = self.remaining.pop_front() {
+                    self.current = Some((message, 0));
+                    continue;
+                }
+                try_ready!(self.writer.poll_close(cx));
+                for done in self.closed.drain(..) {
+                    let _ = done.send(());
+                }
+                return Ok(Ready(()));
+            }
+
+            match self.poll_message(cx)? {
+                Ready(Some(Message::Data(message))) => self.current = Some((message, 0)),
+                Ready(Some(Message::Close(done))) => {
+                    self.closed.push(done);
+                    self.start_closing(cx);
+                }
+                Ready(None) => self.start_closing(cx),
+                Pending => {
+                    try_ready!(self.writer.poll_flush(cx));
+                    return Ok(Pending);
+                }
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite> Future for BoxSenderTask<W> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        let polled = self.poll_messages(cx);
+        if polled.is_err() {
+            self.rx.close();
+            self.closed.clear();
+        }
+        polled
+    }
+}
+
+fn closed() -> Error {
+    Error::new(ErrorKind::BrokenPipe, SENDER_CLOSED)
+}
@@ -1,11 +1,17 @@
 // Implementation of BoxWriter, a wrapper for writers that encrypts all writes.
 
+use std::sync::Arc;
+
 use futures_core::Poll;
 use futures_core::task::Context;
 use futures_io::{Error, AsyncWrite};
 use sodiumoxide::crypto::secretbox;
 
 use encryptor::*;
+use observer::{PacketObserver, share};
+use rate_limit::{Counting, RateLimiter, Shaper};
+use stats::{Stats, StatsRegistry};
+use timeout::{Deadlines, Timeouts, Timer};
 
 /// Wraps a writer, encrypting all writes.
 pub struct BoxWriter<W> {
@@ -39,6 +45,47 @@ impl<W> BoxWriter<W> {
         &mut self.inner
     }
 
+    /// Call `observer` on every packet of this writer, see `PacketObserver`.
+    pub fn set_observer<O: PacketObserver + Send + 'static>(&mut self, observer: O) {
+        self.encryptor.observer = Some(share(observer));
+    }
+
+    /// Returns a snapshot of the traffic statistics of this writer.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            written: self.encryptor.recorder.stats(),
+            ..Stats::default()
+        }
+    }
+
+    /// Report the traffic of this writer to `registry` from now on.
+    /// A writer reports to at most one registry, later calls have no effect.
+    pub fn register(&mut self, registry: &Arc<StatsRegistry>) {
+        self.encryptor.recorder.register(registry.clone(), true);
+    }
+
+    /// Emit the tracing events of this writer in a child span of `span`, e.g. a
+    /// span identifying the connection. By default, the parent is the span
+    /// that was current when this writer was created.
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: ::tracing::Span) {
+        self.encryptor.set_parent_span(&span);
+    }
+
+    /// Enforce the `packet` and `close` deadlines of `timeouts` on all writes,
+    /// flushes and closes from now on, using `timer` to wait for them. See
+    /// `Timeouts`.
+    pub fn set_timeouts<T: Timer + Send + Sync + 'static>(&mut self, timeouts: Timeouts, timer: T) {
+        self.encryptor.deadlines = Some(Deadlines::new(timeouts, Arc::new(timer)));
+    }
+
+    /// Limit writes to the rate of `limiter` from now on, counting the bytes
+    /// selected by `counting`. Packets are only sealed once the limiter allows
+    /// them in their entirety. See `RateLimiter`.
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter, counting: Counting) {
+        self.encryptor.shaper = Some(Shaper::new(limiter, counting));
+    }
+
     /// Unwraps this `BoxWriter`, returning the underlying writer.
     pub fn into_inner(self) -> W {
         self.inner
@@ -151,3 +151,27 @@ pub unsafe fn decrypt_packet_inplace(cypher_packet: *mut u8,
                                      -> bool {
     bs_decrypt_packet_inplace(cypher_packet, plain_header, decryption_key, nonce)
 }
+
+/// Returns the nonce used for the header of the packet at `packet_index`,
+/// given the `nonce` the box stream started with.
+///
+/// Each packet consumes two nonces (one for its header, one for its body), so
+/// this is `nonce + 2 * packet_index`, interpreting nonces as big-endian
+/// integers. The final header of a stream of `n` packets uses
+/// `packet_nonce(nonce, n)`.
+pub fn packet_nonce(nonce: &[u8; secretbox::NONCEBYTES],
+                    packet_index: u64)
+                    -> [u8; secretbox::NONCEBYTES] {
+    let mut result = *nonce;
+    // Add 2 * packet_index as a 65 bit number, then propagate the carry.
+    let mut carry = (packet_index as u128) << 1;
+    for byte in result.iter_mut().rev() {
+        if carry == 0 {
+            break;
+        }
+        carry += *byte as u128;
+        *byte = carry as u8;
+        carry >>= 8;
+    }
+    result
+}
@@ -8,9 +8,15 @@ use futures_core::task::Context;
 use futures_io::{Error, AsyncRead, ErrorKind};
 use sodiumoxide::crypto::secretbox;
 use sodiumoxide::utils::memzero;
+#[cfg(feature = "tracing")]
+use tracing::Span;
 
 use crypto::{CYPHER_HEADER_SIZE, CYPHER_HEADER_SIZE_U16, MAX_PACKET_SIZE, MAX_PACKET_USIZE,
              PlainHeader, decrypt_header_inplace, decrypt_packet_inplace};
+use observer::{PacketDirection, PacketInfo, SharedObserver, observe};
+use rate_limit::Shaper;
+use stats::{Direction, Recorder};
+use timeout::{Deadlines, Phase};
 
 const BUFFER_SIZE: usize = CYPHER_HEADER_SIZE + MAX_PACKET_USIZE;
 
@@ -26,11 +32,24 @@ pub const UNAUTHENTICATED_PACKET: &'static str = "read unauthenticated packet";
 /// The error value signaling that the box stream reached an unauthenticated eof.
 pub const UNAUTHENTICATED_EOF: &'static str = "reached unauthenticated eof";
 
+/// The error value signaling that a box stream continued after its final header.
+pub const TRAILING_DATA: &'static str = "read data after final header";
+
 // Implements the base functionality for creating decrypting wrappers around `AsyncRead`s.
 pub struct Decryptor {
     // Bytes are read into this buffer and get decrypted in-place
     buffer: [u8; BUFFER_SIZE],
     state: State,
+    pub recorder: Recorder,
+    pub observer: Option<SharedObserver>,
+    pub deadlines: Option<Deadlines>,
+    pub shaper: Option<Shaper>,
+    // The kind and message of the error with which the observer vetoed a packet. Every read fails
+    // with this error once it is set.
+    vetoed: Option<(ErrorKind, String)>,
+    // The parent of all events emitted by this decryptor.
+    #[cfg(feature = "tracing")]
+    pub span: Span,
 }
 
 impl Decryptor {
@@ -38,15 +57,30 @@ impl Decryptor {
         Decryptor {
             buffer: [0; BUFFER_SIZE],
             state: ReadCypherHeader { offset: 0 },
+            recorder: Recorder::new(Direction::Read),
+            observer: None,
+            deadlines: None,
+            shaper: None,
+            vetoed: None,
+            #[cfg(feature = "tracing")]
+            span: debug_span!("box_stream_decryptor"),
         }
     }
 
+    #[cfg(feature = "tracing")]
+    pub fn set_parent_span(&mut self, parent: &Span) {
+        self.span = debug_span!(parent: parent, "box_stream_decryptor");
+    }
+
     // A Read wrapper using the decryptor should delegate to this method in its `read` implementation.
     //
     // If this returns Ok(0) and the provided buffer was not 0 bytes in length, a final header was read
     // and no more data will be emitted. If the underlying Read emitted 0 bytes although it was not
     // given a 0 length buffer, this results in an io::Error of kind `UnexpectedEof` (since EOF
     // must be signaled by the final header).
+    //
+    // Once a deadline has passed, this errors with the timeout error from then on. Likewise, once
+    // the observer vetoed a packet, this errors with the veto error from then on.
     pub fn poll_read<R: AsyncRead>(&mut self,
                                    cx: &mut Context,
                                    buf: &mut [u8],
@@ -54,21 +88,71 @@ impl Decryptor {
                                    key: &secretbox::Key,
                                    nonce: &mut secretbox::Nonce)
                                    -> Poll<usize, Error> {
+        if let Some((kind, ref message)) = self.vetoed {
+            return Err(Error::new(kind, message.clone()));
+        }
+        if let Some(ref deadlines) = self.deadlines {
+            deadlines.check()?;
+        }
+
+        let result = self.poll_read_state(cx, buf, reader, key, nonce);
+        let phase = self.phase();
+        match self.deadlines {
+            Some(ref mut deadlines) => deadlines.poll(cx, phase, result),
+            None => result,
+        }
+    }
+
+    // The phase of the stream the deadlines apply to.
+    fn phase(&self) -> Phase {
+        // Waiting for the rate limiter is not waiting for the peer.
+        match self.shaper {
+            Some(ref shaper) if shaper.waiting => return Phase::Buffered,
+            _ => {}
+        }
+
+        let packet = self.recorder.stats().packets;
+        match self.state {
+            ReadCypherHeader { offset: 0 } => Phase::Idle(packet),
+            ReadCypherHeader { .. } |
+            ReadCypherPacket { .. } => Phase::Packet(packet),
+            Readable { .. } => Phase::Buffered,
+        }
+    }
+
+    fn poll_read_state<R: AsyncRead>(&mut self,
+                                     cx: &mut Context,
+                                     buf: &mut [u8],
+                                     reader: &mut R,
+                                     key: &secretbox::Key,
+                                     nonce: &mut secretbox::Nonce)
+                                     -> Poll<usize, Error> {
         match self.state {
             ReadCypherHeader { offset } => {
                 debug_assert!(offset < CYPHER_HEADER_SIZE_U16);
 
-                let new_offset = offset +
-                                 (try_ready!(poll_read_nonzero(reader,
-                                                               cx,
-                                                               &mut self.buffer
-                                                                        [(offset as usize)..
-                                                                    CYPHER_HEADER_SIZE])) as
-                                  u16);
+                if offset == 0 {
+                    if let Some(ref mut shaper) = self.shaper {
+                        try_ready!(shaper.poll_reserve(cx, 1));
+                    }
+                }
+
+                let read = try_ready!(poll_read_nonzero(reader,
+                                                        cx,
+                                                        &mut self.buffer[(offset as usize)..
+                                                             CYPHER_HEADER_SIZE]));
+                self.recorder.cypher_bytes(read);
+                let new_offset = offset + read as u16;
+                state_event!(&self.span,
+                             TRACE,
+                             state = "ReadCypherHeader",
+                             packet = self.recorder.stats().packets,
+                             offset = new_offset,
+                             "read header bytes");
 
                 if new_offset < CYPHER_HEADER_SIZE_U16 {
                     self.state = ReadCypherHeader { offset: new_offset };
-                    return self.poll_read(cx, buf, reader, key, nonce);
+                    return self.poll_read_state(cx, buf, reader, key, nonce);
                 } else {
                     let is_header_valid =
                         unsafe {
@@ -82,20 +166,49 @@ impl Decryptor {
                         let plain_header = unsafe { self.plain_header() };
 
                         if plain_header.is_final_header() {
+                            if let Some(ref mut shaper) = self.shaper {
+                                let cost = shaper.final_header_cost();
+                                shaper.charge(cost);
+                            }
+                            self.recorder.final_header();
+                            state_event!(&self.span,
+                                         DEBUG,
+                                         state = "ReadCypherHeader",
+                                         packets = self.recorder.stats().packets,
+                                         "authenticated final header");
                             return Ok(Ready(0));
                         } else {
                             let len = plain_header.get_packet_len();
                             if len > MAX_PACKET_SIZE || len == 0 {
+                                self.recorder.auth_failure();
+                                state_event!(&self.span,
+                                             DEBUG,
+                                             state = "ReadCypherHeader",
+                                             packet = self.recorder.stats().packets,
+                                             len = len,
+                                             error = INVALID_LENGTH);
                                 return Err(Error::new(ErrorKind::InvalidData, INVALID_LENGTH));
                             } else {
+                                state_event!(&self.span,
+                                             TRACE,
+                                             state = "ReadCypherHeader",
+                                             packet = self.recorder.stats().packets,
+                                             len = len,
+                                             "authenticated header");
                                 self.state = ReadCypherPacket {
                                     offset: 0,
                                     length: len,
                                 };
-                                return self.poll_read(cx, buf, reader, key, nonce);
+                                return self.poll_read_state(cx, buf, reader, key, nonce);
                             }
                         }
                     } else {
+                        self.recorder.auth_failure();
+                        state_event!(&self.span,
+                                     DEBUG,
+                                     state = "ReadCypherHeader",
+                                     packet = self.recorder.stats().packets,
+                                     error = UNAUTHENTICATED_HEADER);
                         return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_HEADER));
                     }
                 }
@@ -105,20 +218,27 @@ impl Decryptor {
                 debug_assert!(offset < length);
                 debug_assert!(length <= MAX_PACKET_SIZE);
 
-                let new_offset =
-                    offset +
-                    (try_ready!(poll_read_nonzero(reader,
-                                                  cx,
-                                                  &mut self.buffer[CYPHER_HEADER_SIZE + (offset as usize)..
-                                                       CYPHER_HEADER_SIZE +
-                                                       (length as usize)])) as
-                     u16);
+                let read = try_ready!(poll_read_nonzero(reader,
+                                                        cx,
+                                                        &mut self.buffer[CYPHER_HEADER_SIZE +
+                                                             (offset as usize)..
+                                                             CYPHER_HEADER_SIZE +
+                                                             (length as usize)]));
+                self.recorder.cypher_bytes(read);
+                let new_offset = offset + read as u16;
+                state_event!(&self.span,
+                             TRACE,
+                             state = "ReadCypherPacket",
+                             packet = self.recorder.stats().packets,
+                             offset = new_offset,
+                             len = length,
+                             "read packet bytes");
                 if new_offset < length {
                     self.state = ReadCypherPacket {
                         offset: new_offset,
                         length: length,
                     };
-                    return self.poll_read(cx, buf, reader, key, nonce);
+                    return self.poll_read_state(cx, buf, reader, key, nonce);
                 } else {
                     let plain_header = unsafe { self.plain_header() };
 
@@ -132,12 +252,44 @@ impl Decryptor {
                     };
 
                     if is_packet_valid {
+                        let observed = observe(&self.observer,
+                                               PacketInfo {
+                                                   index: self.recorder.stats().packets,
+                                                   len: length,
+                                                   direction: PacketDirection::Read,
+                                               });
+                        if let Err(e) = observed {
+                            memzero(&mut self.buffer);
+                            self.recorder.packet(length);
+                            self.state = ReadCypherHeader { offset: 0 };
+                            self.vetoed = Some((e.kind(), e.to_string()));
+                            return Err(e);
+                        }
+
+                        state_event!(&self.span,
+                                     TRACE,
+                                     state = "ReadCypherPacket",
+                                     packet = self.recorder.stats().packets,
+                                     len = length,
+                                     "authenticated packet");
+                        if let Some(ref mut shaper) = self.shaper {
+                            let cost = shaper.packet_cost(length);
+                            shaper.charge(cost);
+                        }
+                        self.recorder.packet(length);
                         self.state = Readable {
                             offset: 0,
                             length: length,
                         };
-                        return self.poll_read(cx, buf, reader, key, nonce);
+                        return self.poll_read_state(cx, buf, reader, key, nonce);
                     } else {
+                        self.recorder.auth_failure();
+                        state_event!(&self.span,
+                                     DEBUG,
+                                     state = "ReadCypherPacket",
+                                     packet = self.recorder.stats().packets,
+                                     len = length,
+                                     error = UNAUTHENTICATED_PACKET);
                         return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_PACKET));
                     }
                 }
@@ -164,6 +316,12 @@ impl Decryptor {
                         length: length,
                     };
                 } else {
+                    state_event!(&self.span,
+                                 TRACE,
+                                 state = "Readable",
+                                 packet = self.recorder.stats().packets - 1,
+                                 len = length,
+                                 "packet consumed");
                     self.state = ReadCypherHeader { offset: 0 }
                 }
 
@@ -172,6 +330,65 @@ impl Decryptor {
         }
     }
 
+    // Like `poll_read`, but discards the plaintext of the next packet (zeroing it) as soon as it has
+    // been authenticated, instead of copying it anywhere.
+    //
+    // Returns the length of the packet, or Ok(0) if a final header was read.
+    pub fn poll_skip_packet<R: AsyncRead>(&mut self,
+                                          cx: &mut Context,
+                                          reader: &mut R,
+                                          key: &secretbox::Key,
+                                          nonce: &mut secretbox::Nonce)
+                                          -> Poll<u16, Error> {
+        match self.state {
+            Readable { .. } => {}
+            _ => {
+                // With an empty buffer, this only returns once the state is `Readable`, or once a
+                // final header has been read.
+                try_ready!(self.poll_read(cx, &mut [], reader, key, nonce));
+            }
+        }
+
+        match self.state {
+            Readable { offset, length } => {
+                memzero(&mut self.buffer[CYPHER_HEADER_SIZE..CYPHER_HEADER_SIZE + length as usize]);
+                self.state = ReadCypherHeader { offset: 0 };
+                Ok(Ready(length - offset))
+            }
+            _ => Ok(Ready(0)),
+        }
+    }
+
+    // Like `poll_skip_packet`, but returns the plaintext of the next packet (or the part of it
+    // that has not been read yet), zeroing the buffer.
+    //
+    // Returns None if a final header was read.
+    pub fn poll_packet<R: AsyncRead>(&mut self,
+                                     cx: &mut Context,
+                                     reader: &mut R,
+                                     key: &secretbox::Key,
+                                     nonce: &mut secretbox::Nonce)
+                                     -> Poll<Option<Vec<u8>>, Error> {
+        match self.state {
+            Readable { .. } => {}
+            _ => {
+                try_ready!(self.poll_read(cx, &mut [], reader, key, nonce));
+            }
+        }
+
+        match self.state {
+            Readable { offset, length } => {
+                let packet = &mut self.buffer[CYPHER_HEADER_SIZE..CYPHER_HEADER_SIZE +
+                                                                 length as usize];
+                let plaintext = packet[offset as usize..].to_vec();
+                memzero(packet);
+                self.state = ReadCypherHeader { offset: 0 };
+                Ok(Ready(Some(plaintext)))
+            }
+            _ => Ok(Ready(None)),
+        }
+    }
+
     // This unsafely casts the first 2 + secretbox::MACBYTES bytes of the buffer as a PlainHeader.
     // Everything goes horribly wrong if these bytes don't actually contain a decrypted header.
     unsafe fn plain_header(&self) -> PlainHeader {
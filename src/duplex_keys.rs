@@ -0,0 +1,117 @@
+// Derivation of the keys and nonces of a BoxDuplex from the secrets of a secret-handshake, or
+// from a pre-shared key.
+
+use sodiumoxide::crypto::{auth, box_, secretbox, sign};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::utils::memzero;
+
+/// The keys and nonces for both directions of a `BoxDuplex`.
+pub struct DuplexKeys {
+    /// The key for encrypting outgoing data.
+    pub encryption_key: secretbox::Key,
+    /// The key for decrypting incoming data.
+    pub decryption_key: secretbox::Key,
+    /// The initial nonce for encrypting outgoing data.
+    pub encryption_nonce: secretbox::Nonce,
+    /// The initial nonce for decrypting incoming data.
+    pub decryption_nonce: secretbox::Nonce,
+}
+
+impl DuplexKeys {
+    /// Derive the keys and nonces of a box stream from the outcome of a
+    /// [secret-handshake](https://github.com/auditdrivencrypto/secret-handshake).
+    ///
+    /// `shared_secret` is the final secret both peers agree on, i.e.
+    /// `sha256(network_key ++ ab ++ aB ++ Ab)`. The keys are oriented towards the
+    /// local peer: data is encrypted with `sha256(sha256(shared_secret) ++ remote_pk)`
+    /// and the first 24 bytes of `hmac(network_key, remote_eph_pk)` as the nonce,
+    /// and decrypted with the same values computed from the local keys. This is
+    /// the same for client and server.
+    pub fn from_shs_secrets(network_key: &auth::Key,
+                            shared_secret: &[u8; 32],
+                            local_pk: &sign::PublicKey,
+                            local_eph_pk: &box_::PublicKey,
+                            remote_pk: &sign::PublicKey,
+                            remote_eph_pk: &box_::PublicKey)
+                            -> DuplexKeys {
+        let mut hashed_secret = sha256::hash(shared_secret).0;
+
+        let keys = DuplexKeys {
+            encryption_key: key(&hashed_secret, remote_pk),
+            decryption_key: key(&hashed_secret, local_pk),
+            encryption_nonce: nonce(network_key, remote_eph_pk),
+            decryption_nonce: nonce(network_key, local_eph_pk),
+        };
+
+        memzero(&mut hashed_secret);
+        keys
+    }
+
+    /// Derive the keys and nonces of a box stream from a pre-shared key and a
+    /// random nonce chosen by each peer for its outgoing data.
+    ///
+    /// Both peers must have sent their nonce to each other (e.g. in plaintext),
+    /// and exactly one of them must be the `client`. The key for data sent by
+    /// the client is `hmac(psk, "box-stream psk client" ++ client_nonce ++
+    /// server_nonce)`, the key for data sent by the server is derived the same
+    /// way from `"box-stream psk server"`. Since both nonces enter both keys,
+    /// streams can neither be reflected back to their sender nor be replayed to
+    /// a peer that chose a fresh nonce.
+    pub fn from_pre_shared_key(psk: &secretbox::Key,
+                               local_nonce: &secretbox::Nonce,
+                               remote_nonce: &secretbox::Nonce,
+                               client: bool)
+                               -> DuplexKeys {
+        let (client_nonce, server_nonce) = if client {
+            (local_nonce, remote_nonce)
+        } else {
+            (remote_nonce, local_nonce)
+        };
+
+        let mut psk = auth::Key(psk.0);
+        let client_key = psk_key(&psk, b"box-stream psk client", client_nonce, server_nonce);
+        let server_key = psk_key(&psk, b"box-stream psk server", client_nonce, server_nonce);
+        memzero(&mut psk.0);
+
+        let (encryption_key, decryption_key) = if client {
+            (client_key, server_key)
+        } else {
+            (server_key, client_key)
+        };
+
+        DuplexKeys {
+            encryption_key,
+            decryption_key,
+            encryption_nonce: local_nonce.clone(),
+            decryption_nonce: remote_nonce.clone(),
+        }
+    }
+}
+
+fn psk_key(psk: &auth::Key,
+           label: &[u8],
+           client_nonce: &secretbox::Nonce,
+           server_nonce: &secretbox::Nonce)
+           -> secretbox::Key {
+    let mut input = Vec::with_capacity(label.len() + 2 * secretbox::NONCEBYTES);
+    input.extend_from_slice(label);
+    input.extend_from_slice(&client_nonce.0);
+    input.extend_from_slice(&server_nonce.0);
+
+    secretbox::Key(auth::authenticate(&input, psk).0)
+}
+
+fn key(hashed_secret: &[u8; 32], pk: &sign::PublicKey) -> secretbox::Key {
+    let mut input = [0u8; 32 + sign::PUBLICKEYBYTES];
+    input[..32].copy_from_slice(hashed_secret);
+    input[32..].copy_from_slice(&pk.0);
+
+    let key = secretbox::Key(sha256::hash(&input).0);
+    memzero(&mut input);
+    key
+}
+
+fn nonce(network_key: &auth::Key, eph_pk: &box_::PublicKey) -> secretbox::Nonce {
+    let tag = auth::authenticate(&eph_pk.0, network_key);
+    secretbox::Nonce::from_slice(&tag.0[..secretbox::NONCEBYTES]).unwrap()
+}
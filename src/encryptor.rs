@@ -6,8 +6,14 @@ use futures_core::task::Context;
 use futures_io::{Error, AsyncWrite, ErrorKind};
 use sodiumoxide::crypto::secretbox;
 use sodiumoxide::utils::memzero;
+#[cfg(feature = "tracing")]
+use tracing::Span;
 
-use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_SIZE, MAX_PACKET_USIZE, encrypt_packet, final_header};
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_USIZE, encrypt_packet, final_header};
+use observer::{PacketDirection, PacketInfo, SharedObserver, observe};
+use rate_limit::Shaper;
+use stats::{Direction, Recorder};
+use timeout::{Deadlines, Phase};
 
 const BUFFER_SIZE: usize = CYPHER_HEADER_SIZE + MAX_PACKET_USIZE;
 
@@ -16,6 +22,16 @@ pub struct Encryptor {
     // Bytes are written into this buffer and get encrypted in-place
     buffer: [u8; BUFFER_SIZE],
     state: State,
+    pub recorder: Recorder,
+    pub observer: Option<SharedObserver>,
+    pub deadlines: Option<Deadlines>,
+    pub shaper: Option<Shaper>,
+    // The kind and message of the error with which the observer vetoed a packet. Every write, flush
+    // and close fails with this error once it is set.
+    vetoed: Option<(ErrorKind, String)>,
+    // The parent of all events emitted by this encryptor.
+    #[cfg(feature = "tracing")]
+    pub span: Span,
 }
 
 impl Encryptor {
@@ -23,10 +39,26 @@ impl Encryptor {
         Encryptor {
             buffer: [0; BUFFER_SIZE],
             state: Writable,
+            recorder: Recorder::new(Direction::Written),
+            observer: None,
+            deadlines: None,
+            shaper: None,
+            vetoed: None,
+            #[cfg(feature = "tracing")]
+            span: debug_span!("box_stream_encryptor"),
         }
     }
 
+    #[cfg(feature = "tracing")]
+    pub fn set_parent_span(&mut self, parent: &Span) {
+        self.span = debug_span!(parent: parent, "box_stream_encryptor");
+    }
+
     // A Write wrapper using the encryptor should delegate to this method in its `write` implementation.
+    //
+    // Once a deadline has passed, this, `poll_flush` and `poll_close` error with the timeout error
+    // from then on. Likewise, once the observer vetoed a packet, they error with the veto error from
+    // then on.
     pub fn poll_write<W: AsyncWrite>(&mut self,
                                      cx: &mut Context,
                                      buf: &[u8],
@@ -34,9 +66,114 @@ impl Encryptor {
                                      key: &secretbox::Key,
                                      nonce: &mut secretbox::Nonce)
                                      -> Poll<usize, Error> {
+        self.check_vetoed()?;
+        if let Some(ref deadlines) = self.deadlines {
+            deadlines.check()?;
+        }
+
+        let result = self.poll_write_state(cx, buf, writer, key, nonce);
+        self.track(cx, result)
+    }
+
+    pub fn poll_flush<W: AsyncWrite>(&mut self,
+                                     cx: &mut Context,
+                                     writer: &mut W,
+                                     key: &secretbox::Key,
+                                     nonce: &mut secretbox::Nonce)
+                                     -> Poll<(), Error> {
+        self.check_vetoed()?;
+        if let Some(ref deadlines) = self.deadlines {
+            deadlines.check()?;
+        }
+
+        let result = self.poll_flush_state(cx, writer, key, nonce);
+        self.track(cx, result)
+    }
+
+    pub fn poll_close<W: AsyncWrite>(&mut self,
+                                     cx: &mut Context,
+                                     writer: &mut W,
+                                     key: &secretbox::Key,
+                                     nonce: &mut secretbox::Nonce)
+                                     -> Poll<(), Error> {
+        self.check_vetoed()?;
+        if let Some(ref mut deadlines) = self.deadlines {
+            deadlines.check()?;
+            deadlines.closing = true;
+        }
+
+        let result = self.poll_close_state(cx, writer, key, nonce);
+        self.track(cx, result)
+    }
+
+    // Fail with the veto error if the observer vetoed a packet.
+    fn check_vetoed(&self) -> Result<(), Error> {
+        match self.vetoed {
+            Some((kind, ref message)) => Err(Error::new(kind, message.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // Apply the deadlines to the result of polling the state machine.
+    fn track<T>(&mut self, cx: &mut Context, result: Poll<T, Error>) -> Poll<T, Error> {
+        let phase = self.phase();
+        match self.deadlines {
+            Some(ref mut deadlines) => deadlines.poll(cx, phase, result),
+            None => result,
+        }
+    }
+
+    // The phase of the stream the deadlines apply to.
+    fn phase(&self) -> Phase {
+        // Waiting for the rate limiter is not waiting for the peer.
+        match self.shaper {
+            Some(ref shaper) if shaper.waiting => return Phase::Buffered,
+            _ => {}
+        }
+
+        match self.deadlines {
+            Some(ref deadlines) if deadlines.closing => return Phase::Close,
+            _ => {}
+        }
+
+        match self.state {
+            Writable => Phase::Buffered,
+            WriteInner { .. } => Phase::Packet(self.recorder.stats().packets),
+            Shutdown { .. } => Phase::Close,
+        }
+    }
+
+    fn poll_write_state<W: AsyncWrite>(&mut self,
+                                       cx: &mut Context,
+                                       buf: &[u8],
+                                       writer: &mut W,
+                                       key: &secretbox::Key,
+                                       nonce: &mut secretbox::Nonce)
+                                       -> Poll<usize, Error> {
         match self.state {
             Writable => {
-                let written = min(buf.len() as u16, MAX_PACKET_SIZE);
+                // Cap before converting, lengths of 65536 and above would wrap around.
+                let written = min(buf.len(), MAX_PACKET_USIZE) as u16;
+                if let Some(ref mut shaper) = self.shaper {
+                    let cost = shaper.packet_cost(written);
+                    try_ready!(shaper.poll_reserve(cx, cost));
+                }
+
+                let observed = observe(&self.observer,
+                                       PacketInfo {
+                                           index: self.recorder.stats().packets,
+                                           len: written,
+                                           direction: PacketDirection::Written,
+                                       });
+                if let Err(e) = observed {
+                    self.vetoed = Some((e.kind(), e.to_string()));
+                    return Err(e);
+                }
+
+                if let Some(ref mut shaper) = self.shaper {
+                    let cost = shaper.packet_cost(written);
+                    shaper.charge(cost);
+                }
 
                 unsafe {
                     encrypt_packet(self.buffer.as_mut_ptr(),
@@ -45,6 +182,13 @@ impl Encryptor {
                                    &key.0,
                                    &mut nonce.0);
                 }
+                state_event!(&self.span,
+                             TRACE,
+                             state = "Writable",
+                             packet = self.recorder.stats().packets,
+                             len = written,
+                             "sealed packet");
+                self.recorder.packet(written);
 
                 self.state = WriteInner {
                     offset: 0,
@@ -66,6 +210,14 @@ impl Encryptor {
                 if written == 0 {
                     return Err(Error::new(ErrorKind::WriteZero, "failed to write data"));
                 } else {
+                    self.recorder.cypher_bytes(written);
+                    state_event!(&self.span,
+                                 TRACE,
+                                 state = "WriteInner",
+                                 packet = self.recorder.stats().packets - 1,
+                                 offset = offset + written as u16,
+                                 len = length,
+                                 "wrote packet bytes");
                     if offset + (written as u16) < length + CYPHER_HEADER_SIZE as u16 {
                         self.state = WriteInner {
                             offset: offset + (written as u16),
@@ -76,7 +228,7 @@ impl Encryptor {
                     }
                 }
 
-                return self.poll_write(cx, buf, writer, key, nonce);
+                return self.poll_write_state(cx, buf, writer, key, nonce);
             }
 
             Shutdown { offset: _ } => {
@@ -85,12 +237,12 @@ impl Encryptor {
         }
     }
 
-    pub fn poll_flush<W: AsyncWrite>(&mut self,
-                                     cx: &mut Context,
-                                     writer: &mut W,
-                                     key: &secretbox::Key,
-                                     nonce: &mut secretbox::Nonce)
-                                     -> Poll<(), Error> {
+    fn poll_flush_state<W: AsyncWrite>(&mut self,
+                                       cx: &mut Context,
+                                       writer: &mut W,
+                                       key: &secretbox::Key,
+                                       nonce: &mut secretbox::Nonce)
+                                       -> Poll<(), Error> {
         match self.state {
             Writable => {
                 return writer.poll_flush(cx);
@@ -108,6 +260,14 @@ impl Encryptor {
                 if written == 0 {
                     return Err(Error::new(ErrorKind::WriteZero, "failed to write buffered data"));
                 } else {
+                    self.recorder.cypher_bytes(written);
+                    state_event!(&self.span,
+                                 TRACE,
+                                 state = "WriteInner",
+                                 packet = self.recorder.stats().packets - 1,
+                                 offset = offset + written as u16,
+                                 len = length,
+                                 "wrote packet bytes");
                     if offset + (written as u16) < length + CYPHER_HEADER_SIZE as u16 {
                         self.state = WriteInner {
                             offset: offset + (written as u16),
@@ -118,7 +278,7 @@ impl Encryptor {
                     }
                 }
 
-                return self.poll_flush(cx, writer, key, nonce);
+                return self.poll_flush_state(cx, writer, key, nonce);
             }
 
             Shutdown { offset } => {
@@ -130,6 +290,12 @@ impl Encryptor {
                 if written == 0 {
                     return Err(Error::new(ErrorKind::WriteZero, "failed to write final packet"));
                 } else {
+                    self.recorder.cypher_bytes(written);
+                    state_event!(&self.span,
+                                 TRACE,
+                                 state = "Shutdown",
+                                 offset = offset + written as u16,
+                                 "wrote final header bytes");
                     if offset + (written as u16) < CYPHER_HEADER_SIZE as u16 {
                         self.state = Shutdown { offset: offset + (written as u16) };
                     } else {
@@ -137,38 +303,50 @@ impl Encryptor {
                     }
                 }
 
-                return self.poll_flush(cx, writer, key, nonce);
+                return self.poll_flush_state(cx, writer, key, nonce);
             }
         }
     }
 
-    pub fn poll_close<W: AsyncWrite>(&mut self,
-                                     cx: &mut Context,
-                                     writer: &mut W,
-                                     key: &secretbox::Key,
-                                     nonce: &mut secretbox::Nonce)
-                                     -> Poll<(), Error> {
+    fn poll_close_state<W: AsyncWrite>(&mut self,
+                                       cx: &mut Context,
+                                       writer: &mut W,
+                                       key: &secretbox::Key,
+                                       nonce: &mut secretbox::Nonce)
+                                       -> Poll<(), Error> {
         match self.state {
             Writable => {
+                if let Some(ref mut shaper) = self.shaper {
+                    let cost = shaper.final_header_cost();
+                    try_ready!(shaper.poll_reserve(cx, cost));
+                    shaper.charge(cost);
+                }
+
                 unsafe {
                     final_header(&mut *(self.buffer.as_mut_ptr() as *mut [u8; CYPHER_HEADER_SIZE]),
                                  &key.0,
                                  &nonce.0);
                 }
+                self.recorder.final_header();
+                state_event!(&self.span,
+                             DEBUG,
+                             state = "Writable",
+                             packets = self.recorder.stats().packets,
+                             "sealed final header");
                 self.state = Shutdown { offset: 0 };
-                return self.poll_flush(cx, writer, key, nonce);
+                return self.poll_flush_state(cx, writer, key, nonce);
             }
 
             WriteInner {
                 offset: _,
                 length: _,
             } => {
-                let _ = try_ready!(self.poll_flush(cx, writer, key, nonce));
+                let _ = try_ready!(self.poll_flush_state(cx, writer, key, nonce));
                 debug_assert!(self.state == Writable);
-                return self.poll_close(cx, writer, key, nonce);
+                return self.poll_close_state(cx, writer, key, nonce);
             }
 
-            Shutdown { offset: _ } => return self.poll_flush(cx, writer, key, nonce),
+            Shutdown { offset: _ } => return self.poll_flush_state(cx, writer, key, nonce),
         }
 
     }
@@ -0,0 +1,681 @@
+//! A self-describing container format for box streams stored in files.
+//!
+//! A box-stream file consists of a header followed by the box stream:
+//!
+//! - 8 bytes of magic: `box-strm`
+//! - 1 byte format version (currently 1)
+//! - 1 byte length of the key id, followed by the key id itself
+//! - the 24 byte initial nonce of the box stream (generated randomly)
+//! - the box stream, including its final header
+//!
+//! The key id is not secret, it is used to look up the key for decryption.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use futures_core::Poll;
+use futures_core::Async::Ready;
+use futures_core::task::Context;
+use futures_io::{Error, ErrorKind, AsyncRead, AsyncWrite};
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+
+use blocking::read_until_eof;
+use box_reader::BoxReader;
+use box_writer::BoxWriter;
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_SIZE, MAX_PACKET_USIZE, PlainHeader, decrypt_header,
+             decrypt_packet_inplace, packet_nonce};
+use decryptor::{INVALID_LENGTH, TRAILING_DATA, UNAUTHENTICATED_EOF, UNAUTHENTICATED_HEADER,
+                UNAUTHENTICATED_PACKET};
+use reencrypt::reencrypt;
+use sync_box_reader::SyncBoxReader;
+use sync_box_writer::SyncBoxWriter;
+
+/// The magic bytes at the start of every box-stream file.
+pub const MAGIC: [u8; 8] = *b"box-strm";
+
+/// The version of the format written by this module.
+pub const VERSION: u8 = 1;
+
+/// The maximum length of a key id.
+pub const MAX_KEY_ID_LEN: usize = 255;
+
+/// The error value signaling that a file does not start with `MAGIC`.
+pub const UNKNOWN_FORMAT: &'static str = "not a box-stream file";
+
+/// The error value signaling that a file uses an unsupported format version.
+pub const UNSUPPORTED_VERSION: &'static str = "unsupported box-stream file version";
+
+/// The error value signaling that a file ended within its header.
+pub const TRUNCATED_HEADER: &'static str = "box-stream file ended within its header";
+
+/// The error value signaling that no key is known for the key id of a file.
+pub const UNKNOWN_KEY: &'static str = "no key for the key id of the box-stream file";
+
+/// The error value signaling that a key id is longer than `MAX_KEY_ID_LEN`.
+pub const KEY_ID_TOO_LONG: &'static str = "key id too long";
+
+// Magic, version and key id length.
+const PREFIX_LEN: usize = 8 + 1 + 1;
+
+/// The header of a box-stream file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileHeader {
+    /// Identifies the key used to encrypt the file.
+    pub key_id: Vec<u8>,
+    /// The initial nonce of the box stream.
+    pub nonce: secretbox::Nonce,
+}
+
+impl FileHeader {
+    /// Returns the encoded header.
+    ///
+    /// # Errors
+    /// Errors with `ErrorKind::InvalidInput` and `KEY_ID_TOO_LONG` if the key id
+    /// exceeds `MAX_KEY_ID_LEN` bytes.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        if self.key_id.len() > MAX_KEY_ID_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, KEY_ID_TOO_LONG));
+        }
+
+        let mut out = Vec::with_capacity(PREFIX_LEN + self.key_id.len() + secretbox::NONCEBYTES);
+        out.extend_from_slice(&MAGIC);
+        out.push(VERSION);
+        out.push(self.key_id.len() as u8);
+        out.extend_from_slice(&self.key_id);
+        out.extend_from_slice(&self.nonce.0);
+        Ok(out)
+    }
+
+    /// Returns the length of the encoded header.
+    pub fn encoded_len(&self) -> usize {
+        PREFIX_LEN + self.key_id.len() + secretbox::NONCEBYTES
+    }
+}
+
+/// Read and parse the header of a box-stream file from `reader`.
+///
+/// # Errors
+/// Errors with `ErrorKind::InvalidData` and `UNKNOWN_FORMAT` or
+/// `UNSUPPORTED_VERSION` if the file is not a box-stream file of a supported
+/// version, and with `ErrorKind::UnexpectedEof` and `TRUNCATED_HEADER` if the
+/// reader ends within the header.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<FileHeader, Error> {
+    let mut header = vec![0u8; PREFIX_LEN];
+    read_exact_header(reader, &mut header)?;
+
+    let len = header_len(&header)?;
+    header.resize(len, 0);
+    read_exact_header(reader, &mut header[PREFIX_LEN..])?;
+
+    Ok(parse_header(&header))
+}
+
+// Checks magic and version, and returns the total length of the header given its prefix.
+fn header_len(prefix: &[u8]) -> Result<usize, Error> {
+    if prefix[..8] != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, UNKNOWN_FORMAT));
+    }
+    if prefix[8] != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, UNSUPPORTED_VERSION));
+    }
+    Ok(PREFIX_LEN + prefix[9] as usize + secretbox::NONCEBYTES)
+}
+
+// Parses a complete header whose prefix has already been checked with `header_len`.
+fn parse_header(header: &[u8]) -> FileHeader {
+    let key_id_end = PREFIX_LEN + header[9] as usize;
+    FileHeader {
+        key_id: header[PREFIX_LEN..key_id_end].to_vec(),
+        nonce: secretbox::Nonce::from_slice(&header[key_id_end..]).unwrap(),
+    }
+}
+
+fn read_exact_header<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    reader
+        .read_exact(buf)
+        .map_err(|e| if e.kind() == ErrorKind::UnexpectedEof {
+                     Error::new(ErrorKind::UnexpectedEof, TRUNCATED_HEADER)
+                 } else {
+                     e
+                 })
+}
+
+/// Re-encrypt the box-stream file at `path` under `key` with the given
+/// `key_id` and a random nonce, looking up the old key via `keys`.
+///
+/// The new file is written next to the old one (with `.tmp` appended to its
+/// name), synced to disk, and then atomically renamed to `path`. On unix, the
+/// directory is synced after the rename as well. The old file is only
+/// replaced if it has been authenticated completely.
+///
+/// # Errors
+/// Propagates the errors of `BoxFileReader::open` and `reencrypt`. Errors with
+/// `ErrorKind::AlreadyExists` if the temporary file exists already, e.g. after
+/// an interrupted re-encryption. The old file is left unchanged on errors,
+/// except for errors syncing the directory, which happen after the rename.
+pub fn reencrypt_file<P, F>(path: P,
+                            keys: F,
+                            key_id: &[u8],
+                            key: secretbox::Key)
+                            -> Result<(), Error>
+    where P: AsRef<Path>,
+          F: FnOnce(&[u8]) -> Option<secretbox::Key>
+{
+    let path = path.as_ref();
+    let mut tmp_path = OsString::from(path.as_os_str());
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut old = File::open(path)?;
+    let old_header = read_header(&mut old)?;
+    let old_key = keys(&old_header.key_id)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, UNKNOWN_KEY))?;
+
+    let new_header = FileHeader {
+        key_id: key_id.to_vec(),
+        nonce: secretbox::gen_nonce(),
+    };
+    let encoded_header = new_header.encode()?;
+
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+
+    let result = tmp.write_all(&encoded_header)
+        .and_then(|_| {
+                      reencrypt(&mut old,
+                                &old_key,
+                                &old_header.nonce,
+                                &mut tmp,
+                                &key,
+                                &new_header.nonce)
+                  })
+        .and_then(|_| tmp.set_permissions(old.metadata()?.permissions()))
+        .and_then(|_| tmp.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return result;
+    }
+    sync_parent(path)
+}
+
+// Sync the directory containing `path`, so that a rename to `path` survives a crash.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<(), Error> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+// Directories can not be opened as files on other platforms.
+#[cfg(not(unix))]
+fn sync_parent(_: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Writes a box-stream file to a blocking writer, encrypting all writes.
+///
+/// The box stream is written in fixed-size mode (see `SyncBoxWriter`), so the
+/// file can be read with a `SeekableBoxReader` after skipping its header. This
+/// does not hold for files that have been continued via `append`.
+pub struct BoxFileWriter<W> {
+    writer: SyncBoxWriter<W>,
+}
+
+impl<W: Write> BoxFileWriter<W> {
+    /// Write the header of a new box-stream file to `inner`, using `key` with
+    /// the given `key_id` and a random nonce.
+    pub fn create(inner: W, key_id: &[u8], key: secretbox::Key) -> Result<BoxFileWriter<W>, Error> {
+        BoxFileWriter::with_nonce(inner, key_id, key, secretbox::gen_nonce())
+    }
+
+    /// Same as `create`, but uses the given `nonce` instead of a random one.
+    ///
+    /// Never use the same key and nonce for two different files.
+    pub fn with_nonce(mut inner: W,
+                      key_id: &[u8],
+                      key: secretbox::Key,
+                      nonce: secretbox::Nonce)
+                      -> Result<BoxFileWriter<W>, Error> {
+        let header = FileHeader {
+            key_id: key_id.to_vec(),
+            nonce,
+        };
+        inner.write_all(&header.encode()?)?;
+
+        Ok(BoxFileWriter { writer: SyncBoxWriter::fixed_size(inner, key, header.nonce) })
+    }
+
+    /// Write all buffered data and the final header of the box stream. The file
+    /// is incomplete unless this is called.
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.writer.close()
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+
+    /// Unwraps this `BoxFileWriter`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+impl BoxFileWriter<File> {
+    /// Open the box-stream file `file` for appending, looking up the key for
+    /// its key id via `keys`. The file must be opened for reading and writing.
+    ///
+    /// The whole box stream is authenticated, then its final header is removed
+    /// from the file, and the returned writer continues the box stream. Unlike
+    /// a newly created file, `flush` writes all buffered data to the file.
+    ///
+    /// If appending is interrupted before `close` is called, the file ends
+    /// without a final header, possibly within a packet. Such a file can be
+    /// continued with `recover`.
+    ///
+    /// The nonce of the removed final header is used again for the header of
+    /// the next appended packet. The plaintext of a final header is known, so
+    /// anyone holding a copy of the file from before the append can forge that
+    /// header. Do not append to a file if a previous version might have been
+    /// seen by an attacker, copy its data into a new file instead.
+    ///
+    /// # Errors
+    /// Propagates the errors of `BoxFileReader::open` and `SyncBoxReader`.
+    /// Errors with `ErrorKind::InvalidData` and `TRAILING_DATA` if there is
+    /// data after the final header.
+    pub fn append<F>(file: File, keys: F) -> Result<BoxFileWriter<File>, Error>
+        where F: FnOnce(&[u8]) -> Option<secretbox::Key>
+    {
+        let (file, key, nonce, _) = open_for_append(file, keys, false)?;
+        Ok(BoxFileWriter { writer: SyncBoxWriter::new(file, key, nonce) })
+    }
+
+    /// Same as `append`, but also accepts a file whose box stream ends without
+    /// a final header, as left behind by an interrupted append or a crashed
+    /// writer. An incomplete packet at the end of the file is removed, and the
+    /// number of removed bytes is returned along with the writer.
+    ///
+    /// Data that fails to authenticate is never removed, this errors instead.
+    ///
+    /// The nonces of a removed packet are used again for the next appended
+    /// packet. Do not recover a file if the removed bytes might have been seen
+    /// by an attacker, copy the authenticated data into a new file instead.
+    pub fn recover<F>(file: File, keys: F) -> Result<(BoxFileWriter<File>, u64), Error>
+        where F: FnOnce(&[u8]) -> Option<secretbox::Key>
+    {
+        let (file, key, nonce, removed) = open_for_append(file, keys, true)?;
+        Ok((BoxFileWriter { writer: SyncBoxWriter::new(file, key, nonce) }, removed))
+    }
+}
+
+/// Same as `BoxFileWriter::append`, but continues the box stream with a
+/// `BoxWriter`, for writing asynchronously.
+///
+/// The file is authenticated and truncated with blocking io, then passed to
+/// `into_async`, which converts it into the asynchronous writer that is
+/// wrapped, e.g. the file type of the runtime in use. That writer must write
+/// to the end of the file.
+pub fn append_async<F, G, W>(file: File, keys: F, into_async: G) -> Result<BoxWriter<W>, Error>
+    where F: FnOnce(&[u8]) -> Option<secretbox::Key>,
+          G: FnOnce(File) -> W
+{
+    let (file, key, nonce, _) = open_for_append(file, keys, false)?;
+    Ok(BoxWriter::new(into_async(file), key, nonce))
+}
+
+/// Same as `BoxFileWriter::recover`, but continues the box stream with a
+/// `BoxWriter`, like `append_async`.
+pub fn recover_async<F, G, W>(file: File,
+                              keys: F,
+                              into_async: G)
+                              -> Result<(BoxWriter<W>, u64), Error>
+    where F: FnOnce(&[u8]) -> Option<secretbox::Key>,
+          G: FnOnce(File) -> W
+{
+    let (file, key, nonce, removed) = open_for_append(file, keys, true)?;
+    Ok((BoxWriter::new(into_async(file), key, nonce), removed))
+}
+
+// Authenticate the box stream of `file` and remove its end, returning the file positioned for
+// continuing the stream, its key, the nonce of the next packet and the number of removed bytes.
+fn open_for_append<F>(mut file: File,
+                      keys: F,
+                      recover: bool)
+                      -> Result<(File, secretbox::Key, secretbox::Nonce, u64), Error>
+    where F: FnOnce(&[u8]) -> Option<secretbox::Key>
+{
+    file.seek(SeekFrom::Start(0))?;
+    let header = read_header(&mut file)?;
+    let key = keys(&header.key_id)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, UNKNOWN_KEY))?;
+
+    let walk = walk(&mut file, &key, &header.nonce)?;
+    let len = file.seek(SeekFrom::End(0))?;
+
+    let removed = if walk.complete {
+        if len != walk.end + CYPHER_HEADER_SIZE as u64 {
+            return Err(Error::new(ErrorKind::InvalidData, TRAILING_DATA));
+        }
+        0
+    } else if recover {
+        len - walk.end
+    } else {
+        return Err(Error::new(ErrorKind::UnexpectedEof, UNAUTHENTICATED_EOF));
+    };
+
+    // Until the next packet has been written, the file is in the same state as after an
+    // interrupted append.
+    file.set_len(walk.end)?;
+    file.seek(SeekFrom::Start(walk.end))?;
+
+    let nonce = secretbox::Nonce(packet_nonce(&header.nonce.0, walk.packets));
+    Ok((file, key, nonce, removed))
+}
+
+// The result of walking a box stream.
+struct Walk {
+    // The number of authenticated packets.
+    packets: u64,
+    // The offset of the end of the last authenticated packet.
+    end: u64,
+    // Whether the last authenticated packet is followed by the final header.
+    complete: bool,
+}
+
+// Authenticate the box stream at the current position of `file`, stopping at the final header
+// or at a premature end of the file.
+fn walk<R: Read + Seek>(file: &mut R,
+                        key: &secretbox::Key,
+                        nonce: &secretbox::Nonce)
+                        -> Result<Walk, Error> {
+    let mut walk = Walk {
+        packets: 0,
+        end: file.seek(SeekFrom::Current(0))?,
+        complete: false,
+    };
+    let mut buffer = [0u8; MAX_PACKET_USIZE];
+    let result = walk_packets(file, key, nonce, &mut walk, &mut buffer);
+    memzero(&mut buffer);
+    result.map(|_| walk)
+}
+
+fn walk_packets<R: Read>(file: &mut R,
+                         key: &secretbox::Key,
+                         nonce: &secretbox::Nonce,
+                         walk: &mut Walk,
+                         buffer: &mut [u8; MAX_PACKET_USIZE])
+                         -> Result<(), Error> {
+    loop {
+        let mut nonce = packet_nonce(&nonce.0, walk.packets);
+
+        let mut cypher_header = [0u8; CYPHER_HEADER_SIZE];
+        if !read_until_eof(file, &mut cypher_header)? {
+            return Ok(());
+        }
+
+        let mut plain_header = PlainHeader::new();
+        if !unsafe { decrypt_header(&mut plain_header, &cypher_header, &key.0, &mut nonce) } {
+            return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_HEADER));
+        }
+
+        if plain_header.is_final_header() {
+            walk.complete = true;
+            return Ok(());
+        }
+
+        let len = plain_header.get_packet_len();
+        if len > MAX_PACKET_SIZE || len == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, INVALID_LENGTH));
+        }
+
+        if !read_until_eof(file, &mut buffer[..len as usize])? {
+            return Ok(());
+        }
+        if !unsafe {
+                decrypt_packet_inplace(buffer.as_mut_ptr(), &plain_header, &key.0, &mut nonce)
+            } {
+            return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_PACKET));
+        }
+
+        walk.packets += 1;
+        walk.end += (CYPHER_HEADER_SIZE + len as usize) as u64;
+    }
+}
+
+impl<W: Write> Write for BoxFileWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads a box-stream file from a blocking reader, decrypting all reads.
+pub struct BoxFileReader<R> {
+    key_id: Vec<u8>,
+    reader: SyncBoxReader<R>,
+}
+
+impl<R: Read> BoxFileReader<R> {
+    /// Read the header of a box-stream file from `inner`, then look up the key
+    /// for its key id via `keys`.
+    ///
+    /// # Errors
+    /// Propagates the errors of `read_header`. Errors with
+    /// `ErrorKind::NotFound` and `UNKNOWN_KEY` if `keys` returns `None`.
+    pub fn open<F>(mut inner: R, keys: F) -> Result<BoxFileReader<R>, Error>
+        where F: FnOnce(&[u8]) -> Option<secretbox::Key>
+    {
+        let header = read_header(&mut inner)?;
+        let key = keys(&header.key_id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, UNKNOWN_KEY))?;
+
+        Ok(BoxFileReader {
+               reader: SyncBoxReader::new(inner, key, header.nonce),
+               key_id: header.key_id,
+           })
+    }
+
+    /// Returns the key id of the file.
+    pub fn key_id(&self) -> &[u8] {
+        &self.key_id
+    }
+
+    /// Unwraps this `BoxFileReader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+}
+
+impl<R: Read> Read for BoxFileReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Writes a box-stream file to an async writer, encrypting all writes.
+pub struct AsyncBoxFileWriter<W> {
+    header: Vec<u8>,
+    // How much of the header has been written.
+    offset: usize,
+    writer: BoxWriter<W>,
+}
+
+impl<W> AsyncBoxFileWriter<W> {
+    /// Create a new box-stream file on `inner`, using `key` with the given
+    /// `key_id` and a random nonce. The header is written together with the
+    /// first data.
+    pub fn create(inner: W,
+                  key_id: &[u8],
+                  key: secretbox::Key)
+                  -> Result<AsyncBoxFileWriter<W>, Error> {
+        AsyncBoxFileWriter::with_nonce(inner, key_id, key, secretbox::gen_nonce())
+    }
+
+    /// Same as `create`, but uses the given `nonce` instead of a random one.
+    ///
+    /// Never use the same key and nonce for two different files.
+    pub fn with_nonce(inner: W,
+                      key_id: &[u8],
+                      key: secretbox::Key,
+                      nonce: secretbox::Nonce)
+                      -> Result<AsyncBoxFileWriter<W>, Error> {
+        let header = FileHeader {
+            key_id: key_id.to_vec(),
+            nonce,
+        };
+
+        Ok(AsyncBoxFileWriter {
+               header: header.encode()?,
+               offset: 0,
+               writer: BoxWriter::new(inner, key, header.nonce),
+           })
+    }
+
+    /// Unwraps this `AsyncBoxFileWriter`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+impl<W: AsyncWrite> AsyncBoxFileWriter<W> {
+    fn poll_write_header(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        while self.offset < self.header.len() {
+            let written = try_ready!(self.writer
+                                         .get_mut()
+                                         .poll_write(cx, &self.header[self.offset..]));
+            if written == 0 {
+                return Err(Error::new(ErrorKind::WriteZero, "failed to write file header"));
+            }
+            self.offset += written;
+        }
+        Ok(Ready(()))
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for AsyncBoxFileWriter<W> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, Error> {
+        try_ready!(self.poll_write_header(cx));
+        self.writer.poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        try_ready!(self.poll_write_header(cx));
+        self.writer.poll_flush(cx)
+    }
+
+    /// Write the final header of the box stream. The file is incomplete unless
+    /// this is called.
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        try_ready!(self.poll_write_header(cx));
+        self.writer.poll_close(cx)
+    }
+}
+
+/// Reads a box-stream file from an async reader, decrypting all reads.
+///
+/// The header is read on the first call to `poll_read`, `keys` is then called
+/// with the key id to look up the key.
+pub struct AsyncBoxFileReader<R, F> {
+    inner: Option<R>,
+    keys: Option<F>,
+    header: Vec<u8>,
+    // How much of the header has been read.
+    offset: usize,
+    key_id: Option<Vec<u8>>,
+    // Whether `keys` found no key, which fails all reads.
+    unknown_key: bool,
+    reader: Option<BoxReader<R>>,
+}
+
+impl<R, F> AsyncBoxFileReader<R, F>
+    where F: FnOnce(&[u8]) -> Option<secretbox::Key>
+{
+    /// Create a new reader for the box-stream file in `inner`, looking up the
+    /// key via `keys`.
+    pub fn open(inner: R, keys: F) -> AsyncBoxFileReader<R, F> {
+        AsyncBoxFileReader {
+            inner: Some(inner),
+            keys: Some(keys),
+            header: vec![0; PREFIX_LEN],
+            offset: 0,
+            key_id: None,
+            unknown_key: false,
+            reader: None,
+        }
+    }
+
+    /// Returns the key id of the file, once the header has been read.
+    pub fn key_id(&self) -> Option<&[u8]> {
+        self.key_id.as_ref().map(|key_id| key_id.as_slice())
+    }
+}
+
+impl<R: AsyncRead, F> AsyncBoxFileReader<R, F>
+    where F: FnOnce(&[u8]) -> Option<secretbox::Key>
+{
+    fn poll_read_header(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        if self.unknown_key {
+            return Err(Error::new(ErrorKind::NotFound, UNKNOWN_KEY));
+        }
+
+        loop {
+            let len = if self.offset < PREFIX_LEN {
+                PREFIX_LEN
+            } else {
+                header_len(&self.header)?
+            };
+
+            if self.offset == len {
+                break;
+            }
+            self.header.resize(len, 0);
+
+            let read = try_ready!(self.inner
+                                      .as_mut()
+                                      .unwrap()
+                                      .poll_read(cx, &mut self.header[self.offset..len]));
+            if read == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, TRUNCATED_HEADER));
+            }
+            self.offset += read;
+        }
+
+        let header = parse_header(&self.header);
+        let keys = self.keys.take().unwrap();
+        let key = match keys(&header.key_id) {
+            Some(key) => key,
+            None => {
+                self.unknown_key = true;
+                return Err(Error::new(ErrorKind::NotFound, UNKNOWN_KEY));
+            }
+        };
+
+        self.reader = Some(BoxReader::new(self.inner.take().unwrap(), key, header.nonce));
+        self.key_id = Some(header.key_id);
+        Ok(Ready(()))
+    }
+}
+
+impl<R: AsyncRead, F> AsyncRead for AsyncBoxFileReader<R, F>
+    where F: FnOnce(&[u8]) -> Option<secretbox::Key>
+{
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, Error> {
+        if self.reader.is_none() {
+            try_ready!(self.poll_read_header(cx));
+        }
+        self.reader.as_mut().unwrap().poll_read(cx, buf)
+    }
+}
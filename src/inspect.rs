@@ -0,0 +1,146 @@
+// Dissection of box streams for debugging.
+
+use sodiumoxide::crypto::secretbox;
+
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_SIZE, PlainHeader, decrypt_header, decrypt_packet,
+             packet_nonce};
+use decryptor::{INVALID_LENGTH, UNAUTHENTICATED_EOF, UNAUTHENTICATED_HEADER,
+                UNAUTHENTICATED_PACKET};
+
+/// A part of a box stream, as decoded by `inspect`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inspected {
+    /// An authenticated packet.
+    Packet {
+        /// The offset of the header in the box stream.
+        offset: u64,
+        /// The nonce of the header. The body uses the nonce following it.
+        nonce: secretbox::Nonce,
+        /// The mac of the header itself.
+        header_mac: [u8; secretbox::MACBYTES],
+        /// The mac of the packet body, as stated in the header.
+        packet_mac: [u8; secretbox::MACBYTES],
+        /// The decrypted body.
+        body: Vec<u8>,
+    },
+    /// The authenticated final header.
+    FinalHeader {
+        /// The offset of the final header in the box stream.
+        offset: u64,
+        /// The nonce of the final header.
+        nonce: secretbox::Nonce,
+    },
+    /// The first part of the box stream that could not be authenticated.
+    /// Nothing after it is inspected.
+    Failure {
+        /// The offset of the header or packet body that failed.
+        offset: u64,
+        /// The nonce used for the failed header or packet body.
+        nonce: secretbox::Nonce,
+        /// The packet length stated in the header, if the header authenticated.
+        length: Option<u16>,
+        /// The error value a `BoxReader` would have produced.
+        reason: &'static str,
+    },
+    /// Data following the final header.
+    TrailingData {
+        /// The offset of the data in the box stream.
+        offset: u64,
+        /// The number of bytes after the final header.
+        len: u64,
+    },
+}
+
+/// Decode the (possibly damaged) box stream `cypher`, returning every packet
+/// up to and including the final header or the first failure, and any data
+/// following the final header.
+///
+/// This returns decrypted plaintext, so it is only meant for debugging.
+pub fn inspect(cypher: &[u8], key: &secretbox::Key, nonce: &secretbox::Nonce) -> Vec<Inspected> {
+    let mut parts = Vec::new();
+    let mut offset = 0;
+    let mut index = 0;
+
+    loop {
+        let header_nonce = packet_nonce(&nonce.0, index);
+        let failure = |offset: usize, nonce, length, reason| {
+            Inspected::Failure {
+                offset: offset as u64,
+                nonce: secretbox::Nonce(nonce),
+                length,
+                reason,
+            }
+        };
+
+        if cypher.len() - offset < CYPHER_HEADER_SIZE {
+            parts.push(failure(offset, header_nonce, None, UNAUTHENTICATED_EOF));
+            return parts;
+        }
+
+        let cypher_header = &cypher[offset..offset + CYPHER_HEADER_SIZE];
+        let mut plain_header = PlainHeader::new();
+        let mut current_nonce = header_nonce;
+        if !unsafe {
+                decrypt_header(&mut plain_header,
+                               &*(cypher_header.as_ptr() as *const [u8; CYPHER_HEADER_SIZE]),
+                               &key.0,
+                               &mut current_nonce)
+            } {
+            parts.push(failure(offset, header_nonce, None, UNAUTHENTICATED_HEADER));
+            return parts;
+        }
+
+        if plain_header.is_final_header() {
+            parts.push(Inspected::FinalHeader {
+                           offset: offset as u64,
+                           nonce: secretbox::Nonce(header_nonce),
+                       });
+            offset += CYPHER_HEADER_SIZE;
+            if offset < cypher.len() {
+                parts.push(Inspected::TrailingData {
+                               offset: offset as u64,
+                               len: (cypher.len() - offset) as u64,
+                           });
+            }
+            return parts;
+        }
+
+        let len = plain_header.get_packet_len();
+        if len > MAX_PACKET_SIZE || len == 0 {
+            parts.push(failure(offset, header_nonce, Some(len), INVALID_LENGTH));
+            return parts;
+        }
+
+        let body_offset = offset + CYPHER_HEADER_SIZE;
+        let body_nonce = current_nonce;
+        if cypher.len() - body_offset < len as usize {
+            parts.push(failure(body_offset, body_nonce, Some(len), UNAUTHENTICATED_EOF));
+            return parts;
+        }
+
+        let mut body = vec![0u8; len as usize];
+        if !unsafe {
+                decrypt_packet(body.as_mut_ptr(),
+                               cypher[body_offset..].as_ptr(),
+                               &plain_header,
+                               &key.0,
+                               &mut current_nonce)
+            } {
+            parts.push(failure(body_offset, body_nonce, Some(len), UNAUTHENTICATED_PACKET));
+            return parts;
+        }
+
+        let mut header_mac = [0u8; secretbox::MACBYTES];
+        header_mac.copy_from_slice(&cypher_header[..secretbox::MACBYTES]);
+        parts.push(Inspected::Packet {
+                       offset: offset as u64,
+                       nonce: secretbox::Nonce(header_nonce),
+                       header_mac,
+                       packet_mac: plain_header.get_packet_mac(),
+                       body,
+                   });
+
+        offset = body_offset + len as usize;
+        index += 1;
+    }
+}
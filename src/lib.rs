@@ -9,20 +9,92 @@ extern crate sodiumoxide;
 #[macro_use]
 extern crate futures_core;
 extern crate futures_io;
+#[cfg(any(feature = "packet_stream", feature = "sender", feature = "packets"))]
+extern crate futures_sink;
+#[cfg(any(feature = "muxrpc", feature = "sender"))]
+extern crate futures_channel;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
+#[cfg(any(test, feature = "vectors"))]
+extern crate serde_json;
+#[cfg(feature = "tokio")]
+extern crate tokio_timer;
+#[cfg(feature = "tokio")]
+extern crate futures01;
+#[cfg(feature = "packets")]
+extern crate bytes;
+
+#[macro_use]
+mod trace;
 
 pub mod crypto;
+pub mod file;
+#[cfg(feature = "packet_stream")]
+pub mod packet_stream;
+#[cfg(feature = "muxrpc")]
+pub mod muxrpc;
+#[cfg(feature = "shs")]
+pub mod shs;
+#[cfg(feature = "tunnel")]
+pub mod tunnel;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(test, feature = "vectors"))]
+pub mod vectors;
 mod box_writer;
 mod box_reader;
 mod box_duplex;
+mod sync_box_writer;
+mod sync_box_reader;
+mod blocking;
+mod seekable_box_reader;
+mod recovering_box_reader;
+mod one_shot;
+mod verify;
+mod reencrypt;
+mod inspect;
+mod duplex_keys;
 mod decryptor;
 mod encryptor;
+mod stats;
+mod observer;
+mod timeout;
+mod rate_limit;
+#[cfg(feature = "parallel")]
+mod parallel;
+#[cfg(feature = "sender")]
+mod box_sender;
+#[cfg(feature = "packets")]
+mod box_packets;
 
 pub use decryptor::{UNAUTHENTICATED_EOF, INVALID_LENGTH, UNAUTHENTICATED_HEADER,
-                    UNAUTHENTICATED_PACKET};
+                    UNAUTHENTICATED_PACKET, TRAILING_DATA};
 
 pub use box_writer::*;
 pub use box_reader::*;
 pub use box_duplex::*;
+pub use sync_box_writer::*;
+pub use sync_box_reader::*;
+pub use seekable_box_reader::*;
+pub use recovering_box_reader::*;
+pub use one_shot::*;
+pub use verify::*;
+pub use reencrypt::*;
+pub use inspect::*;
+pub use duplex_keys::*;
+pub use stats::*;
+pub use observer::*;
+pub use timeout::*;
+pub use rate_limit::*;
+#[cfg(feature = "parallel")]
+pub use parallel::*;
+#[cfg(feature = "sender")]
+pub use box_sender::*;
+#[cfg(feature = "packets")]
+pub use box_packets::*;
 
 #[cfg(test)]
 extern crate async_ringbuffer;
@@ -0,0 +1,778 @@
+//! Multiplexing of concurrent [muxrpc](https://github.com/ssbc/muxrpc) requests
+//! and streams over a single `PacketStream`.
+//!
+//! `muxrpc` splits a packet stream into three parts: an `RpcDriver` future
+//! which performs all reading and writing and must be polled until the
+//! connection ends, an `RpcClient` for opening requests to the peer, and the
+//! `IncomingRequests` opened by the peer.
+//!
+//! Each request gets its own channels. The driver routes incoming packets by
+//! request number, and sends outgoing packets by taking turns between all open
+//! requests, one packet at a time, so that a busy stream can not starve the
+//! others.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use futures_channel::mpsc::{channel, unbounded, Receiver, Sender, UnboundedReceiver,
+                            UnboundedSender};
+use futures_core::{Future, Poll, Stream};
+use futures_core::Async::{Pending, Ready};
+use futures_core::task::Context;
+use futures_io::{Error, ErrorKind, AsyncRead, AsyncWrite};
+use futures_sink::Sink;
+
+use packet_stream::{Body, Packet, PacketStream};
+
+/// The error value signaling that the connection ended before a request was
+/// completed.
+pub const CONNECTION_CLOSED: &'static str = "muxrpc connection closed";
+
+/// The error value signaling an attempt to send on a stream that has already
+/// been ended.
+pub const STREAM_ENDED: &'static str = "sent on an ended muxrpc stream";
+
+// The error body sent in response to requests when nobody listens for incoming requests.
+const NO_HANDLER: &'static str = r#"{"name":"Error","message":"no handler for incoming requests"}"#;
+
+// The number of packets each request can queue before its sender has to wait for the driver.
+const OUTGOING_BUFFER: usize = 8;
+
+// The maximum number of packets read or written in a single call to `RpcDriver::poll`, before
+// yielding to the other direction.
+const BUDGET: usize = 64;
+
+/// Split `packets` into a driver, a client for sending requests, and the
+/// stream of requests opened by the peer.
+pub fn muxrpc<S>(packets: PacketStream<S>) -> (RpcDriver<S>, RpcClient, IncomingRequests) {
+    let (control_tx, control_rx) = unbounded();
+    let (requests_tx, requests_rx) = unbounded();
+    let local_keys = Arc::new(Mutex::new(LocalKeys {
+                                             next: 1,
+                                             live: HashSet::new(),
+                                         }));
+
+    (RpcDriver {
+         packets,
+         control_rx,
+         control_tx: control_tx.clone(),
+         requests: Some(requests_tx),
+         entries: HashMap::new(),
+         local_keys: local_keys.clone(),
+         order: VecDeque::new(),
+         control_packets: VecDeque::new(),
+         closing: false,
+         closed: false,
+         remote_done: false,
+     },
+     RpcClient {
+         control: control_tx,
+         local_keys,
+     },
+     IncomingRequests { requests: requests_rx })
+}
+
+// Messages from the handles to the driver.
+enum Control {
+    // Register a new request.
+    Open {
+        key: i32,
+        stream: bool,
+        sends_data: bool,
+        outgoing: Receiver<Packet>,
+        incoming: Option<UnboundedSender<Packet>>,
+    },
+    // The local side is no longer interested in incoming packets of a request.
+    CancelReceive(i32),
+    // End a request with an error.
+    Abort(i32, Body),
+    // Close the connection once all requests are done sending.
+    Close,
+}
+
+// The driver's bookkeeping for a request.
+//
+// Requests are keyed by the request number of their outgoing packets: positive for
+// requests opened locally, negative for requests opened by the peer. Incoming packets
+// belong to the request keyed by their negated request number.
+struct Entry {
+    stream: bool,
+    // Whether the local side sends data (and thus ends the stream by itself), as
+    // opposed to only sending the request.
+    sends_data: bool,
+    outgoing: Option<Receiver<Packet>>,
+    incoming: Option<UnboundedSender<Packet>>,
+    local_ended: bool,
+    remote_ended: bool,
+}
+
+// The request numbers of the requests opened locally, shared between the client and the driver.
+struct LocalKeys {
+    next: i32,
+    // Keys which have been handed out and whose entries have not been removed yet.
+    live: HashSet<i32>,
+}
+
+enum Outgoing {
+    Packet(Packet),
+    Pending,
+    Finished,
+}
+
+/// Future which performs all reading and writing of a muxrpc connection.
+///
+/// Resolves once the connection has been closed in both directions.
+pub struct RpcDriver<S> {
+    packets: PacketStream<S>,
+    control_rx: UnboundedReceiver<Control>,
+    control_tx: UnboundedSender<Control>,
+    requests: Option<UnboundedSender<IncomingRequest>>,
+    entries: HashMap<i32, Entry>,
+    local_keys: Arc<Mutex<LocalKeys>>,
+    // Requests that may have outgoing packets, in the order in which they get to send.
+    order: VecDeque<i32>,
+    // End and error packets take precedence over regular packets.
+    control_packets: VecDeque<Packet>,
+    closing: bool,
+    closed: bool,
+    remote_done: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite> RpcDriver<S> {
+    fn poll_control(&mut self, cx: &mut Context) {
+        loop {
+            match self.control_rx.poll_next(cx) {
+                Ok(Ready(Some(control))) => self.handle_control(control),
+                Ok(Ready(None)) | Ok(Pending) => return,
+                Err(never) => match never {},
+            }
+        }
+    }
+
+    fn handle_control(&mut self, control: Control) {
+        match control {
+            Control::Open {
+                key,
+                stream,
+                sends_data,
+                outgoing,
+                incoming,
+            } => {
+                // Dropping the channels signals to the handles that the connection is closed.
+                if !self.closing {
+                    self.entries.insert(key,
+                                        Entry {
+                                            stream,
+                                            sends_data,
+                                            outgoing: Some(outgoing),
+                                            incoming,
+                                            local_ended: false,
+                                            remote_ended: false,
+                                        });
+                    self.order.push_back(key);
+                } else {
+                    self.local_keys.lock().unwrap().live.remove(&key);
+                }
+            }
+
+            Control::CancelReceive(key) => {
+                if let Some(entry) = self.entries.get_mut(&key) {
+                    entry.incoming = None;
+                    if entry.stream && !entry.sends_data && !entry.local_ended {
+                        self.control_packets.push_back(end_packet(key));
+                        entry.local_ended = true;
+                    }
+                }
+                self.remove_if_done(key);
+            }
+
+            Control::Abort(key, body) => {
+                if let Some(entry) = self.entries.get_mut(&key) {
+                    entry.outgoing = None;
+                    if !entry.local_ended {
+                        self.control_packets.push_back(Packet {
+                                                           request: key,
+                                                           stream: entry.stream,
+                                                           end: true,
+                                                           body,
+                                                       });
+                        entry.local_ended = true;
+                    }
+                }
+                self.remove_if_done(key);
+            }
+
+            Control::Close => self.closing = true,
+        }
+    }
+
+    fn poll_incoming(&mut self, cx: &mut Context) -> Result<(), Error> {
+        for _ in 0..BUDGET {
+            match self.packets.poll_next(cx)? {
+                Ready(Some(packet)) => self.handle_packet(packet),
+                Ready(None) => {
+                    // Dropping all channels signals CONNECTION_CLOSED to unfinished requests.
+                    self.remote_done = true;
+                    self.closing = true;
+                    self.entries.clear();
+                    self.local_keys.lock().unwrap().live.clear();
+                    self.order.clear();
+                    self.requests = None;
+                    return Ok(());
+                }
+                Pending => return Ok(()),
+            }
+        }
+
+        // Give the outgoing packets a chance, but make sure to continue reading afterwards.
+        cx.waker().wake();
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, packet: Packet) {
+        // Packets with a request number that can not be negated belong to no request.
+        let key = match packet.request.checked_neg() {
+            Some(key) => key,
+            None => return,
+        };
+
+        if self.entries.contains_key(&key) {
+            {
+                let entry = self.entries.get_mut(&key).unwrap();
+                let ends = packet.end || !entry.stream;
+
+                if let Some(ref incoming) = entry.incoming {
+                    let _ = incoming.unbounded_send(packet);
+                }
+
+                if ends {
+                    entry.remote_ended = true;
+                    entry.incoming = None;
+                    if entry.stream && !entry.sends_data && !entry.local_ended {
+                        self.control_packets.push_back(end_packet(key));
+                        entry.local_ended = true;
+                    }
+                }
+            }
+            self.remove_if_done(key);
+        } else if packet.request > 0 && !packet.end {
+            // An end packet without an entry belongs to a request that is already done.
+            self.handle_request(key, packet);
+        }
+    }
+
+    fn handle_request(&mut self, key: i32, packet: Packet) {
+        let stream = packet.stream;
+
+        let (tx, rx) = channel(OUTGOING_BUFFER);
+        let (receiver, incoming) = if stream {
+            let (in_tx, in_rx) = unbounded();
+            (Some(RpcStream {
+                      key,
+                      incoming: in_rx,
+                      control: self.control_tx.clone(),
+                      done: false,
+                  }),
+             Some(in_tx))
+        } else {
+            (None, None)
+        };
+
+        let request = IncomingRequest {
+            body: packet.body,
+            stream,
+            sender: RpcSender {
+                key,
+                stream,
+                tx,
+                control: self.control_tx.clone(),
+                ended: false,
+            },
+            receiver,
+        };
+
+        let delivered = match self.requests {
+            Some(ref requests) => requests.unbounded_send(request).is_ok(),
+            None => false,
+        };
+
+        if delivered {
+            self.entries.insert(key,
+                                Entry {
+                                    stream,
+                                    sends_data: true,
+                                    outgoing: Some(rx),
+                                    incoming,
+                                    local_ended: false,
+                                    remote_ended: !stream || packet.end,
+                                });
+            self.order.push_back(key);
+        } else {
+            self.control_packets.push_back(Packet {
+                                               request: key,
+                                               stream,
+                                               end: true,
+                                               body: Body::Json(NO_HANDLER.to_string()),
+                                           });
+        }
+    }
+
+    fn poll_entry(&mut self, cx: &mut Context, key: i32) -> Outgoing {
+        let entry = match self.entries.get_mut(&key) {
+            Some(entry) => entry,
+            None => return Outgoing::Finished,
+        };
+
+        let polled = match entry.outgoing {
+            Some(ref mut outgoing) => outgoing.poll_next(cx),
+            None => return Outgoing::Finished,
+        };
+
+        match polled {
+            Ok(Ready(Some(packet))) => {
+                if packet.end {
+                    entry.local_ended = true;
+                }
+                Outgoing::Packet(packet)
+            }
+            Ok(Ready(None)) => {
+                entry.outgoing = None;
+                if !entry.local_ended {
+                    if !entry.stream {
+                        entry.local_ended = true;
+                    } else if entry.sends_data {
+                        // The sender was dropped without closing the stream.
+                        self.control_packets.push_back(end_packet(key));
+                        entry.local_ended = true;
+                    }
+                }
+                Outgoing::Finished
+            }
+            Ok(Pending) => Outgoing::Pending,
+            Err(never) => match never {},
+        }
+    }
+
+    // Send queued packets until all queues are empty or the packet stream is not ready.
+    fn poll_outgoing(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        let mut budget = BUDGET;
+
+        loop {
+            while let Some(packet) = self.control_packets.pop_front() {
+                match self.packets.poll_ready(cx)? {
+                    Ready(()) => self.packets.start_send(packet)?,
+                    Pending => {
+                        self.control_packets.push_front(packet);
+                        return Ok(Pending);
+                    }
+                }
+            }
+
+            let mut sent = false;
+            for _ in 0..self.order.len() {
+                try_ready!(self.packets.poll_ready(cx));
+                let key = self.order.pop_front().unwrap();
+
+                match self.poll_entry(cx, key) {
+                    Outgoing::Packet(packet) => {
+                        self.packets.start_send(packet)?;
+                        self.order.push_back(key);
+                        sent = true;
+                    }
+                    Outgoing::Pending => self.order.push_back(key),
+                    Outgoing::Finished => self.remove_if_done(key),
+                }
+            }
+
+            if !sent && self.control_packets.is_empty() {
+                return Ok(Ready(()));
+            }
+
+            budget -= 1;
+            if budget == 0 {
+                cx.waker().wake();
+                return Ok(Ready(()));
+            }
+        }
+    }
+
+    fn remove_if_done(&mut self, key: i32) {
+        let done = self.entries
+            .get(&key)
+            .map(|entry| entry.local_ended && entry.remote_ended)
+            .unwrap_or(false);
+        if done {
+            self.entries.remove(&key);
+            if key > 0 {
+                self.local_keys.lock().unwrap().live.remove(&key);
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for RpcDriver<S> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.poll_control(cx);
+
+        if !self.remote_done {
+            self.poll_incoming(cx)?;
+        }
+
+        if !self.closed {
+            let drained = self.poll_outgoing(cx)?.is_ready() && self.order.is_empty() &&
+                          self.control_packets.is_empty();
+
+            if self.closing && drained {
+                if self.packets.poll_close(cx)?.is_ready() {
+                    self.closed = true;
+                }
+            } else {
+                let _ = self.packets.poll_flush(cx)?;
+            }
+        }
+
+        if self.closed && self.remote_done {
+            Ok(Ready(()))
+        } else {
+            Ok(Pending)
+        }
+    }
+}
+
+/// Handle for opening requests to the peer. Can be cloned freely.
+#[derive(Clone)]
+pub struct RpcClient {
+    control: UnboundedSender<Control>,
+    local_keys: Arc<Mutex<LocalKeys>>,
+}
+
+impl RpcClient {
+    /// Send an async request, resolving to the peer's response.
+    pub fn request(&self, body: Body) -> RpcResponse {
+        let (key, tx, incoming) = self.open(body, false, false);
+        drop(tx);
+        RpcResponse {
+            key,
+            incoming,
+            control: self.control.clone(),
+            done: false,
+        }
+    }
+
+    /// Open a source stream, in which the peer sends data.
+    pub fn source(&self, body: Body) -> RpcStream {
+        let (key, tx, incoming) = self.open(body, true, false);
+        drop(tx);
+        RpcStream {
+            key,
+            incoming,
+            control: self.control.clone(),
+            done: false,
+        }
+    }
+
+    /// Open a sink stream, in which the local side sends data.
+    pub fn sink(&self, body: Body) -> RpcSender {
+        let (key, tx, _) = self.open(body, true, true);
+        RpcSender {
+            key,
+            stream: true,
+            tx,
+            control: self.control.clone(),
+            ended: false,
+        }
+    }
+
+    /// Open a duplex stream, in which both sides send data.
+    pub fn duplex(&self, body: Body) -> (RpcSender, RpcStream) {
+        let (key, tx, incoming) = self.open(body, true, true);
+        (RpcSender {
+             key,
+             stream: true,
+             tx,
+             control: self.control.clone(),
+             ended: false,
+         },
+         RpcStream {
+             key,
+             incoming,
+             control: self.control.clone(),
+             done: false,
+         })
+    }
+
+    /// Close the connection once all open requests are done sending, and no
+    /// longer accept new requests.
+    pub fn close(&self) {
+        let _ = self.control.unbounded_send(Control::Close);
+    }
+
+    fn open(&self,
+            body: Body,
+            stream: bool,
+            sends_data: bool)
+            -> (i32, Sender<Packet>, UnboundedReceiver<Packet>) {
+        // Request numbers are positive, wrapping around after i32::MAX, and skip those of requests
+        // which are still open.
+        let key = {
+            let mut keys = self.local_keys.lock().unwrap();
+            loop {
+                let key = keys.next;
+                keys.next = if key == i32::max_value() { 1 } else { key + 1 };
+                if keys.live.insert(key) {
+                    break key;
+                }
+            }
+        };
+
+        let (mut tx, rx) = channel(OUTGOING_BUFFER);
+        let (in_tx, in_rx) = unbounded();
+
+        // A fresh channel always has room for the request.
+        let _ = tx.try_send(Packet {
+                                request: key,
+                                stream,
+                                end: false,
+                                body,
+                            });
+        let _ = self.control
+            .unbounded_send(Control::Open {
+                                key,
+                                stream,
+                                sends_data,
+                                outgoing: rx,
+                                incoming: Some(in_tx),
+                            });
+
+        (key, tx, in_rx)
+    }
+}
+
+/// The stream of requests opened by the peer.
+pub struct IncomingRequests {
+    requests: UnboundedReceiver<IncomingRequest>,
+}
+
+impl Stream for IncomingRequests {
+    type Item = IncomingRequest;
+    type Error = Error;
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<IncomingRequest>, Error> {
+        match self.requests.poll_next(cx) {
+            Ok(polled) => Ok(polled),
+            Err(never) => match never {},
+        }
+    }
+}
+
+/// A request opened by the peer.
+pub struct IncomingRequest {
+    /// The body of the request.
+    pub body: Body,
+    /// Whether this is a stream request (as opposed to an async request).
+    pub stream: bool,
+    /// Sends the response. For async requests, exactly one item should be sent.
+    pub sender: RpcSender,
+    /// The data sent by the peer for a stream request, `None` for async requests.
+    pub receiver: Option<RpcStream>,
+}
+
+/// Future for the response to an async request.
+pub struct RpcResponse {
+    key: i32,
+    incoming: UnboundedReceiver<Packet>,
+    control: UnboundedSender<Control>,
+    done: bool,
+}
+
+impl Future for RpcResponse {
+    type Item = Body;
+    type Error = Error;
+
+    /// Resolves to the response body.
+    ///
+    /// # Errors
+    /// Errors with `ErrorKind::Other` and the body as the error value if the peer
+    /// responded with an error, or with `ErrorKind::BrokenPipe` and
+    /// `CONNECTION_CLOSED` if the connection ended first.
+    fn poll(&mut self, cx: &mut Context) -> Poll<Body, Error> {
+        let polled = match self.incoming.poll_next(cx) {
+            Ok(polled) => polled,
+            Err(never) => match never {},
+        };
+
+        match polled {
+            Ready(Some(packet)) => {
+                self.done = true;
+                if packet.end {
+                    Err(remote_error(&packet.body))
+                } else {
+                    Ok(Ready(packet.body))
+                }
+            }
+            Ready(None) => {
+                self.done = true;
+                Err(Error::new(ErrorKind::BrokenPipe, CONNECTION_CLOSED))
+            }
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+/// Dropping an unresolved response discards it once it arrives.
+impl Drop for RpcResponse {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.control.unbounded_send(Control::CancelReceive(self.key));
+        }
+    }
+}
+
+/// The incoming half of a stream request.
+pub struct RpcStream {
+    key: i32,
+    incoming: UnboundedReceiver<Packet>,
+    control: UnboundedSender<Control>,
+    done: bool,
+}
+
+impl Stream for RpcStream {
+    type Item = Body;
+    type Error = Error;
+
+    /// Yields the bodies sent by the peer, ending when the peer ends the stream.
+    ///
+    /// # Errors
+    /// Errors with `ErrorKind::Other` and the body as the error value if the peer
+    /// ended the stream with an error, or with `ErrorKind::BrokenPipe` and
+    /// `CONNECTION_CLOSED` if the connection ended first.
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Body>, Error> {
+        if self.done {
+            return Ok(Ready(None));
+        }
+
+        let polled = match self.incoming.poll_next(cx) {
+            Ok(polled) => polled,
+            Err(never) => match never {},
+        };
+
+        match polled {
+            Ready(Some(packet)) => {
+                if packet.end {
+                    self.done = true;
+                    if is_true(&packet.body) {
+                        Ok(Ready(None))
+                    } else {
+                        Err(remote_error(&packet.body))
+                    }
+                } else {
+                    Ok(Ready(Some(packet.body)))
+                }
+            }
+            Ready(None) => {
+                self.done = true;
+                Err(Error::new(ErrorKind::BrokenPipe, CONNECTION_CLOSED))
+            }
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+/// Dropping an unfinished stream discards further incoming data. For source
+/// streams, this also tells the peer to end the stream.
+impl Drop for RpcStream {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.control.unbounded_send(Control::CancelReceive(self.key));
+        }
+    }
+}
+
+/// The outgoing half of a request.
+///
+/// Closing the sink ends the stream. Dropping it without closing ends the stream
+/// as well, after all items sent so far have been delivered.
+pub struct RpcSender {
+    key: i32,
+    stream: bool,
+    tx: Sender<Packet>,
+    control: UnboundedSender<Control>,
+    ended: bool,
+}
+
+impl RpcSender {
+    /// End the request with an error, given as the body of the error packet.
+    ///
+    /// Items which have not been sent yet are discarded.
+    pub fn abort(&mut self, error: Body) {
+        if !self.ended {
+            self.ended = true;
+            let _ = self.control.unbounded_send(Control::Abort(self.key, error));
+        }
+    }
+}
+
+impl Sink for RpcSender {
+    type SinkItem = Body;
+    type SinkError = Error;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.tx.poll_ready(cx).map_err(|_| Error::new(ErrorKind::BrokenPipe, CONNECTION_CLOSED))
+    }
+
+    fn start_send(&mut self, body: Body) -> Result<(), Error> {
+        if self.ended {
+            return Err(Error::new(ErrorKind::InvalidInput, STREAM_ENDED));
+        }
+
+        self.tx
+            .start_send(Packet {
+                            request: self.key,
+                            stream: self.stream,
+                            end: false,
+                            body,
+                        })
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, CONNECTION_CLOSED))
+    }
+
+    /// Packets are written by the `RpcDriver`, so this does not wait for them to
+    /// reach the peer.
+    fn poll_flush(&mut self, _: &mut Context) -> Poll<(), Error> {
+        Ok(Ready(()))
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        if self.stream && !self.ended {
+            try_ready!(self.poll_ready(cx));
+            self.tx
+                .start_send(end_packet(self.key))
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, CONNECTION_CLOSED))?;
+        }
+        self.ended = true;
+        Ok(Ready(()))
+    }
+}
+
+fn end_packet(key: i32) -> Packet {
+    Packet {
+        request: key,
+        stream: true,
+        end: true,
+        body: Body::Json("true".to_string()),
+    }
+}
+
+fn is_true(body: &Body) -> bool {
+    match *body {
+        Body::Json(ref json) => json.trim() == "true",
+        _ => false,
+    }
+}
+
+fn remote_error(body: &Body) -> Error {
+    Error::new(ErrorKind::Other,
+               String::from_utf8_lossy(body.as_bytes()).into_owned())
+}
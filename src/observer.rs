@@ -0,0 +1,57 @@
+// Hooks for observing and vetoing the packets of box streams.
+
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+
+/// Whether a packet is being written or has been read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// The packet has been read and authenticated, but its plaintext has not
+    /// been handed out yet.
+    Read,
+    /// The packet is about to be sealed and written.
+    Written,
+}
+
+/// Metadata of a packet, as passed to a `PacketObserver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// The index of the packet in its box stream, starting at 0.
+    pub index: u64,
+    /// The length of the plaintext of the packet.
+    pub len: u16,
+    /// Whether the packet is being written or has been read.
+    pub direction: PacketDirection,
+}
+
+/// Observes every packet of a `BoxReader`, `BoxWriter` or `BoxDuplex`, and may
+/// veto packets to abort the stream.
+pub trait PacketObserver {
+    /// Called for every packet, before it is sealed when writing, and after it
+    /// has been authenticated (but before its plaintext is handed out) when
+    /// reading.
+    ///
+    /// Returning an error vetoes the packet: the write or read that would have
+    /// produced the packet fails with that error. A vetoed packet is not
+    /// written, and the plaintext of a vetoed packet that has been read is
+    /// discarded. A stream stays failed after a veto: every later read of a
+    /// reader, and every later write, flush or close of a writer, fails with
+    /// an error of the same kind and message. For a `BoxDuplex`, this applies
+    /// to the direction of the vetoed packet only.
+    fn observe(&mut self, packet: &PacketInfo) -> Result<(), Error>;
+}
+
+// An observer that can be shared by the encryptor and decryptor of a duplex stream.
+pub(crate) type SharedObserver = Arc<Mutex<Box<PacketObserver + Send>>>;
+
+pub(crate) fn share<O: PacketObserver + Send + 'static>(observer: O) -> SharedObserver {
+    Arc::new(Mutex::new(Box::new(observer)))
+}
+
+// Calls the observer, if there is one.
+pub(crate) fn observe(observer: &Option<SharedObserver>, packet: PacketInfo) -> Result<(), Error> {
+    match *observer {
+        Some(ref observer) => observer.lock().unwrap().observe(&packet),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,61 @@
+// One-shot encryption and decryption of complete box streams that are held in memory.
+
+use std::io::{Error, ErrorKind, Read, Write};
+
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_USIZE};
+use decryptor::TRAILING_DATA;
+use sync_box_reader::SyncBoxReader;
+use sync_box_writer::SyncBoxWriter;
+
+/// Encrypt all of `plain` into a complete box stream, including the final
+/// header.
+///
+/// The result is exactly the stream a `BoxWriter` with the same `key` and
+/// `nonce` emits when `plain` is written in one `write_all` call and the
+/// writer is then closed.
+pub fn seal_all(plain: &[u8], key: &secretbox::Key, nonce: &secretbox::Nonce) -> Vec<u8> {
+    let packets = (plain.len() + MAX_PACKET_USIZE - 1) / MAX_PACKET_USIZE;
+    let cypher = Vec::with_capacity(plain.len() + (packets + 1) * CYPHER_HEADER_SIZE);
+
+    let mut writer = SyncBoxWriter::new(cypher, key.clone(), nonce.clone());
+    // Writing to a `Vec` never fails.
+    writer.write_all(plain).unwrap();
+    writer.close().unwrap();
+    writer.into_inner()
+}
+
+/// Decrypt a complete box stream, including the final header.
+///
+/// # Errors
+/// Fails with the same errors as a `BoxReader` reading `cypher` would. Additionally,
+/// fails with `ErrorKind::InvalidData` and error value `TRAILING_DATA` if
+/// `cypher` continues after the final header. No plaintext is returned unless
+/// the whole stream, including the final header, has been authenticated.
+pub fn open_all(cypher: &[u8],
+                key: &secretbox::Key,
+                nonce: &secretbox::Nonce)
+                -> Result<Vec<u8>, Error> {
+    // The plaintext is shorter than `cypher`, so `plain` is never reallocated and no copies
+    // of the plaintext are left behind.
+    let mut plain = Vec::with_capacity(cypher.len());
+    let mut reader = SyncBoxReader::new(cypher, key.clone(), nonce.clone());
+
+    let result = reader
+        .read_to_end(&mut plain)
+        .and_then(|_| if reader.get_ref().is_empty() {
+                      Ok(())
+                  } else {
+                      Err(Error::new(ErrorKind::InvalidData, TRAILING_DATA))
+                  });
+
+    match result {
+        Ok(()) => Ok(plain),
+        Err(e) => {
+            memzero(&mut plain);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Implementation of the [packet-stream](https://github.com/ssbc/packet-stream-codec)
+//! wire format on top of a `BoxDuplex`, as used by muxrpc.
+//!
+//! Every packet starts with a 9 byte header: one byte of flags, the body length
+//! as a big-endian u32, and the request number as a big-endian i32. A header
+//! consisting only of zeros (the goodbye) signals the end of the packet
+//! stream.
+
+use std::mem::replace;
+
+use futures_core::{Poll, Stream};
+use futures_core::Async::Ready;
+use futures_core::task::Context;
+use futures_io::{Error, ErrorKind, AsyncRead, AsyncWrite};
+use futures_sink::Sink;
+
+use box_duplex::BoxDuplex;
+
+/// The size of a packet-stream header.
+pub const HEADER_SIZE: usize = 9;
+
+/// The default maximum length of a packet body.
+pub const DEFAULT_MAX_BODY_LEN: u32 = 1024 * 1024;
+
+/// The error value signaling that a packet body exceeds the maximum length.
+pub const BODY_TOO_LONG: &'static str = "packet body exceeds maximum length";
+
+/// The error value signaling that a header declares an unknown body type.
+pub const INVALID_BODY_TYPE: &'static str = "read header with invalid body type";
+
+/// The error value signaling that a utf8 or json body is not valid utf8.
+pub const INVALID_UTF8: &'static str = "read packet body with invalid utf8";
+
+/// The error value signaling that the box stream ended in the middle of a packet.
+pub const UNEXPECTED_END: &'static str = "box stream ended within a packet";
+
+const FLAG_STREAM: u8 = 0b0000_1000;
+const FLAG_END: u8 = 0b0000_0100;
+const TYPE_MASK: u8 = 0b0000_0011;
+
+/// The body of a packet, tagged with its type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    /// Arbitrary bytes.
+    Binary(Vec<u8>),
+    /// A utf8 string.
+    Utf8(String),
+    /// A utf8 encoded json value.
+    Json(String),
+}
+
+impl Body {
+    /// Returns the encoded body.
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            Body::Binary(ref bytes) => bytes,
+            Body::Utf8(ref string) |
+            Body::Json(ref string) => string.as_bytes(),
+        }
+    }
+
+    fn type_flags(&self) -> u8 {
+        match *self {
+            Body::Binary(_) => 0,
+            Body::Utf8(_) => 1,
+            Body::Json(_) => 2,
+        }
+    }
+}
+
+/// A single packet-stream message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// The request number. Positive for requests, negative for responses.
+    pub request: i32,
+    /// Whether this packet belongs to a stream (as opposed to an async request).
+    pub stream: bool,
+    /// Whether this packet ends a stream, or is an error response.
+    pub end: bool,
+    /// The body of the packet.
+    pub body: Body,
+}
+
+/// A `Stream` and `Sink` of packet-stream messages over a `BoxDuplex`.
+///
+/// Closing the sink sends the goodbye header and then closes the box stream.
+/// The stream ends when the goodbye header is received (or if the box stream
+/// ends exactly between two packets).
+pub struct PacketStream<S> {
+    duplex: BoxDuplex<S>,
+    max_body_len: u32,
+    read_header: [u8; HEADER_SIZE],
+    read_buffer: Vec<u8>,
+    read_state: ReadState,
+    write_buffer: Vec<u8>,
+    write_offset: usize,
+    sent_goodbye: bool,
+}
+
+impl<S> PacketStream<S> {
+    /// Create a new packet stream over `duplex`, accepting bodies of up to
+    /// `DEFAULT_MAX_BODY_LEN` bytes.
+    pub fn new(duplex: BoxDuplex<S>) -> PacketStream<S> {
+        PacketStream::with_max_body_len(duplex, DEFAULT_MAX_BODY_LEN)
+    }
+
+    /// Create a new packet stream over `duplex`, accepting bodies of up to
+    /// `max_body_len` bytes in both directions.
+    pub fn with_max_body_len(duplex: BoxDuplex<S>, max_body_len: u32) -> PacketStream<S> {
+        PacketStream {
+            duplex,
+            max_body_len,
+            read_header: [0; HEADER_SIZE],
+            read_buffer: Vec::new(),
+            read_state: ReadState::Header { offset: 0 },
+            write_buffer: Vec::new(),
+            write_offset: 0,
+            sent_goodbye: false,
+        }
+    }
+
+    /// Gets a reference to the underlying `BoxDuplex`.
+    pub fn get_ref(&self) -> &BoxDuplex<S> {
+        &self.duplex
+    }
+
+    /// Gets a mutable reference to the underlying `BoxDuplex`.
+    ///
+    /// It is inadvisable to directly write to or read from the underlying
+    /// stream.
+    pub fn get_mut(&mut self) -> &mut BoxDuplex<S> {
+        &mut self.duplex
+    }
+
+    /// Unwraps this `PacketStream`, returning the underlying `BoxDuplex`.
+    pub fn into_inner(self) -> BoxDuplex<S> {
+        self.duplex
+    }
+}
+
+impl<S: AsyncWrite> PacketStream<S> {
+    // Write all of write_buffer[write_offset..] to the duplex.
+    fn poll_write_buffer(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        while self.write_offset < self.write_buffer.len() {
+            let written = try_ready!(self.duplex
+                                         .poll_write(cx, &self.write_buffer[self.write_offset..]));
+            if written == 0 {
+                return Err(Error::new(ErrorKind::WriteZero, "failed to write packet"));
+            }
+            self.write_offset += written;
+        }
+
+        self.write_buffer.clear();
+        self.write_offset = 0;
+        Ok(Ready(()))
+    }
+}
+
+// State of the reading half. Initial state is Header { offset: 0 }.
+enum ReadState {
+    // Reading the header into read_header[offset..].
+    Header { offset: usize },
+    // Reading the body described by read_header into read_buffer[offset..].
+    Body { offset: usize },
+    // The goodbye has been received.
+    Done,
+    // A header was rejected with the given error value, so the framing of the stream is lost.
+    Failed(&'static str),
+}
+
+impl<S: AsyncRead> Stream for PacketStream<S> {
+    type Item = Packet;
+    type Error = Error;
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Packet>, Error> {
+        loop {
+            match self.read_state {
+                ReadState::Header { offset } => {
+                    let read = try_ready!(self.duplex
+                                              .poll_read(cx, &mut self.read_header[offset..]));
+
+                    if read == 0 {
+                        if offset == 0 {
+                            self.read_state = ReadState::Done;
+                            return Ok(Ready(None));
+                        } else {
+                            return Err(Error::new(ErrorKind::UnexpectedEof, UNEXPECTED_END));
+                        }
+                    }
+
+                    if offset + read < HEADER_SIZE {
+                        self.read_state = ReadState::Header { offset: offset + read };
+                    } else if self.read_header == [0; HEADER_SIZE] {
+                        self.read_state = ReadState::Done;
+                        return Ok(Ready(None));
+                    } else {
+                        let len = read_u32(&self.read_header[1..5]);
+                        if len > self.max_body_len {
+                            self.read_state = ReadState::Failed(BODY_TOO_LONG);
+                            continue;
+                        }
+                        if self.read_header[0] & TYPE_MASK == TYPE_MASK {
+                            self.read_state = ReadState::Failed(INVALID_BODY_TYPE);
+                            continue;
+                        }
+
+                        self.read_buffer = vec![0; len as usize];
+                        self.read_state = ReadState::Body { offset: 0 };
+                    }
+                }
+
+                ReadState::Body { offset } => {
+                    if offset == self.read_buffer.len() {
+                        self.read_state = ReadState::Header { offset: 0 };
+                        let flags = self.read_header[0];
+                        let body = decode_body(flags, replace(&mut self.read_buffer, Vec::new()))?;
+
+                        return Ok(Ready(Some(Packet {
+                                                 request: read_u32(&self.read_header[5..9]) as i32,
+                                                 stream: flags & FLAG_STREAM != 0,
+                                                 end: flags & FLAG_END != 0,
+                                                 body,
+                                             })));
+                    }
+
+                    let read = try_ready!(self.duplex
+                                              .poll_read(cx, &mut self.read_buffer[offset..]));
+                    if read == 0 {
+                        return Err(Error::new(ErrorKind::UnexpectedEof, UNEXPECTED_END));
+                    }
+                    self.read_state = ReadState::Body { offset: offset + read };
+                }
+
+                ReadState::Done => return Ok(Ready(None)),
+
+                ReadState::Failed(value) => {
+                    return Err(Error::new(ErrorKind::InvalidData, value));
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite> Sink for PacketStream<S> {
+    type SinkItem = Packet;
+    type SinkError = Error;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.poll_write_buffer(cx)
+    }
+
+    fn start_send(&mut self, packet: Packet) -> Result<(), Error> {
+        let body = packet.body.as_bytes();
+        if body.len() > self.max_body_len as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, BODY_TOO_LONG));
+        }
+
+        let mut flags = packet.body.type_flags();
+        if packet.stream {
+            flags |= FLAG_STREAM;
+        }
+        if packet.end {
+            flags |= FLAG_END;
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        header[0] = flags;
+        write_u32(&mut header[1..5], body.len() as u32);
+        write_u32(&mut header[5..9], packet.request as u32);
+
+        self.write_buffer.extend_from_slice(&header);
+        self.write_buffer.extend_from_slice(body);
+        Ok(())
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        try_ready!(self.poll_write_buffer(cx));
+        self.duplex.poll_flush(cx)
+    }
+
+    /// Send the goodbye header, then close the underlying `BoxDuplex`.
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        if !self.sent_goodbye {
+            try_ready!(self.poll_write_buffer(cx));
+            self.write_buffer.extend_from_slice(&[0; HEADER_SIZE]);
+            self.sent_goodbye = true;
+        }
+
+        try_ready!(self.poll_write_buffer(cx));
+        self.duplex.poll_close(cx)
+    }
+}
+
+fn decode_body(flags: u8, bytes: Vec<u8>) -> Result<Body, Error> {
+    match flags & TYPE_MASK {
+        0 => Ok(Body::Binary(bytes)),
+        1 => {
+            String::from_utf8(bytes)
+                .map(Body::Utf8)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, INVALID_UTF8))
+        }
+        _ => {
+            String::from_utf8(bytes)
+                .map(Body::Json)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, INVALID_UTF8))
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) |
+    (bytes[3] as u32)
+}
+
+fn write_u32(out: &mut [u8], n: u32) {
+    out[0] = (n >> 24) as u8;
+    out[1] = (n >> 16) as u8;
+    out[2] = (n >> 8) as u8;
+    out[3] = n as u8;
+}
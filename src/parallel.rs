@@ -0,0 +1,174 @@
+// Parallel encryption and decryption of complete box streams that are held in memory.
+
+use std::mem::replace;
+
+use futures_io::{Error, ErrorKind};
+use rayon::prelude::*;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_SIZE, MAX_PACKET_USIZE, PlainHeader, decrypt_header,
+             decrypt_packet, encrypt_packet, final_header, packet_nonce};
+use decryptor::{INVALID_LENGTH, TRAILING_DATA, UNAUTHENTICATED_EOF, UNAUTHENTICATED_HEADER,
+                UNAUTHENTICATED_PACKET};
+
+/// Returns the length of the box stream produced by `encrypt_parallel` for
+/// `plain_len` bytes of plaintext, including the final header.
+pub fn encrypted_len(plain_len: usize) -> usize {
+    let packets = (plain_len + MAX_PACKET_USIZE - 1) / MAX_PACKET_USIZE;
+    plain_len + (packets + 1) * CYPHER_HEADER_SIZE
+}
+
+/// Encrypt all of `plain` into a complete box stream, sealing packets in
+/// parallel.
+///
+/// The result is exactly the stream a `BoxWriter` with the same `key` and
+/// `nonce` emits when `plain` is written in one `write_all` call and the
+/// writer is then closed: packets of `MAX_PACKET_SIZE` bytes (the last one
+/// possibly shorter), followed by the final header.
+pub fn encrypt_parallel(plain: &[u8], key: &secretbox::Key, nonce: &secretbox::Nonce) -> Vec<u8> {
+    let mut out = vec![0u8; encrypted_len(plain.len())];
+    encrypt_parallel_into(&mut out, plain, key, nonce);
+    out
+}
+
+/// Same as `encrypt_parallel`, but writes the box stream into `out`, e.g. a
+/// memory-mapped output file.
+///
+/// # Panics
+/// Panics if `out.len()` is not `encrypted_len(plain.len())`.
+pub fn encrypt_parallel_into(out: &mut [u8],
+                             plain: &[u8],
+                             key: &secretbox::Key,
+                             nonce: &secretbox::Nonce) {
+    assert_eq!(out.len(), encrypted_len(plain.len()));
+
+    let packets = (plain.len() + MAX_PACKET_USIZE - 1) / MAX_PACKET_USIZE;
+    let final_offset = out.len() - CYPHER_HEADER_SIZE;
+    let (body, last) = out.split_at_mut(final_offset);
+
+    body.par_chunks_mut(CYPHER_HEADER_SIZE + MAX_PACKET_USIZE)
+        .zip(plain.par_chunks(MAX_PACKET_USIZE))
+        .enumerate()
+        .for_each(|(i, (cypher_packet, plain_packet))| {
+            let mut nonce = packet_nonce(&nonce.0, i as u64);
+            unsafe {
+                encrypt_packet(cypher_packet.as_mut_ptr(),
+                               plain_packet.as_ptr(),
+                               plain_packet.len() as u16,
+                               &key.0,
+                               &mut nonce);
+            }
+        });
+
+    unsafe {
+        final_header(&mut *(last.as_mut_ptr() as *mut [u8; CYPHER_HEADER_SIZE]),
+                     &key.0,
+                     &packet_nonce(&nonce.0, packets as u64));
+    }
+}
+
+// A packet whose header has already been decrypted.
+struct OpenedHeader {
+    // Offset of the encrypted packet body in the box stream.
+    offset: usize,
+    header: PlainHeader,
+    // The nonce for decrypting the packet body.
+    nonce: [u8; secretbox::NONCEBYTES],
+}
+
+/// Decrypt a complete box stream, opening packets in parallel.
+///
+/// Headers are decrypted sequentially (each one determines where the next one
+/// starts), the packets they describe are then authenticated and decrypted in
+/// parallel.
+///
+/// # Errors
+/// Fails with the same errors as a `BoxReader` reading `cypher` would. Additionally,
+/// fails with `ErrorKind::InvalidData` and error value `TRAILING_DATA` if
+/// `cypher` continues after the final header. No plaintext is returned unless
+/// the whole stream, including the final header, has been authenticated.
+pub fn decrypt_parallel(cypher: &[u8],
+                        key: &secretbox::Key,
+                        nonce: &secretbox::Nonce)
+                        -> Result<Vec<u8>, Error> {
+    let mut packets: Vec<OpenedHeader> = Vec::new();
+    let mut offset = 0;
+    let mut plain_len = 0;
+
+    loop {
+        if cypher.len() - offset < CYPHER_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::UnexpectedEof, UNAUTHENTICATED_EOF));
+        }
+
+        let mut header = PlainHeader::new();
+        let mut header_nonce = packet_nonce(&nonce.0, packets.len() as u64);
+        let is_header_valid = unsafe {
+            decrypt_header(&mut header,
+                           &*(cypher[offset..].as_ptr() as *const [u8; CYPHER_HEADER_SIZE]),
+                           &key.0,
+                           &mut header_nonce)
+        };
+
+        if !is_header_valid {
+            return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_HEADER));
+        }
+        offset += CYPHER_HEADER_SIZE;
+
+        if header.is_final_header() {
+            break;
+        }
+
+        let len = header.get_packet_len();
+        if len > MAX_PACKET_SIZE || len == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, INVALID_LENGTH));
+        }
+        if cypher.len() - offset < len as usize {
+            return Err(Error::new(ErrorKind::UnexpectedEof, UNAUTHENTICATED_EOF));
+        }
+
+        packets.push(OpenedHeader {
+                         offset,
+                         header,
+                         nonce: header_nonce,
+                     });
+        offset += len as usize;
+        plain_len += len as usize;
+    }
+
+    if offset != cypher.len() {
+        return Err(Error::new(ErrorKind::InvalidData, TRAILING_DATA));
+    }
+
+    let mut plain = vec![0u8; plain_len];
+    let is_stream_valid = {
+        let mut outs = Vec::with_capacity(packets.len());
+        let mut rest: &mut [u8] = &mut plain;
+        for packet in packets.iter() {
+            let (out, tail) = replace(&mut rest, &mut [])
+                .split_at_mut(packet.header.get_packet_len() as usize);
+            outs.push(out);
+            rest = tail;
+        }
+
+        outs.into_par_iter()
+            .zip(packets.par_iter())
+            .all(|(out, packet)| {
+                let mut nonce = packet.nonce;
+                unsafe {
+                    decrypt_packet(out.as_mut_ptr(),
+                                   cypher[packet.offset..].as_ptr(),
+                                   &packet.header,
+                                   &key.0,
+                                   &mut nonce)
+                }
+            })
+    };
+
+    if is_stream_valid {
+        Ok(plain)
+    } else {
+        memzero(&mut plain);
+        Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_PACKET))
+    }
+}
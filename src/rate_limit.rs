@@ -0,0 +1,224 @@
+// Token-bucket rate limiting for box streams.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_core::{Async, Poll};
+use futures_core::task::{Context, Waker};
+use futures_io::Error;
+
+use crypto::CYPHER_HEADER_SIZE;
+use timeout::{Delay, Timer};
+
+/// Which bytes a `RateLimiter` counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counting {
+    /// Count only the plaintext of packets.
+    Plaintext,
+    /// Count all bytes on the wire, i.e. headers (including the final header)
+    /// as well as packets.
+    Wire,
+}
+
+/// A token bucket that limits the throughput of box streams.
+///
+/// Attach a limiter to a `BoxReader`, `BoxWriter` or `BoxDuplex` to limit that
+/// stream, or attach clones of the same limiter to several streams to limit
+/// them as a group. The limiter allows bursts of up to `burst` bytes, and
+/// refills at `bytes_per_second`.
+///
+/// Limiting respects packet boundaries: a writer waits before sealing a
+/// packet until the limiter allows the whole packet, and then writes it out
+/// without further delay. A reader waits before reading the header of the
+/// next packet, and accounts for the packet once it has been read, so its
+/// throughput may temporarily exceed the limit by one packet.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    timer: Arc<Timer + Send + Sync>,
+}
+
+struct Bucket {
+    bytes_per_second: u64,
+    burst: u64,
+    // May become negative when a reader accounts for a packet it has read.
+    tokens: f64,
+    refilled: Instant,
+    // Bumped whenever the rate changes.
+    generation: u64,
+    // The tasks waiting for tokens by the id of their shaper, woken when the rate changes. Each
+    // shaper has at most one entry, which it removes once it stops waiting.
+    waiting: HashMap<u64, Waker>,
+    // The id of the next shaper.
+    next_shaper: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if now > self.refilled {
+            let elapsed = now - self.refilled;
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            self.tokens = (self.tokens + elapsed * self.bytes_per_second as f64)
+                .min(self.burst as f64);
+            self.refilled = now;
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Create a limiter that allows `bytes_per_second` on average, in bursts of
+    /// up to `burst` bytes, using `timer` to wait for tokens. The bucket
+    /// starts out full.
+    ///
+    /// # Panics
+    /// Panics if `bytes_per_second` or `burst` is 0.
+    pub fn new<T: Timer + Send + Sync + 'static>(bytes_per_second: u64,
+                                                   burst: u64,
+                                                   timer: T)
+                                                   -> RateLimiter {
+        assert!(bytes_per_second > 0 && burst > 0,
+                "a rate limiter needs a nonzero rate and burst");
+
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                                            bytes_per_second,
+                                            burst,
+                                            tokens: burst as f64,
+                                            refilled: Instant::now(),
+                                            generation: 0,
+                                            waiting: HashMap::new(),
+                                            next_shaper: 0,
+                                        })),
+            timer: Arc::new(timer),
+        }
+    }
+
+    /// Returns the current rate in bytes per second, and the burst size.
+    pub fn rate(&self) -> (u64, u64) {
+        let bucket = self.bucket.lock().unwrap();
+        (bucket.bytes_per_second, bucket.burst)
+    }
+
+    /// Change the rate and burst size of this limiter and all of its clones.
+    /// Streams that are currently waiting are woken to take the new rate into
+    /// account.
+    ///
+    /// # Panics
+    /// Panics if `bytes_per_second` or `burst` is 0.
+    pub fn set_rate(&self, bytes_per_second: u64, burst: u64) {
+        assert!(bytes_per_second > 0 && burst > 0,
+                "a rate limiter needs a nonzero rate and burst");
+
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.bytes_per_second = bytes_per_second;
+        bucket.burst = burst;
+        bucket.tokens = bucket.tokens.min(burst as f64);
+        bucket.generation += 1;
+        for (_, waker) in bucket.waiting.drain() {
+            waker.wake();
+        }
+    }
+}
+
+// Applies a `RateLimiter` to an `Encryptor` or `Decryptor`.
+pub(crate) struct Shaper {
+    limiter: RateLimiter,
+    counting: Counting,
+    // Identifies the waker of this shaper in the bucket.
+    id: u64,
+    // The delay until enough tokens are available, and the generation of the bucket it was
+    // computed for.
+    delay: Option<(Box<Delay + Send>, u64)>,
+    // Whether the stream is waiting for tokens.
+    pub waiting: bool,
+}
+
+impl Shaper {
+    pub fn new(limiter: RateLimiter, counting: Counting) -> Shaper {
+        let id = {
+            let mut bucket = limiter.bucket.lock().unwrap();
+            bucket.next_shaper += 1;
+            bucket.next_shaper
+        };
+
+        Shaper {
+            limiter,
+            counting,
+            id,
+            delay: None,
+            waiting: false,
+        }
+    }
+
+    // The number of tokens a packet of length `len` costs.
+    pub fn packet_cost(&self, len: u16) -> u64 {
+        match self.counting {
+            Counting::Plaintext => len as u64,
+            Counting::Wire => len as u64 + CYPHER_HEADER_SIZE as u64,
+        }
+    }
+
+    // The number of tokens a final header costs.
+    pub fn final_header_cost(&self) -> u64 {
+        match self.counting {
+            Counting::Plaintext => 0,
+            Counting::Wire => CYPHER_HEADER_SIZE as u64,
+        }
+    }
+
+    // Wait until `needed` tokens (or a full bucket, if it is smaller) are available.
+    pub fn poll_reserve(&mut self, cx: &mut Context, needed: u64) -> Poll<(), Error> {
+        loop {
+            let (wait, generation) = {
+                let mut bucket = self.limiter.bucket.lock().unwrap();
+                bucket.refill();
+
+                let needed = min(needed, bucket.burst) as f64;
+                if bucket.tokens >= needed {
+                    bucket.waiting.remove(&self.id);
+                    self.delay = None;
+                    self.waiting = false;
+                    return Ok(Async::Ready(()));
+                }
+
+                bucket.waiting.insert(self.id, cx.waker().clone());
+
+                let nanos = ((needed - bucket.tokens) * 1e9 / bucket.bytes_per_second as f64)
+                    .ceil() as u64;
+                (Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32),
+                 bucket.generation)
+            };
+
+            let outdated = match self.delay {
+                Some((_, delay_generation)) => delay_generation != generation,
+                None => true,
+            };
+            if outdated {
+                self.delay = Some((self.limiter.timer.delay(Instant::now() + wait), generation));
+            }
+
+            self.waiting = true;
+            match self.delay.as_mut().unwrap().0.poll_elapsed(cx)? {
+                Async::Ready(()) => self.delay = None,
+                Async::Pending => return Ok(Async::Pending),
+            }
+        }
+    }
+
+    // Take `amount` tokens from the bucket, possibly leaving it in debt.
+    pub fn charge(&mut self, amount: u64) {
+        let mut bucket = self.limiter.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.tokens -= amount as f64;
+    }
+}
+
+impl Drop for Shaper {
+    fn drop(&mut self) {
+        self.limiter.bucket.lock().unwrap().waiting.remove(&self.id);
+    }
+}
@@ -0,0 +1,180 @@
+// Implementation of RecoveringBoxReader, which reads all authentic data of damaged box streams.
+
+use std::cmp::min;
+use std::io::{Error, Read};
+
+use sodiumoxide::crypto::secretbox;
+
+use blocking::{Buffer, read_until_eof};
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_SIZE, PlainHeader, decrypt_header,
+             decrypt_packet_inplace};
+use decryptor::{INVALID_LENGTH, UNAUTHENTICATED_EOF, UNAUTHENTICATED_HEADER,
+                UNAUTHENTICATED_PACKET};
+
+/// Describes where and why a box stream stopped being authentic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Damage {
+    /// The offset in the box stream of the first packet (or final header) that
+    /// could not be authenticated.
+    pub offset: u64,
+    /// The error value a `BoxReader` would have produced: `UNAUTHENTICATED_EOF`
+    /// if the stream was truncated, otherwise `UNAUTHENTICATED_HEADER`,
+    /// `INVALID_LENGTH` or `UNAUTHENTICATED_PACKET`.
+    pub reason: &'static str,
+}
+
+/// What a `RecoveringBoxReader` has read so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecoveryOutcome {
+    /// The number of authenticated packets.
+    pub packets: u64,
+    /// The number of authenticated plaintext bytes.
+    pub bytes: u64,
+    /// Whether the authenticated final header has been read.
+    pub final_header: bool,
+    /// Where the box stream stopped being authentic, if it did.
+    pub damage: Option<Damage>,
+}
+
+/// Wraps a blocking reader of a possibly damaged box stream, e.g. one whose
+/// writer crashed before writing the final header, and decrypts all
+/// authenticated packets.
+///
+/// Instead of erroring on a truncated or corrupted stream, this signals end of
+/// stream after the last authenticated packet, and records the damage in its
+/// `outcome`. Errors of the underlying reader are still propagated.
+pub struct RecoveringBoxReader<R> {
+    inner: R,
+    key: secretbox::Key,
+    nonce: secretbox::Nonce,
+    buffer: Buffer,
+    // The unread plaintext is buffer[offset..length].
+    offset: usize,
+    length: usize,
+    // Offset in the box stream of the next packet.
+    position: u64,
+    outcome: RecoveryOutcome,
+}
+
+impl<R> RecoveringBoxReader<R> {
+    /// Create a new reader, wrapping `inner` and using `key` and `nonce` for
+    /// decryption.
+    pub fn new(inner: R, key: secretbox::Key, nonce: secretbox::Nonce) -> RecoveringBoxReader<R> {
+        RecoveringBoxReader {
+            inner,
+            key,
+            nonce,
+            buffer: Buffer::new(),
+            offset: 0,
+            length: 0,
+            position: 0,
+            outcome: RecoveryOutcome::default(),
+        }
+    }
+
+    /// Returns what has been read so far. Once the reader has signalled end of
+    /// stream, this is the final outcome.
+    pub fn outcome(&self) -> &RecoveryOutcome {
+        &self.outcome
+    }
+
+    /// Returns whether the reader has reached the final header or damage.
+    pub fn is_done(&self) -> bool {
+        self.outcome.final_header || self.outcome.damage.is_some()
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Unwraps this `RecoveringBoxReader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn damaged(&mut self, reason: &'static str) -> Result<(), Error> {
+        self.outcome.damage = Some(Damage {
+                                       offset: self.position,
+                                       reason,
+                                   });
+        Ok(())
+    }
+}
+
+impl<R: Read> RecoveringBoxReader<R> {
+    // Read, authenticate and decrypt the next packet into the buffer, or record the final header
+    // or damage.
+    fn read_packet(&mut self) -> Result<(), Error> {
+        let mut cypher_header = [0u8; CYPHER_HEADER_SIZE];
+        if !read_until_eof(&mut self.inner, &mut cypher_header)? {
+            return self.damaged(UNAUTHENTICATED_EOF);
+        }
+
+        let mut plain_header = PlainHeader::new();
+        if !unsafe {
+                decrypt_header(&mut plain_header,
+                               &cypher_header,
+                               &self.key.0,
+                               &mut self.nonce.0)
+            } {
+            return self.damaged(UNAUTHENTICATED_HEADER);
+        }
+
+        if plain_header.is_final_header() {
+            self.outcome.final_header = true;
+            return Ok(());
+        }
+
+        let len = plain_header.get_packet_len();
+        if len > MAX_PACKET_SIZE || len == 0 {
+            return self.damaged(INVALID_LENGTH);
+        }
+
+        if !read_until_eof(&mut self.inner, &mut self.buffer.0[..len as usize])? {
+            return self.damaged(UNAUTHENTICATED_EOF);
+        }
+        if !unsafe {
+                decrypt_packet_inplace(self.buffer.0.as_mut_ptr(),
+                                       &plain_header,
+                                       &self.key.0,
+                                       &mut self.nonce.0)
+            } {
+            return self.damaged(UNAUTHENTICATED_PACKET);
+        }
+
+        self.offset = 0;
+        self.length = len as usize;
+        self.position += (CYPHER_HEADER_SIZE + len as usize) as u64;
+        self.outcome.packets += 1;
+        self.outcome.bytes += len as u64;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for RecoveringBoxReader<R> {
+    /// Read bytes from the wrapped reader and decrypt them. End of stream is
+    /// signalled by returning `Ok(0)` even though this function was passed a
+    /// buffer of nonzero length, after the final header or after the last
+    /// authenticated packet before any damage.
+    ///
+    /// # Errors
+    /// Propagates errors of the wrapped reader, other than a premature end of it.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+
+        while self.offset == self.length {
+            if self.is_done() {
+                return Ok(0);
+            }
+            self.read_packet()?;
+        }
+
+        let read = min(buf.len(), self.length - self.offset);
+        buf[..read].copy_from_slice(&self.buffer.0[self.offset..self.offset + read]);
+        self.offset += read;
+        Ok(read)
+    }
+}
@@ -0,0 +1,57 @@
+// Re-encryption of box streams under a new key and nonce.
+
+use std::io::{Error, ErrorKind, Read, Write};
+
+use sodiumoxide::crypto::secretbox;
+
+use blocking::Buffer;
+use decryptor::TRAILING_DATA;
+use sync_box_reader::SyncBoxReader;
+use sync_box_writer::SyncBoxWriter;
+
+/// Decrypt the box stream read from `reader` with `old_key` and `old_nonce`,
+/// and write it to `writer` as a box stream encrypted with `new_key` and
+/// `new_nonce`. Returns the number of plaintext bytes.
+///
+/// Packets are written as soon as they are full, but the final header of the
+/// new box stream is only written once the final header of the old one has
+/// been authenticated and `reader` has ended. So unless this returns `Ok`, the
+/// output is not a complete box stream, and readers of it will error. Use
+/// `file::reencrypt_file` to replace a file atomically instead.
+///
+/// All plaintext is zeroed after use. The written stream consists of
+/// fixed-size packets (see `SyncBoxWriter`).
+///
+/// # Errors
+/// Propagates errors of `reader` and `writer`, and fails with the same errors as
+/// a `SyncBoxReader` reading the old stream would. Additionally, fails with
+/// `ErrorKind::InvalidData` and error value `TRAILING_DATA` if `reader`
+/// continues after the final header.
+pub fn reencrypt<R: Read, W: Write>(reader: R,
+                                    old_key: &secretbox::Key,
+                                    old_nonce: &secretbox::Nonce,
+                                    writer: W,
+                                    new_key: &secretbox::Key,
+                                    new_nonce: &secretbox::Nonce)
+                                    -> Result<u64, Error> {
+    let mut reader = SyncBoxReader::new(reader, old_key.clone(), old_nonce.clone());
+    let mut writer = SyncBoxWriter::fixed_size(writer, new_key.clone(), new_nonce.clone());
+    let mut buffer = Buffer::new();
+    let mut total = 0;
+
+    loop {
+        let read = reader.read(&mut buffer.0)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer.0[..read])?;
+        total += read as u64;
+    }
+
+    if reader.get_mut().read(&mut buffer.0[..1])? != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, TRAILING_DATA));
+    }
+
+    writer.close()?;
+    Ok(total)
+}
@@ -0,0 +1,249 @@
+// Implementation of SeekableBoxReader, which decrypts fixed-size box streams at arbitrary offsets.
+
+use std::cmp::min;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+
+use blocking::{Buffer, read_exact_authenticated};
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_USIZE, PlainHeader, decrypt_header, decrypt_packet,
+             packet_nonce};
+use decryptor::{INVALID_LENGTH, UNAUTHENTICATED_EOF, UNAUTHENTICATED_HEADER,
+                UNAUTHENTICATED_PACKET};
+
+const PACKET_SIZE: u64 = MAX_PACKET_USIZE as u64;
+const CYPHER_PACKET_SIZE: u64 = (CYPHER_HEADER_SIZE + MAX_PACKET_USIZE) as u64;
+
+/// The error value signaling that the length of a box stream is impossible for
+/// a stream of fixed-size packets.
+pub const NOT_FIXED_SIZE: &'static str = "box stream does not consist of fixed-size packets";
+
+/// Wraps a seekable reader of a box stream in which every packet except the
+/// last one has exactly `MAX_PACKET_SIZE` bytes, and decrypts reads at
+/// arbitrary plaintext offsets.
+///
+/// Such streams are written by a `SyncBoxWriter` in fixed-size mode, a
+/// `BoxFileWriter`, and `encrypt_parallel`.
+///
+/// Only the packets that are actually read from are authenticated. Reading at
+/// the end of the plaintext authenticates the final header before signalling
+/// end of stream.
+///
+/// This reader only implements the blocking `Read` and `Seek`: futures-io 0.2
+/// has no `AsyncSeek` trait, so there is no asynchronous counterpart.
+pub struct SeekableBoxReader<R> {
+    inner: R,
+    key: secretbox::Key,
+    nonce: secretbox::Nonce,
+    // Offset of the box stream within the inner reader.
+    base: u64,
+    plain_len: u64,
+    packets: u64,
+    // Current position in the plaintext.
+    pos: u64,
+    // Plaintext of the packet at index `cached`.
+    buffer: Buffer,
+    cached: Option<u64>,
+    verified_end: bool,
+}
+
+impl<R: Read + Seek> SeekableBoxReader<R> {
+    /// Create a new reader for the box stream that starts at the current
+    /// position of `inner` and extends to its end, using `key` and `nonce` for
+    /// decryption.
+    ///
+    /// # Errors
+    /// Errors with `ErrorKind::InvalidData` and `NOT_FIXED_SIZE` if the length of
+    /// the stream is impossible for a stream of fixed-size packets, or with
+    /// `ErrorKind::UnexpectedEof` and `UNAUTHENTICATED_EOF` if the stream is
+    /// shorter than a final header.
+    pub fn new(mut inner: R,
+               key: secretbox::Key,
+               nonce: secretbox::Nonce)
+               -> Result<SeekableBoxReader<R>, Error> {
+        let base = inner.seek(SeekFrom::Current(0))?;
+        let len = inner.seek(SeekFrom::End(0))? - base;
+
+        if len < CYPHER_HEADER_SIZE as u64 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, UNAUTHENTICATED_EOF));
+        }
+
+        let body_len = len - CYPHER_HEADER_SIZE as u64;
+        let full_packets = body_len / CYPHER_PACKET_SIZE;
+        let rest = body_len % CYPHER_PACKET_SIZE;
+
+        let (packets, plain_len) = if rest == 0 {
+            (full_packets, full_packets * PACKET_SIZE)
+        } else if rest > CYPHER_HEADER_SIZE as u64 {
+            (full_packets + 1, full_packets * PACKET_SIZE + rest - CYPHER_HEADER_SIZE as u64)
+        } else {
+            return Err(Error::new(ErrorKind::InvalidData, NOT_FIXED_SIZE));
+        };
+
+        Ok(SeekableBoxReader {
+               inner,
+               key,
+               nonce,
+               base,
+               plain_len,
+               packets,
+               pos: 0,
+               buffer: Buffer::new(),
+               cached: None,
+               verified_end: false,
+           })
+    }
+}
+
+impl<R> SeekableBoxReader<R> {
+    /// Returns the length of the plaintext.
+    pub fn plain_len(&self) -> u64 {
+        self.plain_len
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Unwraps this `SeekableBoxReader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn packet_len(&self, index: u64) -> usize {
+        min(PACKET_SIZE, self.plain_len - index * PACKET_SIZE) as usize
+    }
+}
+
+impl<R: Read + Seek> SeekableBoxReader<R> {
+    // Read, authenticate and decrypt the packet at `index` into the buffer.
+    fn load_packet(&mut self, index: u64) -> Result<(), Error> {
+        self.cached = None;
+        let len = self.packet_len(index);
+
+        let mut cypher = [0u8; CYPHER_HEADER_SIZE + MAX_PACKET_USIZE];
+        self.inner
+            .seek(SeekFrom::Start(self.base + index * CYPHER_PACKET_SIZE))?;
+        read_exact_authenticated(&mut self.inner, &mut cypher[..CYPHER_HEADER_SIZE + len])?;
+
+        let mut nonce = packet_nonce(&self.nonce.0, index);
+        let mut plain_header = PlainHeader::new();
+        if !unsafe {
+                decrypt_header(&mut plain_header,
+                               &*(cypher.as_ptr() as *const [u8; CYPHER_HEADER_SIZE]),
+                               &self.key.0,
+                               &mut nonce)
+            } {
+            return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_HEADER));
+        }
+
+        if plain_header.is_final_header() || plain_header.get_packet_len() as usize != len {
+            return Err(Error::new(ErrorKind::InvalidData, INVALID_LENGTH));
+        }
+
+        let is_packet_valid = unsafe {
+            decrypt_packet(self.buffer.0.as_mut_ptr(),
+                           cypher[CYPHER_HEADER_SIZE..].as_ptr(),
+                           &plain_header,
+                           &self.key.0,
+                           &mut nonce)
+        };
+        memzero(&mut cypher);
+
+        if is_packet_valid {
+            self.cached = Some(index);
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_PACKET))
+        }
+    }
+
+    // Authenticate the final header at the end of the stream.
+    fn verify_final_header(&mut self) -> Result<(), Error> {
+        if self.verified_end {
+            return Ok(());
+        }
+
+        let mut cypher_header = [0u8; CYPHER_HEADER_SIZE];
+        self.inner
+            .seek(SeekFrom::Start(self.base + self.packets * CYPHER_PACKET_SIZE))?;
+        read_exact_authenticated(&mut self.inner, &mut cypher_header)?;
+
+        let mut nonce = packet_nonce(&self.nonce.0, self.packets);
+        let mut plain_header = PlainHeader::new();
+        if !unsafe {
+                decrypt_header(&mut plain_header, &cypher_header, &self.key.0, &mut nonce)
+            } {
+            return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_HEADER));
+        }
+
+        if plain_header.is_final_header() {
+            self.verified_end = true;
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::UnexpectedEof, UNAUTHENTICATED_EOF))
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableBoxReader<R> {
+    /// Read and decrypt bytes at the current position. End of stream is
+    /// signalled by returning `Ok(0)` even though this function was passed a
+    /// buffer of nonzero length, once the final header has been authenticated.
+    ///
+    /// # Errors
+    /// Produces the same errors as a `BoxReader`.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+
+        if self.pos >= self.plain_len {
+            self.verify_final_header()?;
+            return Ok(0);
+        }
+
+        let index = self.pos / PACKET_SIZE;
+        if self.cached != Some(index) {
+            self.load_packet(index)?;
+        }
+
+        let offset = (self.pos - index * PACKET_SIZE) as usize;
+        let read = min(buf.len(), self.packet_len(index) - offset);
+        buf[..read].copy_from_slice(&self.buffer.0[offset..offset + read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableBoxReader<R> {
+    /// Seek to an offset in the plaintext. This does not perform any reads.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => offset_by(self.plain_len, offset),
+            SeekFrom::Current(offset) => offset_by(self.pos, offset),
+        };
+
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => {
+                Err(Error::new(ErrorKind::InvalidInput,
+                               "invalid seek to a negative or overflowing position"))
+            }
+        }
+    }
+}
+
+fn offset_by(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
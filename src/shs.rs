@@ -0,0 +1,513 @@
+//! Implementation of the [secret-handshake](https://github.com/auditdrivencrypto/secret-handshake)
+//! protocol, which establishes the keys and nonces for a `BoxDuplex`.
+//!
+//! Both functions in this module return futures which drive the handshake on
+//! the given duplex stream, resolving to a `BoxDuplex` wrapping that stream and
+//! the authenticated long-term public key of the peer.
+
+use libc::c_int;
+use futures_core::{Future, Poll};
+use futures_core::Async::Ready;
+use futures_core::task::Context;
+use futures_io::{Error, ErrorKind, AsyncRead, AsyncWrite};
+use sodiumoxide::crypto::{auth, box_, secretbox, sign};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use sodiumoxide::utils::memzero;
+
+use box_duplex::BoxDuplex;
+use duplex_keys::DuplexKeys;
+
+/// The error value signaling that the peer's first message was not
+/// authenticated with the expected network key.
+pub const INVALID_HELLO: &'static str = "received invalid handshake hello";
+
+/// The error value signaling that the client failed to prove its identity, or
+/// did not expect to talk to this server.
+pub const INVALID_AUTH: &'static str = "received invalid handshake authentication";
+
+/// The error value signaling that the server failed to prove its identity.
+pub const INVALID_ACCEPT: &'static str = "received invalid handshake acceptance";
+
+/// The error value signaling that a long-term key is not a valid ed25519 key.
+pub const INVALID_KEY: &'static str = "invalid ed25519 public key";
+
+const HELLO_SIZE: usize = auth::TAGBYTES + box_::PUBLICKEYBYTES;
+const AUTH_SIZE: usize = secretbox::MACBYTES + sign::SIGNATUREBYTES + sign::PUBLICKEYBYTES;
+const ACCEPT_SIZE: usize = secretbox::MACBYTES + sign::SIGNATUREBYTES;
+
+const POLLED_AFTER_COMPLETION: &'static str = "polled handshake after completion";
+
+extern "C" {
+    fn crypto_sign_ed25519_pk_to_curve25519(curve25519_pk: *mut [u8; 32],
+                                            ed25519_pk: *const [u8; 32])
+                                            -> c_int;
+
+    fn crypto_sign_ed25519_sk_to_curve25519(curve25519_sk: *mut [u8; 32],
+                                            ed25519_sk: *const [u8; 64])
+                                            -> c_int;
+}
+
+/// Perform the client side of the handshake on `stream`, authenticating as
+/// `pk`/`sk` to the server with long-term public key `server_pk`, in the network
+/// identified by `network_key`.
+pub fn client_handshake<S>(stream: S,
+                           network_key: auth::Key,
+                           pk: sign::PublicKey,
+                           sk: sign::SecretKey,
+                           server_pk: sign::PublicKey)
+                           -> ClientHandshake<S> {
+    let (eph_pk, eph_sk) = box_::gen_keypair();
+    let mut buffer = [0u8; AUTH_SIZE];
+    hello(&mut buffer[..HELLO_SIZE], &network_key, &eph_pk);
+
+    ClientHandshake {
+        stream: Some(stream),
+        network_key,
+        pk,
+        sk,
+        server_pk,
+        eph_pk,
+        eph_sk,
+        server_eph_pk: [0; box_::PUBLICKEYBYTES],
+        shared_ab: [0; 32],
+        shared_a_b: [0; 32],
+        sig_a: [0; sign::SIGNATUREBYTES],
+        buffer,
+        offset: 0,
+        state: ClientState::SendHello,
+    }
+}
+
+/// Future for the client side of the handshake, created by `client_handshake`.
+///
+/// Resolves to a `BoxDuplex` and the public key of the server.
+pub struct ClientHandshake<S> {
+    stream: Option<S>,
+    network_key: auth::Key,
+    pk: sign::PublicKey,
+    sk: sign::SecretKey,
+    server_pk: sign::PublicKey,
+    eph_pk: box_::PublicKey,
+    eph_sk: box_::SecretKey,
+    server_eph_pk: [u8; box_::PUBLICKEYBYTES],
+    // The shared secret from both ephemeral keys.
+    shared_ab: [u8; 32],
+    // The shared secret from the client's ephemeral and the server's long-term key.
+    shared_a_b: [u8; 32],
+    sig_a: [u8; sign::SIGNATUREBYTES],
+    // Holds the message that is currently being sent or received.
+    buffer: [u8; AUTH_SIZE],
+    offset: usize,
+    state: ClientState,
+}
+
+#[derive(PartialEq, Debug)]
+enum ClientState {
+    SendHello,
+    ReceiveHello,
+    SendAuth,
+    ReceiveAccept,
+    Done,
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for ClientHandshake<S> {
+    type Item = (BoxDuplex<S>, sign::PublicKey);
+    type Error = Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.state {
+                ClientState::SendHello => {
+                    try_ready!(poll_write_all(self.stream.as_mut().expect(POLLED_AFTER_COMPLETION),
+                                              cx,
+                                              &self.buffer[..HELLO_SIZE],
+                                              &mut self.offset));
+                    self.offset = 0;
+                    self.state = ClientState::ReceiveHello;
+                }
+
+                ClientState::ReceiveHello => {
+                    try_ready!(poll_read_exact(self.stream.as_mut().expect(POLLED_AFTER_COMPLETION),
+                                               cx,
+                                               &mut self.buffer[..HELLO_SIZE],
+                                               &mut self.offset));
+                    self.offset = 0;
+                    self.server_eph_pk = verify_hello(&self.buffer[..HELLO_SIZE],
+                                                      &self.network_key)?;
+
+                    self.shared_ab = scalarmult(&self.eph_sk.0, &self.server_eph_pk);
+                    self.shared_a_b = scalarmult(&self.eph_sk.0, &curve_pk(&self.server_pk)?);
+
+                    let sig_a = sign::sign_detached(&concat(&[&self.network_key.0,
+                                                              &self.server_pk.0,
+                                                              &sha256::hash(&self.shared_ab).0]),
+                                                    &self.sk);
+                    self.sig_a = sig_a.0;
+
+                    let plain = concat(&[&self.sig_a, &self.pk.0]);
+                    let key = secretbox::Key(sha256::hash(&concat(&[&self.network_key.0,
+                                                                     &self.shared_ab,
+                                                                     &self.shared_a_b]))
+                                                     .0);
+                    let auth = secretbox::seal(&plain, &zero_nonce(), &key);
+                    self.buffer.copy_from_slice(&auth);
+                    self.state = ClientState::SendAuth;
+                }
+
+                ClientState::SendAuth => {
+                    try_ready!(poll_write_all(self.stream.as_mut().expect(POLLED_AFTER_COMPLETION),
+                                              cx,
+                                              &self.buffer[..AUTH_SIZE],
+                                              &mut self.offset));
+                    self.offset = 0;
+                    self.state = ClientState::ReceiveAccept;
+                }
+
+                ClientState::ReceiveAccept => {
+                    try_ready!(poll_read_exact(self.stream.as_mut().expect(POLLED_AFTER_COMPLETION),
+                                               cx,
+                                               &mut self.buffer[..ACCEPT_SIZE],
+                                               &mut self.offset));
+                    self.offset = 0;
+
+                    let mut shared_cap_a_b = scalarmult(&curve_sk(&self.sk), &self.server_eph_pk);
+                    let mut shared_secret = sha256::hash(&concat(&[&self.network_key.0,
+                                                                    &self.shared_ab,
+                                                                    &self.shared_a_b,
+                                                                    &shared_cap_a_b]))
+                            .0;
+                    memzero(&mut shared_cap_a_b);
+
+                    let accepted = match secretbox::open(&self.buffer[..ACCEPT_SIZE],
+                                                         &zero_nonce(),
+                                                         &secretbox::Key(shared_secret)) {
+                        Ok(sig_b) => {
+                            let msg = concat(&[&self.network_key.0,
+                                               &self.sig_a,
+                                               &self.pk.0,
+                                               &sha256::hash(&self.shared_ab).0]);
+                            signature(&sig_b)
+                                .map(|sig_b| sign::verify_detached(&sig_b, &msg, &self.server_pk))
+                                .unwrap_or(false)
+                        }
+                        Err(()) => false,
+                    };
+
+                    if !accepted {
+                        memzero(&mut shared_secret);
+                        return Err(Error::new(ErrorKind::InvalidData, INVALID_ACCEPT));
+                    }
+
+                    let keys = DuplexKeys::from_shs_secrets(&self.network_key,
+                                                            &shared_secret,
+                                                            &self.pk,
+                                                            &self.eph_pk,
+                                                            &self.server_pk,
+                                                            &box_::PublicKey(self.server_eph_pk));
+                    memzero(&mut shared_secret);
+                    let duplex =
+                        BoxDuplex::from_keys(self.stream.take().expect(POLLED_AFTER_COMPLETION),
+                                             keys);
+                    self.state = ClientState::Done;
+                    return Ok(Ready((duplex, self.server_pk.clone())));
+                }
+
+                ClientState::Done => panic!(POLLED_AFTER_COMPLETION),
+            }
+        }
+    }
+}
+
+/// Zero shared secrets on dropping.
+impl<S> Drop for ClientHandshake<S> {
+    fn drop(&mut self) {
+        memzero(&mut self.shared_ab);
+        memzero(&mut self.shared_a_b);
+        memzero(&mut self.buffer);
+    }
+}
+
+/// Perform the server side of the handshake on `stream`, authenticating as
+/// `pk`/`sk` in the network identified by `network_key`.
+///
+/// Any client which can prove its identity and knows the network key and `pk`
+/// is accepted, it is up to the caller to decide whether to talk to the client
+/// with the resulting public key.
+pub fn server_handshake<S>(stream: S,
+                           network_key: auth::Key,
+                           pk: sign::PublicKey,
+                           sk: sign::SecretKey)
+                           -> ServerHandshake<S> {
+    let (eph_pk, eph_sk) = box_::gen_keypair();
+
+    ServerHandshake {
+        stream: Some(stream),
+        network_key,
+        pk,
+        sk,
+        client_pk: None,
+        eph_pk,
+        eph_sk,
+        client_eph_pk: [0; box_::PUBLICKEYBYTES],
+        shared_ab: [0; 32],
+        shared_a_b: [0; 32],
+        shared_secret: [0; 32],
+        buffer: [0; AUTH_SIZE],
+        offset: 0,
+        state: ServerState::ReceiveHello,
+    }
+}
+
+/// Future for the server side of the handshake, created by `server_handshake`.
+///
+/// Resolves to a `BoxDuplex` and the public key of the client.
+pub struct ServerHandshake<S> {
+    stream: Option<S>,
+    network_key: auth::Key,
+    pk: sign::PublicKey,
+    sk: sign::SecretKey,
+    client_pk: Option<sign::PublicKey>,
+    eph_pk: box_::PublicKey,
+    eph_sk: box_::SecretKey,
+    client_eph_pk: [u8; box_::PUBLICKEYBYTES],
+    // The shared secret from both ephemeral keys.
+    shared_ab: [u8; 32],
+    // The shared secret from the client's ephemeral and the server's long-term key.
+    shared_a_b: [u8; 32],
+    // The shared secret from all three Diffie-Hellman exchanges.
+    shared_secret: [u8; 32],
+    // Holds the message that is currently being sent or received.
+    buffer: [u8; AUTH_SIZE],
+    offset: usize,
+    state: ServerState,
+}
+
+#[derive(PartialEq, Debug)]
+enum ServerState {
+    ReceiveHello,
+    SendHello,
+    ReceiveAuth,
+    SendAccept,
+    Done,
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for ServerHandshake<S> {
+    type Item = (BoxDuplex<S>, sign::PublicKey);
+    type Error = Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.state {
+                ServerState::ReceiveHello => {
+                    try_ready!(poll_read_exact(self.stream.as_mut().expect(POLLED_AFTER_COMPLETION),
+                                               cx,
+                                               &mut self.buffer[..HELLO_SIZE],
+                                               &mut self.offset));
+                    self.offset = 0;
+                    self.client_eph_pk = verify_hello(&self.buffer[..HELLO_SIZE],
+                                                      &self.network_key)?;
+
+                    self.shared_ab = scalarmult(&self.eph_sk.0, &self.client_eph_pk);
+                    self.shared_a_b = scalarmult(&curve_sk(&self.sk), &self.client_eph_pk);
+
+                    hello(&mut self.buffer[..HELLO_SIZE], &self.network_key, &self.eph_pk);
+                    self.state = ServerState::SendHello;
+                }
+
+                ServerState::SendHello => {
+                    try_ready!(poll_write_all(self.stream.as_mut().expect(POLLED_AFTER_COMPLETION),
+                                              cx,
+                                              &self.buffer[..HELLO_SIZE],
+                                              &mut self.offset));
+                    self.offset = 0;
+                    self.state = ServerState::ReceiveAuth;
+                }
+
+                ServerState::ReceiveAuth => {
+                    try_ready!(poll_read_exact(self.stream.as_mut().expect(POLLED_AFTER_COMPLETION),
+                                               cx,
+                                               &mut self.buffer[..AUTH_SIZE],
+                                               &mut self.offset));
+                    self.offset = 0;
+
+                    let key = secretbox::Key(sha256::hash(&concat(&[&self.network_key.0,
+                                                                     &self.shared_ab,
+                                                                     &self.shared_a_b]))
+                                                     .0);
+                    let mut plain = secretbox::open(&self.buffer[..AUTH_SIZE], &zero_nonce(), &key)
+                        .map_err(|_| Error::new(ErrorKind::InvalidData, INVALID_AUTH))?;
+
+                    let sig_a = signature(&plain[..sign::SIGNATUREBYTES]);
+                    let client_pk = sign::PublicKey::from_slice(&plain[sign::SIGNATUREBYTES..]);
+                    let (sig_a, client_pk) = match (sig_a, client_pk) {
+                        (Some(sig_a), Some(client_pk)) => (sig_a, client_pk),
+                        _ => {
+                            memzero(&mut plain);
+                            return Err(Error::new(ErrorKind::InvalidData, INVALID_AUTH));
+                        }
+                    };
+                    memzero(&mut plain);
+
+                    let hashed_ab = sha256::hash(&self.shared_ab).0;
+                    let msg = concat(&[&self.network_key.0, &self.pk.0, &hashed_ab]);
+                    if !sign::verify_detached(&sig_a, &msg, &client_pk) {
+                        return Err(Error::new(ErrorKind::InvalidData, INVALID_AUTH));
+                    }
+
+                    let mut shared_cap_a_b = scalarmult(&self.eph_sk.0, &curve_pk(&client_pk)?);
+                    self.shared_secret = sha256::hash(&concat(&[&self.network_key.0,
+                                                                 &self.shared_ab,
+                                                                 &self.shared_a_b,
+                                                                 &shared_cap_a_b]))
+                            .0;
+                    memzero(&mut shared_cap_a_b);
+
+                    let sig_b = sign::sign_detached(&concat(&[&self.network_key.0,
+                                                              &sig_a.0,
+                                                              &client_pk.0,
+                                                              &hashed_ab]),
+                                                    &self.sk);
+                    let accept = secretbox::seal(&sig_b.0,
+                                                 &zero_nonce(),
+                                                 &secretbox::Key(self.shared_secret));
+                    self.buffer[..ACCEPT_SIZE].copy_from_slice(&accept);
+
+                    self.client_pk = Some(client_pk);
+                    self.state = ServerState::SendAccept;
+                }
+
+                ServerState::SendAccept => {
+                    try_ready!(poll_write_all(self.stream.as_mut().expect(POLLED_AFTER_COMPLETION),
+                                              cx,
+                                              &self.buffer[..ACCEPT_SIZE],
+                                              &mut self.offset));
+                    self.offset = 0;
+
+                    let client_pk = self.client_pk.take().expect(POLLED_AFTER_COMPLETION);
+                    let keys = DuplexKeys::from_shs_secrets(&self.network_key,
+                                                            &self.shared_secret,
+                                                            &self.pk,
+                                                            &self.eph_pk,
+                                                            &client_pk,
+                                                            &box_::PublicKey(self.client_eph_pk));
+                    let duplex =
+                        BoxDuplex::from_keys(self.stream.take().expect(POLLED_AFTER_COMPLETION),
+                                             keys);
+                    self.state = ServerState::Done;
+                    return Ok(Ready((duplex, client_pk)));
+                }
+
+                ServerState::Done => panic!(POLLED_AFTER_COMPLETION),
+            }
+        }
+    }
+}
+
+/// Zero shared secrets on dropping.
+impl<S> Drop for ServerHandshake<S> {
+    fn drop(&mut self) {
+        memzero(&mut self.shared_ab);
+        memzero(&mut self.shared_a_b);
+        memzero(&mut self.shared_secret);
+        memzero(&mut self.buffer);
+    }
+}
+
+// Writes the hello message (hmac of the ephemeral key, followed by the key) into `out`.
+fn hello(out: &mut [u8], network_key: &auth::Key, eph_pk: &box_::PublicKey) {
+    let tag = auth::authenticate(&eph_pk.0, network_key);
+    out[..auth::TAGBYTES].copy_from_slice(&tag.0);
+    out[auth::TAGBYTES..HELLO_SIZE].copy_from_slice(&eph_pk.0);
+}
+
+// Checks the hmac of a received hello message and returns the peer's ephemeral key.
+fn verify_hello(msg: &[u8], network_key: &auth::Key) -> Result<[u8; box_::PUBLICKEYBYTES], Error> {
+    let mut tag = [0u8; auth::TAGBYTES];
+    tag.copy_from_slice(&msg[..auth::TAGBYTES]);
+
+    if auth::verify(&auth::Tag(tag), &msg[auth::TAGBYTES..HELLO_SIZE], network_key) {
+        let mut eph_pk = [0u8; box_::PUBLICKEYBYTES];
+        eph_pk.copy_from_slice(&msg[auth::TAGBYTES..HELLO_SIZE]);
+        Ok(eph_pk)
+    } else {
+        Err(Error::new(ErrorKind::InvalidData, INVALID_HELLO))
+    }
+}
+
+fn scalarmult(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
+    curve25519::scalarmult(&curve25519::Scalar(*scalar),
+                           &curve25519::GroupElement(*point))
+            .0
+}
+
+fn curve_pk(pk: &sign::PublicKey) -> Result<[u8; 32], Error> {
+    let mut out = [0u8; 32];
+    if unsafe { crypto_sign_ed25519_pk_to_curve25519(&mut out, &pk.0) } == 0 {
+        Ok(out)
+    } else {
+        Err(Error::new(ErrorKind::InvalidInput, INVALID_KEY))
+    }
+}
+
+fn curve_sk(sk: &sign::SecretKey) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    unsafe {
+        crypto_sign_ed25519_sk_to_curve25519(&mut out, &sk.0);
+    }
+    out
+}
+
+fn signature(bytes: &[u8]) -> Option<sign::Signature> {
+    if bytes.len() == sign::SIGNATUREBYTES {
+        let mut sig = [0u8; sign::SIGNATUREBYTES];
+        sig.copy_from_slice(bytes);
+        Some(sign::Signature(sig))
+    } else {
+        None
+    }
+}
+
+fn zero_nonce() -> secretbox::Nonce {
+    secretbox::Nonce([0; secretbox::NONCEBYTES])
+}
+
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+    out
+}
+
+// Writes all of `buf[*offset..]` to `stream`, then flushes it.
+fn poll_write_all<S: AsyncWrite>(stream: &mut S,
+                                 cx: &mut Context,
+                                 buf: &[u8],
+                                 offset: &mut usize)
+                                 -> Poll<(), Error> {
+    while *offset < buf.len() {
+        let written = try_ready!(stream.poll_write(cx, &buf[*offset..]));
+        if written == 0 {
+            return Err(Error::new(ErrorKind::WriteZero, "failed to write handshake message"));
+        }
+        *offset += written;
+    }
+    stream.poll_flush(cx)
+}
+
+// Fills `buf[*offset..]` from `stream`.
+fn poll_read_exact<S: AsyncRead>(stream: &mut S,
+                                 cx: &mut Context,
+                                 buf: &mut [u8],
+                                 offset: &mut usize)
+                                 -> Poll<(), Error> {
+    while *offset < buf.len() {
+        let read = try_ready!(stream.poll_read(cx, &mut buf[*offset..]));
+        if read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "handshake ended prematurely"));
+        }
+        *offset += read;
+    }
+    Ok(Ready(()))
+}
@@ -0,0 +1,280 @@
+// Traffic statistics of box streams, and a registry aggregating them across streams.
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_SIZE};
+
+/// Traffic statistics of one direction of a box stream.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DirectionStats {
+    /// The number of plaintext bytes in sealed or authenticated packets.
+    pub plain_bytes: u64,
+    /// The number of bytes written to or read from the underlying stream.
+    pub cypher_bytes: u64,
+    /// The number of sealed or authenticated packets, not counting the final
+    /// header.
+    pub packets: u64,
+    /// The number of bytes spent on headers, including the final header.
+    pub header_bytes: u64,
+    /// The number of headers or packets that failed to authenticate, or that
+    /// declared an invalid length.
+    pub auth_failures: u64,
+    /// When the last packet was sealed or authenticated.
+    pub last_packet: Option<Instant>,
+}
+
+impl DirectionStats {
+    /// Returns the average number of plaintext bytes per packet, relative to
+    /// `MAX_PACKET_SIZE`, or `None` if there were no packets.
+    pub fn fill_ratio(&self) -> Option<f64> {
+        if self.packets == 0 {
+            None
+        } else {
+            Some(self.plain_bytes as f64 / (self.packets * MAX_PACKET_SIZE as u64) as f64)
+        }
+    }
+
+    /// Returns the time since the last packet was sealed or authenticated.
+    pub fn since_last_packet(&self) -> Option<Duration> {
+        self.last_packet.map(|instant| instant.elapsed())
+    }
+
+    fn add(&mut self, delta: &DirectionStats) {
+        self.plain_bytes += delta.plain_bytes;
+        self.cypher_bytes += delta.cypher_bytes;
+        self.packets += delta.packets;
+        self.header_bytes += delta.header_bytes;
+        self.auth_failures += delta.auth_failures;
+        if delta.last_packet.is_some() {
+            self.last_packet = delta.last_packet;
+        }
+    }
+}
+
+/// Traffic statistics of a box stream, as returned by the `stats` method of
+/// `BoxReader`, `BoxWriter` and `BoxDuplex`. The direction a wrapper does not
+/// handle stays zeroed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Stats {
+    /// Statistics of decrypted data.
+    pub read: DirectionStats,
+    /// Statistics of encrypted data.
+    pub written: DirectionStats,
+}
+
+/// Aggregates statistics across any number of box streams, e.g. to export
+/// them as metrics.
+///
+/// Streams are added via the `register` method of `BoxReader`, `BoxWriter` and
+/// `BoxDuplex`, and report into the registry for as long as they exist. The
+/// totals are atomic counters, so reporting never blocks on other streams.
+#[derive(Debug)]
+pub struct StatsRegistry {
+    created: Instant,
+    read: AtomicDirectionStats,
+    written: AtomicDirectionStats,
+    streams: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct AtomicDirectionStats {
+    plain_bytes: AtomicU64,
+    cypher_bytes: AtomicU64,
+    packets: AtomicU64,
+    header_bytes: AtomicU64,
+    auth_failures: AtomicU64,
+    // Nanoseconds from the creation of the registry to the last packet plus one, or zero if there
+    // was no packet yet.
+    last_packet: AtomicU64,
+}
+
+impl AtomicDirectionStats {
+    fn add(&self, delta: &DirectionStats, created: Instant) {
+        let counters = [(&self.plain_bytes, delta.plain_bytes),
+                        (&self.cypher_bytes, delta.cypher_bytes),
+                        (&self.packets, delta.packets),
+                        (&self.header_bytes, delta.header_bytes),
+                        (&self.auth_failures, delta.auth_failures)];
+        for &(counter, value) in counters.iter() {
+            if value != 0 {
+                counter.fetch_add(value, Ordering::Relaxed);
+            }
+        }
+
+        if let Some(instant) = delta.last_packet {
+            let since = instant.duration_since(created);
+            let nanos = since.as_secs() * 1_000_000_000 + since.subsec_nanos() as u64;
+            self.last_packet.fetch_max(nanos + 1, Ordering::Relaxed);
+        }
+    }
+
+    fn load(&self, created: Instant) -> DirectionStats {
+        DirectionStats {
+            plain_bytes: self.plain_bytes.load(Ordering::Relaxed),
+            cypher_bytes: self.cypher_bytes.load(Ordering::Relaxed),
+            packets: self.packets.load(Ordering::Relaxed),
+            header_bytes: self.header_bytes.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            last_packet: match self.last_packet.load(Ordering::Relaxed) {
+                0 => None,
+                nanos => Some(created + Duration::from_nanos(nanos - 1)),
+            },
+        }
+    }
+}
+
+impl Default for StatsRegistry {
+    fn default() -> StatsRegistry {
+        StatsRegistry::new()
+    }
+}
+
+impl StatsRegistry {
+    /// Create a new, empty registry.
+    pub fn new() -> StatsRegistry {
+        StatsRegistry {
+            created: Instant::now(),
+            read: AtomicDirectionStats::default(),
+            written: AtomicDirectionStats::default(),
+            streams: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the sums of the statistics of all registered streams. The
+    /// `last_packet` fields hold the time of the last packet of any stream.
+    ///
+    /// The counters are read one after the other, so they may be slightly
+    /// inconsistent with each other while streams are active.
+    pub fn totals(&self) -> Stats {
+        Stats {
+            read: self.read.load(self.created),
+            written: self.written.load(self.created),
+        }
+    }
+
+    /// Returns how many streams have been registered.
+    pub fn streams(&self) -> u64 {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    /// Render the totals in the Prometheus text exposition format, with metric
+    /// names starting with `box_stream_`.
+    pub fn render_prometheus(&self) -> String {
+        let stats = self.totals();
+        let streams = self.streams();
+
+        let mut out = String::new();
+        out.push_str("# TYPE box_stream_streams_total counter\n");
+        writeln!(out, "box_stream_streams_total {}", streams).unwrap();
+
+        let metrics: [(&str, fn(&DirectionStats) -> u64); 5] =
+            [("plain_bytes_total", |s| s.plain_bytes),
+             ("cypher_bytes_total", |s| s.cypher_bytes),
+             ("packets_total", |s| s.packets),
+             ("header_bytes_total", |s| s.header_bytes),
+             ("auth_failures_total", |s| s.auth_failures)];
+
+        for &(name, value) in metrics.iter() {
+            writeln!(out, "# TYPE box_stream_{} counter", name).unwrap();
+            for &(direction, direction_stats) in [("read", &stats.read), ("written", &stats.written)]
+                    .iter() {
+                writeln!(out,
+                         "box_stream_{}{{direction=\"{}\"}} {}",
+                         name,
+                         direction,
+                         value(direction_stats))
+                        .unwrap();
+            }
+        }
+
+        out
+    }
+
+    fn add(&self, direction: Direction, delta: &DirectionStats) {
+        match direction {
+            Direction::Read => self.read.add(delta, self.created),
+            Direction::Written => self.written.add(delta, self.created),
+        }
+    }
+}
+
+// The direction of data handled by a `Recorder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Read,
+    Written,
+}
+
+// Records the statistics of one direction of a box stream, and reports them to a registry.
+pub(crate) struct Recorder {
+    direction: Direction,
+    stats: DirectionStats,
+    registry: Option<Arc<StatsRegistry>>,
+}
+
+impl Recorder {
+    pub fn new(direction: Direction) -> Recorder {
+        Recorder {
+            direction,
+            stats: DirectionStats::default(),
+            registry: None,
+        }
+    }
+
+    pub fn stats(&self) -> DirectionStats {
+        self.stats
+    }
+
+    // Report all future events to `registry`, too, counting the stream if `count_stream` is set.
+    // A recorder reports to at most one registry, later registrations are ignored.
+    pub fn register(&mut self, registry: Arc<StatsRegistry>, count_stream: bool) {
+        if self.registry.is_some() {
+            return;
+        }
+        if count_stream {
+            registry.streams.fetch_add(1, Ordering::Relaxed);
+        }
+        self.registry = Some(registry);
+    }
+
+    pub fn cypher_bytes(&mut self, len: usize) {
+        self.record(DirectionStats {
+                        cypher_bytes: len as u64,
+                        ..DirectionStats::default()
+                    });
+    }
+
+    pub fn packet(&mut self, len: u16) {
+        self.record(DirectionStats {
+                        plain_bytes: len as u64,
+                        packets: 1,
+                        header_bytes: CYPHER_HEADER_SIZE as u64,
+                        last_packet: Some(Instant::now()),
+                        ..DirectionStats::default()
+                    });
+    }
+
+    pub fn final_header(&mut self) {
+        self.record(DirectionStats {
+                        header_bytes: CYPHER_HEADER_SIZE as u64,
+                        ..DirectionStats::default()
+                    });
+    }
+
+    pub fn auth_failure(&mut self) {
+        self.record(DirectionStats {
+                        auth_failures: 1,
+                        ..DirectionStats::default()
+                    });
+    }
+
+    fn record(&mut self, delta: DirectionStats) {
+        self.stats.add(&delta);
+        if let Some(ref registry) = self.registry {
+            registry.add(self.direction, &delta);
+        }
+    }
+}
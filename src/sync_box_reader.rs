@@ -0,0 +1,129 @@
+// Implementation of SyncBoxReader, a wrapper for blocking readers that decrypts all reads.
+
+use std::cmp::min;
+use std::io::{Error, ErrorKind, Read};
+
+use sodiumoxide::crypto::secretbox;
+
+use blocking::{Buffer, read_exact_authenticated};
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_SIZE, PlainHeader, decrypt_header,
+             decrypt_packet_inplace};
+use decryptor::{INVALID_LENGTH, UNAUTHENTICATED_HEADER, UNAUTHENTICATED_PACKET};
+
+/// Wraps a blocking reader, decrypting all reads.
+pub struct SyncBoxReader<R> {
+    inner: R,
+    key: secretbox::Key,
+    nonce: secretbox::Nonce,
+    buffer: Buffer,
+    // The unread plaintext is buffer[offset..length].
+    offset: usize,
+    length: usize,
+    done: bool,
+}
+
+impl<R> SyncBoxReader<R> {
+    /// Create a new reader, wrapping `inner` and using `key` and `nonce` for
+    /// decryption.
+    pub fn new(inner: R, key: secretbox::Key, nonce: secretbox::Nonce) -> SyncBoxReader<R> {
+        SyncBoxReader {
+            inner,
+            key,
+            nonce,
+            buffer: Buffer::new(),
+            offset: 0,
+            length: 0,
+            done: false,
+        }
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying reader.
+    ///
+    /// It is inadvisable to directly read from the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns whether the final header has been read.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Unwraps this `SyncBoxReader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> SyncBoxReader<R> {
+    // Read, authenticate and decrypt the next packet into the buffer, or set `done` if the
+    // next header is the final header.
+    fn read_packet(&mut self) -> Result<(), Error> {
+        let mut cypher_header = [0u8; CYPHER_HEADER_SIZE];
+        read_exact_authenticated(&mut self.inner, &mut cypher_header)?;
+
+        let mut plain_header = PlainHeader::new();
+        if !unsafe {
+                decrypt_header(&mut plain_header,
+                               &cypher_header,
+                               &self.key.0,
+                               &mut self.nonce.0)
+            } {
+            return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_HEADER));
+        }
+
+        if plain_header.is_final_header() {
+            self.done = true;
+            return Ok(());
+        }
+
+        let len = plain_header.get_packet_len();
+        if len > MAX_PACKET_SIZE || len == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, INVALID_LENGTH));
+        }
+
+        read_exact_authenticated(&mut self.inner, &mut self.buffer.0[..len as usize])?;
+        if !unsafe {
+                decrypt_packet_inplace(self.buffer.0.as_mut_ptr(),
+                                       &plain_header,
+                                       &self.key.0,
+                                       &mut self.nonce.0)
+            } {
+            return Err(Error::new(ErrorKind::InvalidData, UNAUTHENTICATED_PACKET));
+        }
+
+        self.offset = 0;
+        self.length = len as usize;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SyncBoxReader<R> {
+    /// Read bytes from the wrapped reader and decrypt them. End of stream is signalled by
+    /// returning `Ok(0)` even though this function was passed a buffer of nonzero length.
+    ///
+    /// # Errors
+    /// Produces the same errors as a `BoxReader`.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+
+        while self.offset == self.length {
+            if self.done {
+                return Ok(0);
+            }
+            self.read_packet()?;
+        }
+
+        let read = min(buf.len(), self.length - self.offset);
+        buf[..read].copy_from_slice(&self.buffer.0[self.offset..self.offset + read]);
+        self.offset += read;
+        Ok(read)
+    }
+}
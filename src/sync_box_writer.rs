@@ -0,0 +1,165 @@
+// Implementation of SyncBoxWriter, a wrapper for blocking writers that encrypts all writes.
+
+use std::cmp::min;
+use std::io::{Error, ErrorKind, Write};
+
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_USIZE, encrypt_packet, final_header};
+
+const BUFFER_SIZE: usize = CYPHER_HEADER_SIZE + MAX_PACKET_USIZE;
+
+/// The error value signaling a write to a `SyncBoxWriter` that has already
+/// been closed.
+pub const WRITE_AFTER_CLOSE: &'static str = "write after closing the box stream";
+
+/// Wraps a blocking writer, encrypting all writes.
+///
+/// Unlike a `BoxWriter`, this buffers written data until a packet of
+/// `MAX_PACKET_SIZE` bytes is full, or until `flush` is called.
+///
+/// In fixed-size mode (see `fixed_size`), `flush` does not emit partial
+/// packets, so that every packet except the last has exactly
+/// `MAX_PACKET_SIZE` bytes. Such streams can be read by a `SeekableBoxReader`.
+pub struct SyncBoxWriter<W> {
+    inner: W,
+    key: secretbox::Key,
+    nonce: secretbox::Nonce,
+    buffers: Buffers,
+    filled: usize,
+    fixed_size: bool,
+    closed: bool,
+}
+
+struct Buffers {
+    // Plaintext of the next packet.
+    plain: [u8; MAX_PACKET_USIZE],
+    // The next packet gets encrypted into this buffer.
+    cypher: [u8; BUFFER_SIZE],
+}
+
+/// Zero buffered data on dropping.
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        memzero(&mut self.plain);
+        memzero(&mut self.cypher);
+    }
+}
+
+impl<W> SyncBoxWriter<W> {
+    /// Create a new writer, wrapping `inner` and using `key` and `nonce` for
+    /// encryption.
+    pub fn new(inner: W, key: secretbox::Key, nonce: secretbox::Nonce) -> SyncBoxWriter<W> {
+        SyncBoxWriter {
+            inner,
+            key,
+            nonce,
+            buffers: Buffers {
+                plain: [0; MAX_PACKET_USIZE],
+                cypher: [0; BUFFER_SIZE],
+            },
+            filled: 0,
+            fixed_size: false,
+            closed: false,
+        }
+    }
+
+    /// Create a new writer in fixed-size mode, wrapping `inner` and using `key`
+    /// and `nonce` for encryption.
+    pub fn fixed_size(inner: W, key: secretbox::Key, nonce: secretbox::Nonce) -> SyncBoxWriter<W> {
+        SyncBoxWriter { fixed_size: true, ..SyncBoxWriter::new(inner, key, nonce) }
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    ///
+    /// It is inadvisable to directly write to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the nonce that will be used for the next packet.
+    pub fn nonce(&self) -> &secretbox::Nonce {
+        &self.nonce
+    }
+
+    /// Unwraps this `SyncBoxWriter`, returning the underlying writer.
+    ///
+    /// Buffered data that has not been flushed is discarded.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> SyncBoxWriter<W> {
+    /// Flush all buffered data, then write the final header which signals the
+    /// end of the box stream, and flush the underlying writer.
+    ///
+    /// Writing to the `SyncBoxWriter` afterwards results in an error.
+    pub fn close(&mut self) -> Result<(), Error> {
+        if !self.closed {
+            self.write_packet()?;
+            unsafe {
+                final_header(&mut *(self.buffers.cypher.as_mut_ptr() as
+                                    *mut [u8; CYPHER_HEADER_SIZE]),
+                             &self.key.0,
+                             &self.nonce.0);
+            }
+            self.closed = true;
+            self.inner.write_all(&self.buffers.cypher[..CYPHER_HEADER_SIZE])?;
+        }
+        self.inner.flush()
+    }
+
+    // Encrypt and write the buffered plaintext, if there is any.
+    fn write_packet(&mut self) -> Result<(), Error> {
+        if self.filled == 0 {
+            return Ok(());
+        }
+
+        unsafe {
+            encrypt_packet(self.buffers.cypher.as_mut_ptr(),
+                           self.buffers.plain.as_ptr(),
+                           self.filled as u16,
+                           &self.key.0,
+                           &mut self.nonce.0);
+        }
+
+        let len = CYPHER_HEADER_SIZE + self.filled;
+        self.filled = 0;
+        self.inner.write_all(&self.buffers.cypher[..len])
+    }
+}
+
+impl<W: Write> Write for SyncBoxWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.closed {
+            return Err(Error::new(ErrorKind::BrokenPipe, WRITE_AFTER_CLOSE));
+        }
+
+        if self.filled == MAX_PACKET_USIZE {
+            self.write_packet()?;
+        }
+
+        let written = min(buf.len(), MAX_PACKET_USIZE - self.filled);
+        self.buffers.plain[self.filled..self.filled + written].copy_from_slice(&buf[..written]);
+        self.filled += written;
+        Ok(written)
+    }
+
+    /// Encrypt and write all buffered data as a (possibly shorter than
+    /// `MAX_PACKET_SIZE`) packet, then flush the underlying writer.
+    ///
+    /// In fixed-size mode, only the underlying writer is flushed.
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.fixed_size {
+            self.write_packet()?;
+        }
+        self.inner.flush()
+    }
+}
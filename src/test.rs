@@ -1,11 +1,14 @@
 use super::*;
 
+use std::io::{self, Cursor, ErrorKind};
+
 use futures::FutureExt;
 use futures::executor::block_on;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use sodiumoxide;
 
 use async_ringbuffer::*;
+use testing::*;
 
 #[test]
 fn success() {
@@ -31,132 +34,1858 @@ fn success() {
     assert!(block_on(write_all.join(read_all)).is_ok());
 }
 
-// #[test]
-// // A reader propagates io errors.
-// fn test_reader_io_error() {
-//     let key = sodiumoxide::crypto::secretbox::gen_key();
-//     let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
-//
-//     let r = MockDuplex::new();
-//     let r = PartialAsyncRead::new(r,
-//                                   vec![PartialOp::Err(ErrorKind::NotFound),
-//                                        PartialOp::Err(ErrorKind::UnexpectedEof)]);
-//
-//     let mut b = BoxReader::new(r, key, nonce);
-//
-//     assert_eq!(b.read(&mut []).unwrap_err().kind(), ErrorKind::NotFound);
-//     assert_eq!(b.read(&mut []).unwrap_err().kind(),
-//                ErrorKind::UnexpectedEof);
-// }
-//
-// #[test]
-// // A reader that reads 0 bytes errors with UnexpectedEof.
-// fn test_reader_read0() {
-//     let key = sodiumoxide::crypto::secretbox::gen_key();
-//     let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
-//
-//     let r = MockDuplex::new();
-//     let r = PartialAsyncRead::new(r, vec![PartialOp::Limited(0)]);
-//
-//     let mut b = BoxReader::new(r, key, nonce);
-//
-//     assert_eq!(b.read(&mut []).unwrap_err().kind(),
-//                ErrorKind::UnexpectedEof);
-// }
-//
-// #[test]
-// // A reader that reads a final header signals it via read returning Ok(0).
-// fn test_reader_final_header() {
-//     let key = sodiumoxide::crypto::secretbox::gen_key();
-//     let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
-//
-//     let inner = MockDuplex::new();
-//     let mut b = BoxWriter::new(inner, key.clone(), nonce.clone());
-//     assert_eq!(b.write(&[0, 1, 2, 3]).unwrap(), 4);
-//     assert_eq!(b.write_final_header().unwrap(), ());
-//     let (_, writes_deque) = b.into_inner().into_inner();
-//     let (data, _) = writes_deque.as_slices();
-//
-//     let mut r = MockDuplex::new();
-//     r.add_read_data(data);
-//
-//     let mut b = BoxReader::new(r, key, nonce);
-//
-//     assert_eq!(b.read(&mut [10, 20, 30, 40]).unwrap(), 4);
-//     assert_eq!(b.read(&mut []).unwrap(), 0);
-// }
-//
-// #[test]
-// // A writer propagates io errors.
-// fn test_writer_io_error() {
-//     let key = sodiumoxide::crypto::secretbox::gen_key();
-//     let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
-//
-//     let w = MockDuplex::new();
-//     let w = PartialAsyncWrite::new(w,
-//                                    vec![PartialOp::Err(ErrorKind::NotFound),
-//                                         PartialOp::Err(ErrorKind::UnexpectedEof)]);
-//
-//     let mut b = BoxWriter::new(w, key, nonce);
-//
-//     assert_eq!(b.flush().unwrap_err().kind(), ErrorKind::NotFound);
-//     assert_eq!(b.flush().unwrap_err().kind(), ErrorKind::UnexpectedEof);
-// }
-//
-// #[test]
-// // A writer errors WriteZero if writing to the underlying Write during flushing returns Ok(0).
-// fn test_writer_write0_flush() {
-//     {
-//         let key = sodiumoxide::crypto::secretbox::gen_key();
-//         let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
-//
-//         let w = MockDuplex::new();
-//         let w = PartialAsyncWrite::new(w, vec![PartialOp::Limited(0)]);
-//
-//         let mut b = BoxWriter::new(w, key, nonce);
-//
-//         assert_eq!(b.write(&[0, 1, 2, 3]).unwrap(), 4);
-//         assert_eq!(b.flush().unwrap_err().kind(), ErrorKind::WriteZero);
-//     }
-//
-//     {
-//         let key = sodiumoxide::crypto::secretbox::gen_key();
-//         let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
-//
-//         let w = MockDuplex::new();
-//         let w = PartialAsyncWrite::new(w, vec![PartialOp::Limited(2), PartialOp::Limited(0)]);
-//
-//         let mut b = BoxWriter::new(w, key, nonce);
-//
-//         assert_eq!(b.write(&[0, 1, 2, 3]).unwrap(), 4);
-//         assert_eq!(b.flush().unwrap_err().kind(), ErrorKind::WriteZero);
-//     }
-// }
-//
-// #[test]
-// // A writer errors WriteZero if writing to the underlying Write during shutdown returns Ok(0).
-// fn test_writer_write0_shutdown() {
-//     {
-//         let key = sodiumoxide::crypto::secretbox::gen_key();
-//         let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
-//
-//         let w = MockDuplex::new();
-//         let w = PartialAsyncWrite::new(w, vec![PartialOp::Limited(0)]);
-//
-//         let mut b = BoxWriter::new(w, key, nonce);
-//
-//         assert_eq!(b.shutdown().unwrap_err().kind(), ErrorKind::WriteZero);
-//     }
-//
-//     {
-//         let key = sodiumoxide::crypto::secretbox::gen_key();
-//         let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
-//
-//         let w = MockDuplex::new();
-//         let w = PartialAsyncWrite::new(w, vec![PartialOp::Limited(2), PartialOp::Limited(0)]);
-//
-//         let mut b = BoxWriter::new(w, key, nonce);
-//
-//         assert_eq!(b.shutdown().unwrap_err().kind(), ErrorKind::WriteZero);
-//     }
-// }
+#[test]
+fn stats_and_registry() {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    let registry = Arc::new(StatsRegistry::new());
+
+    let mut writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    writer.register(&registry);
+    let (writer, _) = block_on(writer.write_all(data.clone())).unwrap();
+    let writer = block_on(writer.close()).unwrap();
+
+    let written = writer.stats().written;
+    assert_eq!(written.plain_bytes, 10000);
+    assert_eq!(written.packets, 3);
+    assert_eq!(written.header_bytes, 4 * 34);
+    assert_eq!(written.cypher_bytes, 10000 + 4 * 34);
+    assert!(written.since_last_packet().is_some());
+    assert_eq!(writer.stats().read, DirectionStats::default());
+
+    let mut cypher = writer.into_inner().into_inner();
+    cypher[5000] ^= 1;
+    let mut reader = BoxReader::new(Cursor::new(cypher), key, nonce);
+    reader.register(&registry);
+    assert!(block_on((&mut reader).read_to_end(Vec::new())).is_err());
+
+    let read = reader.stats().read;
+    assert_eq!(read.packets, 1);
+    assert_eq!(read.plain_bytes, 4096);
+    assert_eq!(read.auth_failures, 1);
+
+    let totals = registry.totals();
+    assert_eq!(registry.streams(), 2);
+    assert_eq!(totals.written.plain_bytes, 10000);
+    assert_eq!(totals.read.auth_failures, 1);
+    assert!(registry
+                .render_prometheus()
+                .contains("box_stream_auth_failures_total{direction=\"read\"} 1\n"));
+}
+
+#[test]
+fn stats_registry_registers_once() {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let first = Arc::new(StatsRegistry::new());
+    let second = Arc::new(StatsRegistry::new());
+
+    let mut writer = BoxWriter::new(Cursor::new(Vec::new()), key, nonce);
+    writer.register(&first);
+    writer.register(&first);
+    writer.register(&second);
+    let (writer, _) = block_on(writer.write_all(vec![0u8; 100])).unwrap();
+    block_on(writer.close()).unwrap();
+
+    assert_eq!(first.streams(), 1);
+    assert_eq!(first.totals().written.plain_bytes, 100);
+    assert_eq!(first.totals().written.packets, 1);
+    assert!(first.totals().written.last_packet.is_some());
+    assert_eq!(second.streams(), 0);
+    assert_eq!(second.totals(), Stats::default());
+}
+
+#[test]
+fn packet_observer_veto() {
+    use std::io::{Cursor, Error, ErrorKind};
+
+    const TOO_SMALL: &'static str = "packet too small";
+
+    struct MinimumSize(u16);
+
+    impl PacketObserver for MinimumSize {
+        fn observe(&mut self, packet: &PacketInfo) -> Result<(), Error> {
+            if packet.len < self.0 {
+                Err(Error::new(ErrorKind::Other, TOO_SMALL))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let mut data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+    data.push(0);
+    let cypher = seal_all(&data, &key, &nonce);
+
+    let mut reader = BoxReader::new(Cursor::new(cypher), key.clone(), nonce.clone());
+    reader.set_observer(MinimumSize(16));
+    let err = block_on((&mut reader).read_to_end(Vec::new())).unwrap_err();
+    assert_eq!(err.to_string(), TOO_SMALL);
+    assert_eq!(reader.stats().read.packets, 2);
+    let err = block_on((&mut reader).read(vec![0u8; 16])).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+    assert_eq!(err.to_string(), TOO_SMALL);
+
+    // The last packet only holds a single byte, and is vetoed before being written.
+    let mut writer = BoxWriter::new(Cursor::new(Vec::new()), key, nonce);
+    writer.set_observer(MinimumSize(16));
+    let err = block_on((&mut writer).write_all(data)).unwrap_err();
+    assert_eq!(err.to_string(), TOO_SMALL);
+    assert_eq!(writer.stats().written.packets, 1);
+    let err = block_on((&mut writer).write_all(vec![0u8; 100])).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+    assert_eq!(err.to_string(), TOO_SMALL);
+    let err = block_on(writer.close()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+    assert_eq!(err.to_string(), TOO_SMALL);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_span() {
+    use std::io::Cursor;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let mut writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    writer.set_span(debug_span!("connection", id = 42));
+    let (writer, _) = block_on(writer.write_all(vec![1, 2, 3])).unwrap();
+    let writer = block_on(writer.close()).unwrap();
+
+    let mut reader = BoxReader::new(Cursor::new(writer.into_inner().into_inner()), key, nonce);
+    reader.set_span(debug_span!("connection", id = 42));
+    let (_, read) = block_on(reader.read_to_end(Vec::new())).unwrap();
+    assert_eq!(read, vec![1, 2, 3]);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_matches_box_writer() {
+    use std::io::Cursor;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+
+    let writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let (writer, _) = block_on(writer.write_all(data.clone())).unwrap();
+    let writer = block_on(writer.close()).unwrap();
+    let expected = writer.into_inner().into_inner();
+
+    let cypher = encrypt_parallel(&data, &key, &nonce);
+    assert_eq!(cypher, expected);
+    assert_eq!(cypher.len(), encrypted_len(data.len()));
+    assert_eq!(decrypt_parallel(&cypher, &key, &nonce).unwrap(), data);
+
+    let mut tampered = cypher.clone();
+    tampered[5000] ^= 1;
+    assert_eq!(decrypt_parallel(&tampered, &key, &nonce)
+                   .unwrap_err()
+                   .to_string(),
+               UNAUTHENTICATED_PACKET);
+
+    let mut trailing = cypher.clone();
+    trailing.push(0);
+    assert_eq!(decrypt_parallel(&trailing, &key, &nonce)
+                   .unwrap_err()
+                   .to_string(),
+               TRAILING_DATA);
+}
+
+#[test]
+fn seal_all_matches_box_writer() {
+    use std::io::Cursor;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+
+    let writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let (writer, _) = block_on(writer.write_all(data.clone())).unwrap();
+    let writer = block_on(writer.close()).unwrap();
+    let expected = writer.into_inner().into_inner();
+
+    let cypher = seal_all(&data, &key, &nonce);
+    assert_eq!(cypher, expected);
+    assert_eq!(open_all(&cypher, &key, &nonce).unwrap(), data);
+
+    assert_eq!(seal_all(&[], &key, &nonce).len(), 34);
+    assert_eq!(open_all(&seal_all(&[], &key, &nonce), &key, &nonce).unwrap(),
+               Vec::<u8>::new());
+
+    let mut trailing = cypher.clone();
+    trailing.push(0);
+    assert_eq!(open_all(&trailing, &key, &nonce).unwrap_err().to_string(),
+               TRAILING_DATA);
+
+    assert_eq!(open_all(&cypher[..cypher.len() - 1], &key, &nonce)
+                   .unwrap_err()
+                   .to_string(),
+               UNAUTHENTICATED_EOF);
+}
+
+#[cfg(feature = "shs")]
+#[test]
+fn shs_handshake() {
+    use atm_io_utils::Duplex;
+    use sodiumoxide::crypto::{auth, sign};
+    use shs::*;
+
+    let network_key = auth::gen_key();
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+
+    let (client_writer, server_reader) = ring_buffer(8);
+    let (server_writer, client_reader) = ring_buffer(8);
+
+    let client = client_handshake(Duplex::new(client_reader, client_writer),
+                                  network_key.clone(),
+                                  client_pk.clone(),
+                                  client_sk,
+                                  server_pk.clone());
+    let server = server_handshake(Duplex::new(server_reader, server_writer),
+                                  network_key,
+                                  server_pk.clone(),
+                                  server_sk);
+
+    let ((client, authenticated_server), (server, authenticated_client)) =
+        block_on(client.join(server)).unwrap();
+    assert_eq!(authenticated_server, server_pk);
+    assert_eq!(authenticated_client, client_pk);
+
+    let data: Vec<u8> = (0..255).collect();
+    let write_all = client
+        .write_all(data.clone())
+        .and_then(|(client, _)| client.close());
+    let read_all = server.read_to_end(Vec::new());
+
+    let (_, (_, read_data)) = block_on(write_all.join(read_all)).unwrap();
+    assert_eq!(read_data, data);
+}
+
+#[cfg(feature = "shs")]
+#[test]
+fn shs_wrong_server() {
+    use atm_io_utils::Duplex;
+    use sodiumoxide::crypto::{auth, sign};
+    use shs::*;
+
+    let network_key = auth::gen_key();
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (other_pk, _) = sign::gen_keypair();
+
+    let (client_writer, server_reader) = ring_buffer(8);
+    let (server_writer, client_reader) = ring_buffer(8);
+
+    let client = client_handshake(Duplex::new(client_reader, client_writer),
+                                  network_key.clone(),
+                                  client_pk,
+                                  client_sk,
+                                  other_pk);
+    let server = server_handshake(Duplex::new(server_reader, server_writer),
+                                  network_key,
+                                  server_pk,
+                                  server_sk);
+
+    assert_eq!(block_on(client.join(server)).map(|_| ()).unwrap_err().to_string(),
+               INVALID_AUTH);
+}
+
+// Returns two connected BoxDuplexes.
+#[cfg(feature = "packet_stream")]
+fn box_duplex_pair(capacity: usize)
+                   -> (BoxDuplex<atm_io_utils::Duplex<Reader, Writer>>,
+                       BoxDuplex<atm_io_utils::Duplex<Reader, Writer>>) {
+    use atm_io_utils::Duplex;
+
+    let key_a = sodiumoxide::crypto::secretbox::gen_key();
+    let key_b = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce_a = sodiumoxide::crypto::secretbox::gen_nonce();
+    let nonce_b = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let (writer_a, reader_b) = ring_buffer(capacity);
+    let (writer_b, reader_a) = ring_buffer(capacity);
+
+    (BoxDuplex::new(Duplex::new(reader_a, writer_a),
+                    key_a.clone(),
+                    key_b.clone(),
+                    nonce_a.clone(),
+                    nonce_b.clone()),
+     BoxDuplex::new(Duplex::new(reader_b, writer_b), key_b, key_a, nonce_b, nonce_a))
+}
+
+#[cfg(feature = "packet_stream")]
+#[test]
+fn packet_stream_roundtrip() {
+    use futures::{SinkExt, StreamExt};
+    use packet_stream::*;
+
+    let (a, b) = box_duplex_pair(16);
+    let a = PacketStream::new(a);
+    let b = PacketStream::with_max_body_len(b, 4);
+
+    let packets = vec![Packet {
+                           request: 1,
+                           stream: false,
+                           end: false,
+                           body: Body::Json("[1]".to_string()),
+                       },
+                       Packet {
+                           request: -1,
+                           stream: true,
+                           end: true,
+                           body: Body::Utf8("bye".to_string()),
+                       },
+                       Packet {
+                           request: 2,
+                           stream: true,
+                           end: false,
+                           body: Body::Binary(vec![]),
+                       }];
+
+    let send = a.send(packets[0].clone())
+        .and_then(|a| a.send(packets[1].clone()))
+        .and_then(|a| a.send(packets[2].clone()))
+        .and_then(|a| a.close());
+    let receive = b.collect();
+
+    let (_, received) = block_on(send.join(receive)).unwrap();
+    assert_eq!(received, packets);
+}
+
+#[cfg(feature = "packet_stream")]
+#[test]
+fn packet_stream_body_too_long() {
+    use futures::{SinkExt, StreamExt};
+    use packet_stream::*;
+
+    let (a, b) = box_duplex_pair(16);
+    let a = PacketStream::new(a);
+    let mut b = PacketStream::with_max_body_len(b, 4);
+
+    let send = a.send(Packet {
+                          request: 1,
+                          stream: false,
+                          end: false,
+                          body: Body::Binary(vec![0; 5]),
+                      })
+        .and_then(|a| a.close());
+    let receive = (&mut b).next().map_err(|(err, _)| err);
+
+    assert_eq!(block_on(send.join(receive)).map(|_| ()).unwrap_err().to_string(),
+               BODY_TOO_LONG);
+
+    // The rejected body is not parsed as the next header.
+    let (err, _) = block_on((&mut b).next()).map(|_| ()).unwrap_err();
+    assert_eq!(err.to_string(), BODY_TOO_LONG);
+}
+
+#[cfg(feature = "muxrpc")]
+#[test]
+fn muxrpc_async_request() {
+    use futures::{SinkExt, StreamExt};
+    use muxrpc::*;
+    use packet_stream::*;
+
+    let (a, b) = box_duplex_pair(16);
+    let (driver_a, client_a, _) = muxrpc(PacketStream::new(a));
+    let (driver_b, client_b, incoming_b) = muxrpc(PacketStream::new(b));
+
+    let a_logic = client_a
+        .request(Body::Json("\"ping\"".to_string()))
+        .map(move |response| {
+                 client_a.close();
+                 response
+             });
+    let b_logic = incoming_b
+        .next()
+        .map_err(|(err, _)| err)
+        .and_then(move |(request, _)| {
+            let request = request.unwrap();
+            assert_eq!(request.body, Body::Json("\"ping\"".to_string()));
+            request
+                .sender
+                .send(Body::Json("\"pong\"".to_string()))
+                .map(move |_| client_b.close())
+        });
+
+    let (_, (response, _)) = block_on(driver_a.join(driver_b).join(a_logic.join(b_logic)))
+        .unwrap();
+    assert_eq!(response, Body::Json("\"pong\"".to_string()));
+}
+
+#[cfg(feature = "muxrpc")]
+#[test]
+fn muxrpc_source_stream() {
+    use futures::{SinkExt, StreamExt};
+    use futures::stream::iter_ok;
+    use muxrpc::*;
+    use packet_stream::*;
+
+    let (a, b) = box_duplex_pair(16);
+    let (driver_a, client_a, _) = muxrpc(PacketStream::new(a));
+    let (driver_b, client_b, incoming_b) = muxrpc(PacketStream::new(b));
+
+    let items: Vec<Body> = (0..20).map(|i| Body::Json(i.to_string())).collect();
+    let expected = items.clone();
+
+    let a_logic = client_a
+        .source(Body::Json("\"numbers\"".to_string()))
+        .collect()
+        .map(move |received| {
+                 client_a.close();
+                 received
+             });
+    let b_logic = incoming_b
+        .next()
+        .map_err(|(err, _)| err)
+        .and_then(move |(request, _)| {
+            let request = request.unwrap();
+            assert!(request.stream);
+            request
+                .sender
+                .send_all(iter_ok::<_, std::io::Error>(items))
+                .and_then(|(sender, _)| sender.close())
+                .map(move |_| client_b.close())
+        });
+
+    let (_, (received, _)) = block_on(driver_a.join(driver_b).join(a_logic.join(b_logic)))
+        .unwrap();
+    assert_eq!(received, expected);
+}
+
+// A packet of a muxrpc request with a json body.
+#[cfg(feature = "muxrpc")]
+fn json_packet(request: i32, stream: bool, end: bool, body: &str) -> packet_stream::Packet {
+    packet_stream::Packet {
+        request,
+        stream,
+        end,
+        body: packet_stream::Body::Json(body.to_string()),
+    }
+}
+
+#[cfg(feature = "muxrpc")]
+#[test]
+// A request number which can not be negated is ignored, instead of crashing the driver.
+fn muxrpc_request_min() {
+    use futures::{SinkExt, StreamExt};
+    use muxrpc::*;
+    use packet_stream::*;
+
+    let (a, b) = box_duplex_pair(64);
+    let a = PacketStream::new(a);
+    let (driver_b, client_b, incoming_b) = muxrpc(PacketStream::new(b));
+
+    let a_logic = a.send(json_packet(i32::min_value(), false, false, "\"evil\""))
+        .and_then(|a| a.send(json_packet(1, false, false, "\"ping\"")))
+        .and_then(|a| a.next().map_err(|(err, _)| err))
+        .and_then(|(response, a)| a.close().map(move |a| (response, a)))
+        .and_then(|(response, a)| a.collect().map(move |rest| (response, rest)));
+    let b_logic = incoming_b
+        .next()
+        .map_err(|(err, _)| err)
+        .and_then(move |(request, _)| {
+            let request = request.unwrap();
+            assert_eq!(request.body, Body::Json("\"ping\"".to_string()));
+            request
+                .sender
+                .send(Body::Json("\"pong\"".to_string()))
+                .map(move |_| client_b.close())
+        });
+
+    let (_, ((response, rest), _)) = block_on(driver_b.join(a_logic.join(b_logic))).unwrap();
+    assert_eq!(response, Some(json_packet(-1, false, false, "\"pong\"")));
+    assert!(rest.is_empty());
+}
+
+#[cfg(feature = "muxrpc")]
+#[test]
+// An end packet for a request that does not exist (any more) is ignored, instead of being taken
+// for a new request.
+fn muxrpc_stray_end() {
+    use futures::{SinkExt, StreamExt};
+    use muxrpc::*;
+    use packet_stream::*;
+
+    let (a, b) = box_duplex_pair(64);
+    let a = PacketStream::new(a);
+    let (driver_b, client_b, incoming_b) = muxrpc(PacketStream::new(b));
+
+    let a_logic = a.send(json_packet(5, true, true, "true"))
+        .and_then(|a| a.send(json_packet(1, false, false, "\"ping\"")))
+        .and_then(|a| a.next().map_err(|(err, _)| err))
+        .and_then(|(response, a)| a.close().map(move |a| (response, a)))
+        .and_then(|(response, a)| a.collect().map(move |rest| (response, rest)));
+    let b_logic = incoming_b
+        .next()
+        .map_err(|(err, _)| err)
+        .and_then(move |(request, _)| {
+            let request = request.unwrap();
+            assert_eq!(request.body, Body::Json("\"ping\"".to_string()));
+            request
+                .sender
+                .send(Body::Json("\"pong\"".to_string()))
+                .map(move |_| client_b.close())
+        });
+
+    let (_, ((response, rest), _)) = block_on(driver_b.join(a_logic.join(b_logic))).unwrap();
+    assert_eq!(response, Some(json_packet(-1, false, false, "\"pong\"")));
+    assert!(rest.is_empty());
+}
+
+#[cfg(feature = "muxrpc")]
+#[test]
+// Dropping a source stream before it ends tells the peer to end it.
+fn muxrpc_cancel_source() {
+    use futures::{SinkExt, StreamExt};
+    use futures::stream::iter_ok;
+    use muxrpc::*;
+    use packet_stream::*;
+
+    let (a, b) = box_duplex_pair(64);
+    let a = PacketStream::new(a);
+    let (driver_b, client_b, _) = muxrpc(PacketStream::new(b));
+
+    let source = client_b.source(Body::Json("\"numbers\"".to_string()));
+    let b_logic = source
+        .next()
+        .map_err(|(err, _)| err)
+        .map(move |(first, source)| {
+                 drop(source);
+                 client_b.close();
+                 first
+             });
+
+    let data: Vec<Packet> = (0..3).map(|i| json_packet(-1, true, false, &i.to_string())).collect();
+    let a_logic = a.next()
+        .map_err(|(err, _)| err)
+        .and_then(|(request, a)| {
+            assert_eq!(request, Some(json_packet(1, true, false, "\"numbers\"")));
+            a.send_all(iter_ok::<_, io::Error>(data))
+        })
+        .and_then(|(a, _)| a.next().map_err(|(err, _)| err))
+        .and_then(|(end, a)| a.close().map(move |a| (end, a)))
+        .and_then(|(end, a)| a.collect().map(move |rest| (end, rest)));
+
+    let (_, ((end, rest), first)) = block_on(driver_b.join(a_logic.join(b_logic))).unwrap();
+    assert_eq!(first, Some(Body::Json("0".to_string())));
+    assert_eq!(end, Some(json_packet(1, true, true, "true")));
+    assert!(rest.is_empty());
+}
+
+#[cfg(feature = "muxrpc")]
+#[test]
+// A stream which always has data to send does not starve the other streams.
+fn muxrpc_fairness() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::{SinkExt, StreamExt};
+    use futures::stream::iter_ok;
+    use muxrpc::*;
+    use packet_stream::*;
+
+    let (a, b) = box_duplex_pair(1024);
+    let (driver_a, client_a, _) = muxrpc(PacketStream::new(a));
+    let (driver_b, client_b, incoming_b) = muxrpc(PacketStream::new(b));
+
+    // Responds to each of the first two requests with as many items as the request asks for.
+    let b_logic = incoming_b
+        .next()
+        .map_err(|(err, _)| err)
+        .and_then(|(first, incoming)| {
+                      incoming
+                          .next()
+                          .map_err(|(err, _)| err)
+                          .map(move |(second, _)| (first.unwrap(), second.unwrap()))
+                  })
+        .and_then(move |(first, second)| {
+            let items = |request: &IncomingRequest| {
+                let n: usize = match request.body {
+                    Body::Json(ref n) => n.parse().unwrap(),
+                    _ => panic!("unexpected request body"),
+                };
+                iter_ok::<_, io::Error>((0..n).map(|i| Body::Json(i.to_string()))
+                                            .collect::<Vec<_>>())
+            };
+            let first_items = items(&first);
+            let second_items = items(&second);
+
+            first
+                .sender
+                .send_all(first_items)
+                .and_then(|(sender, _)| sender.close())
+                .join(second
+                          .sender
+                          .send_all(second_items)
+                          .and_then(|(sender, _)| sender.close()))
+                .map(move |_| client_b.close())
+        });
+
+    let busy_received = Arc::new(AtomicUsize::new(0));
+    let counter = busy_received.clone();
+    let busy = client_a
+        .source(Body::Json("10000".to_string()))
+        .map(move |item| {
+                 counter.fetch_add(1, Ordering::SeqCst);
+                 item
+             })
+        .collect();
+    let small = client_a
+        .source(Body::Json("3".to_string()))
+        .collect()
+        .map(move |items| (items, busy_received.load(Ordering::SeqCst)));
+    let a_logic = small
+        .join(busy)
+        .map(move |result| {
+                 client_a.close();
+                 result
+             });
+
+    let (_, (((small, busy_before), busy), _)) =
+        block_on(driver_a.join(driver_b).join(a_logic.join(b_logic))).unwrap();
+    assert_eq!(small.len(), 3);
+    assert_eq!(busy.len(), 10000);
+    assert!(busy_before < 1000);
+}
+
+#[cfg(feature = "sender")]
+#[test]
+// Messages of several producers are written whole, one after the other, and closing writes the
+// final header after all of them.
+fn box_sender_many_producers() {
+    use std::io::Cursor;
+    use futures::SinkExt;
+    use futures::stream::iter_ok;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let (sender, mut task) = box_sender(writer, 1);
+
+    let messages_a: Vec<Vec<u8>> = (0..10).map(|i| vec![i; 5000]).collect();
+    let messages_b: Vec<Vec<u8>> = (100..110).map(|i| vec![i; 5000]).collect();
+    let a = sender.clone().send_all(iter_ok::<_, io::Error>(messages_a));
+    let b = sender.clone().send_all(iter_ok::<_, io::Error>(messages_b));
+    let closer = sender.clone();
+    let producers = a.join(b).and_then(move |_| closer.close());
+    block_on((&mut task).join(producers)).unwrap();
+
+    let err = block_on(sender.send(vec![1, 2, 3])).map(|_| ()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    assert_eq!(err.to_string(), SENDER_CLOSED);
+
+    let writer = task.into_inner();
+    assert_eq!(writer.stats().written.packets, 40);
+    let cypher = writer.into_inner().into_inner();
+
+    let reader = BoxReader::new(Cursor::new(cypher), key, nonce);
+    let (_, plain) = block_on(reader.read_to_end(Vec::new())).unwrap();
+    assert_eq!(plain.len(), 20 * 5000);
+    for message in plain.chunks(5000) {
+        assert!(message.iter().all(|byte| *byte == message[0]));
+    }
+
+    // Messages are rejected as soon as closing is requested, but those accepted before are
+    // still written.
+    let writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let (sender, mut task) = box_sender(writer, 4);
+    let sender = block_on(sender.send(vec![1; 10])).unwrap();
+    let close = sender.close();
+    let err = block_on(sender.clone().send(vec![2; 10]))
+        .map(|_| ())
+        .unwrap_err();
+    assert_eq!(err.to_string(), SENDER_CLOSED);
+    block_on((&mut task).join(close)).unwrap();
+    assert_eq!(task.get_ref().stats().written.packets, 1);
+}
+
+#[cfg(feature = "sender")]
+#[test]
+// Messages whose length does not fit into a u16 are split into packets like any other.
+fn box_sender_large_message() {
+    use std::io::Cursor;
+    use futures::SinkExt;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let (sender, mut task) = box_sender(writer, 1);
+    let message: Vec<u8> = (0..65536).map(|i| i as u8).collect();
+    let closer = sender.clone();
+    let producer = sender.send(message.clone()).and_then(move |_| closer.close());
+    block_on((&mut task).join(producer)).unwrap();
+
+    let writer = task.into_inner();
+    assert_eq!(writer.stats().written.packets, 16);
+    let cypher = writer.into_inner().into_inner();
+    let reader = BoxReader::new(Cursor::new(cypher), key.clone(), nonce.clone());
+    let (_, plain) = block_on(reader.read_to_end(Vec::new())).unwrap();
+    assert_eq!(plain, message);
+
+    // The same holds for writing the whole message to a BoxWriter at once.
+    let writer = BoxWriter::new(Cursor::new(Vec::new()), key, nonce);
+    let (writer, _) = block_on(writer.write_all(message)).unwrap();
+    assert_eq!(writer.stats().written.packets, 16);
+}
+
+#[cfg(feature = "packets")]
+#[test]
+// Every item of a packet sink becomes one packet, which a packet stream yields as one item.
+fn box_packets_roundtrip() {
+    use std::io::Cursor;
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use futures::stream::iter_ok;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let items: Vec<Bytes> = vec![vec![1], vec![2; 4096], vec![3; 100]]
+        .into_iter()
+        .map(Bytes::from)
+        .collect();
+    let sink = BoxPacketSink::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let (sink, _) = block_on(sink.send_all(iter_ok::<_, io::Error>(items.clone()))).unwrap();
+    let sink = block_on(sink.close()).unwrap();
+    assert_eq!(sink.stats().written.packets, 3);
+    let cypher = sink.into_inner().into_inner();
+
+    let stream = BoxPacketStream::new(Cursor::new(cypher), key.clone(), nonce.clone());
+    let received = block_on(stream.collect()).unwrap();
+    assert_eq!(received, items);
+
+    // Packets written by a BoxWriter keep their boundaries as well.
+    let writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let (writer, _) = block_on(writer.write_all(vec![4; 5000])).unwrap();
+    let writer = block_on(writer.close()).unwrap();
+    let cypher = writer.into_inner().into_inner();
+    let stream = BoxPacketStream::new(Cursor::new(cypher), key.clone(), nonce.clone());
+    let received = block_on(stream.collect()).unwrap();
+    assert_eq!(received, vec![Bytes::from(vec![4; 4096]), Bytes::from(vec![4; 904])]);
+
+    let sink = BoxPacketSink::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let err = block_on(sink.send(Bytes::from(vec![5; 4097]))).map(|_| ()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(err.to_string(), MESSAGE_TOO_LONG);
+
+    let sink = BoxPacketSink::new(Cursor::new(Vec::new()), key, nonce);
+    let err = block_on(sink.send(Bytes::new())).map(|_| ()).unwrap_err();
+    assert_eq!(err.to_string(), EMPTY_MESSAGE);
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+// The test vectors listed in the README.
+fn duplex_keys_from_shs_secrets() {
+    use sodiumoxide::crypto::{auth, box_, sign};
+
+    let network_key =
+        auth::Key::from_slice(&from_hex("d4a1cb88a66f02f8db635ce26441cc5dac1b08420ceaac230839b755845a9ffb"))
+            .unwrap();
+    let mut shared_secret = [0u8; 32];
+    for (i, byte) in shared_secret.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    let keys = DuplexKeys::from_shs_secrets(&network_key,
+                                            &shared_secret,
+                                            &sign::PublicKey([0x11; 32]),
+                                            &box_::PublicKey([0x22; 32]),
+                                            &sign::PublicKey([0x33; 32]),
+                                            &box_::PublicKey([0x44; 32]));
+
+    assert_eq!(keys.encryption_key.0.to_vec(),
+               from_hex("212f11ded5555af1e93d49da492783064d84946c64512d4fcd6dd5e27da16d84"));
+    assert_eq!(keys.decryption_key.0.to_vec(),
+               from_hex("79969b8b2de32c114d0cd96c94f17e983e0acaaa1cc289eb7ca8d4002fbcf35b"));
+    assert_eq!(keys.encryption_nonce.0.to_vec(),
+               from_hex("4813264228cd9cf9fa8eb834af5a9a80dc4d8167c541202a"));
+    assert_eq!(keys.decryption_nonce.0.to_vec(),
+               from_hex("6f4bdba02b3bd7c59e255e664cd26c6fa95b1bdd3ecc2455"));
+}
+
+// The key, nonce and plaintext of the golden files in `test-data`.
+fn golden_secrets() -> (sodiumoxide::crypto::secretbox::Key,
+                        sodiumoxide::crypto::secretbox::Nonce,
+                        Vec<u8>) {
+    let mut key = [0u8; 32];
+    let mut nonce = [0u8; 24];
+    for i in 0..32 {
+        key[i] = i as u8;
+    }
+    for i in 0..24 {
+        nonce[i] = 100 + i as u8;
+    }
+    (sodiumoxide::crypto::secretbox::Key(key),
+     sodiumoxide::crypto::secretbox::Nonce(nonce),
+     (0..5000).map(|i| i as u8).collect())
+}
+
+#[test]
+fn file_golden() {
+    use std::io::{Read, Write};
+    use file::*;
+
+    let golden = include_bytes!("../test-data/file-v1.box");
+    let (key, nonce, plain) = golden_secrets();
+
+    let mut reader = BoxFileReader::open(&golden[..], |key_id| {
+        assert_eq!(key_id, b"golden");
+        Some(key.clone())
+    })
+            .unwrap();
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, plain);
+
+    let mut writer = BoxFileWriter::with_nonce(Vec::new(), b"golden", key.clone(), nonce)
+        .unwrap();
+    writer.write_all(&plain).unwrap();
+    writer.close().unwrap();
+    assert_eq!(&writer.into_inner()[..], &golden[..]);
+}
+
+#[test]
+fn file_golden_async() {
+    use file::*;
+
+    let golden = include_bytes!("../test-data/file-v1.box");
+    let (key, _, plain) = golden_secrets();
+
+    let reader = AsyncBoxFileReader::open(&golden[..], |_| Some(key));
+    let (_, read) = block_on(reader.read_to_end(Vec::new())).unwrap();
+    assert_eq!(read, plain);
+}
+
+#[test]
+// Once the key lookup failed, every read fails the same way.
+fn file_unknown_key_async() {
+    use file::*;
+
+    let golden = include_bytes!("../test-data/file-v1.box");
+
+    let mut reader = AsyncBoxFileReader::open(&golden[..], |_| None);
+    for _ in 0..2 {
+        let err = block_on((&mut reader).read(vec![0; 16]))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.to_string(), UNKNOWN_KEY);
+    }
+}
+
+#[test]
+fn file_unsupported_version() {
+    use file::*;
+
+    let v2 = include_bytes!("../test-data/file-v2.box");
+    let (key, _, _) = golden_secrets();
+
+    assert_eq!(BoxFileReader::open(&v2[..], |_| Some(key))
+                   .map(|_| ())
+                   .unwrap_err()
+                   .to_string(),
+               UNSUPPORTED_VERSION);
+}
+
+#[test]
+// The vector corpus is reproduced by the crypto primitives, a BoxWriter and a BoxReader.
+fn vector_corpus() {
+    use vectors::*;
+
+    let corpus = corpus();
+    assert_eq!(corpus.len(), 23);
+
+    for vector in corpus.iter() {
+        let fail = |mismatch: Mismatch| panic!("{}", mismatch);
+        replay_crypto(vector).unwrap_or_else(&fail);
+        block_on(replay_box_writer(vector)).unwrap_or_else(&fail);
+        block_on(replay_box_reader(vector)).unwrap_or_else(&fail);
+    }
+
+    let mut wrong = corpus[5].clone();
+    wrong.plaintext[0] ^= 1;
+    assert_eq!(block_on(replay_box_reader(&wrong)).unwrap_err().replayed_by,
+               "BoxReader");
+    wrong.writes[1] = b"!".to_vec();
+    assert!(replay_crypto(&wrong).is_err());
+    assert!(block_on(replay_box_writer(&wrong)).is_err());
+
+    assert_eq!(parse("{\"vectors\": [{}]}").unwrap_err().to_string(),
+               INVALID_VECTOR);
+    assert_eq!(parse("[").unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn seekable_reader() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+
+    let mut writer = SyncBoxWriter::fixed_size(Vec::new(), key.clone(), nonce.clone());
+    writer.write_all(&data[..5]).unwrap();
+    writer.flush().unwrap();
+    writer.write_all(&data[5..]).unwrap();
+    writer.close().unwrap();
+    let mut cypher = writer.into_inner();
+
+    // Corrupt the first packet, which is never read below.
+    cypher[40] ^= 1;
+
+    let mut reader = SeekableBoxReader::new(Cursor::new(cypher.clone()), key.clone(), nonce.clone())
+        .unwrap();
+    assert_eq!(reader.plain_len(), 10000);
+
+    let mut buf = [0u8; 100];
+    reader.seek(SeekFrom::Start(5000)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &data[5000..5100]);
+
+    reader.seek(SeekFrom::End(-10)).unwrap();
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail).unwrap();
+    assert_eq!(&tail[..], &data[9990..]);
+
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    assert_eq!(reader.read(&mut buf).unwrap_err().to_string(),
+               UNAUTHENTICATED_PACKET);
+
+    // Without the final header, the stream does not authenticate its end.
+    let len = cypher.len();
+    cypher.truncate(len - 34);
+    let mut reader = SeekableBoxReader::new(Cursor::new(cypher), key, nonce).unwrap();
+    reader.seek(SeekFrom::End(0)).unwrap();
+    assert!(reader.read(&mut buf).is_err());
+}
+
+#[test]
+fn verify_stream() {
+    use std::io::Cursor;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    let cypher = seal_all(&data, &key, &nonce);
+
+    let expected = Verified {
+        packets: 3,
+        bytes: 10000,
+    };
+    assert_eq!(verify(&cypher[..], &key, &nonce).unwrap(), expected);
+    assert_eq!(block_on(verify_async(Cursor::new(cypher.clone()), key.clone(), nonce.clone()))
+                   .unwrap(),
+               expected);
+
+    let mut tampered = cypher.clone();
+    tampered[5000] ^= 1;
+    let err = verify(&tampered[..], &key, &nonce).unwrap_err();
+    assert_eq!(err.offset, 34 + 4096);
+    assert_eq!(err.verified.packets, 1);
+    assert_eq!(err.error.to_string(), UNAUTHENTICATED_PACKET);
+
+    let err = block_on(verify_async(Cursor::new(&cypher[..9000]), key.clone(), nonce.clone()))
+        .unwrap_err();
+    assert_eq!(err.offset, 2 * (34 + 4096));
+    assert_eq!(err.error.to_string(), UNAUTHENTICATED_EOF);
+
+    let mut trailing = cypher.clone();
+    trailing.push(0);
+    let err = verify(&trailing[..], &key, &nonce).unwrap_err();
+    assert_eq!(err.offset, cypher.len() as u64);
+    assert_eq!(err.error.to_string(), TRAILING_DATA);
+}
+
+#[test]
+fn inspect_stream() {
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    let mut cypher = seal_all(&data, &key, &nonce);
+
+    let parts = inspect(&cypher, &key, &nonce);
+    assert_eq!(parts.len(), 3);
+    match parts[1] {
+        Inspected::Packet {
+            offset,
+            nonce: ref packet_nonce,
+            ref body,
+            ..
+        } => {
+            assert_eq!(offset, 34 + 4096);
+            assert_eq!(packet_nonce.0, crypto::packet_nonce(&nonce.0, 1));
+            assert_eq!(&body[..], &data[4096..]);
+        }
+        ref other => panic!("unexpected {:?}", other),
+    }
+    match parts[2] {
+        Inspected::FinalHeader { offset, .. } => assert_eq!(offset as usize, cypher.len() - 34),
+        ref other => panic!("unexpected {:?}", other),
+    }
+
+    cypher[34 + 4096 + 40] ^= 1;
+    cypher.push(0);
+    let parts = inspect(&cypher, &key, &nonce);
+    assert_eq!(parts.len(), 2);
+    match parts[1] {
+        Inspected::Failure {
+            offset,
+            length,
+            reason,
+            ..
+        } => {
+            assert_eq!(offset, 34 + 4096 + 34);
+            assert_eq!(length, Some(5000 - 4096));
+            assert_eq!(reason, UNAUTHENTICATED_PACKET);
+        }
+        ref other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn duplex_keys_from_pre_shared_key() {
+    use sodiumoxide::crypto::secretbox;
+
+    let psk = secretbox::gen_key();
+    let client_nonce = secretbox::gen_nonce();
+    let server_nonce = secretbox::gen_nonce();
+
+    let client = DuplexKeys::from_pre_shared_key(&psk, &client_nonce, &server_nonce, true);
+    let server = DuplexKeys::from_pre_shared_key(&psk, &server_nonce, &client_nonce, false);
+
+    assert_eq!(client.encryption_key, server.decryption_key);
+    assert_eq!(client.decryption_key, server.encryption_key);
+    assert_eq!(client.encryption_nonce, server.decryption_nonce);
+    assert_eq!(client.decryption_nonce, server.encryption_nonce);
+    assert!(client.encryption_key != client.decryption_key);
+}
+
+#[cfg(feature = "tunnel")]
+#[test]
+fn tunnel_loopback() {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use tunnel::*;
+
+    // An echo service that closes its connections once the client has finished sending.
+    let service = TcpListener::bind("127.0.0.1:0").unwrap();
+    let service_addr = service.local_addr().unwrap();
+    thread::spawn(move || for connection in service.incoming() {
+                      let mut connection = connection.unwrap();
+                      let mut data = Vec::new();
+                      connection.read_to_end(&mut data).unwrap();
+                      connection.write_all(&data).unwrap();
+                  });
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_config = Config {
+        role: Role::Server,
+        key: key.clone(),
+        connect: service_addr,
+        max_connections: 4,
+        handshake_timeout: Duration::from_secs(10),
+    };
+    let client = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client_config = Config {
+        role: Role::Client,
+        key,
+        connect: server.local_addr().unwrap(),
+        max_connections: 4,
+        handshake_timeout: Duration::from_secs(10),
+    };
+    let client_addr = client.local_addr().unwrap();
+    thread::spawn(move || run(server, server_config));
+    thread::spawn(move || run(client, client_config));
+
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    let mut connection = TcpStream::connect(client_addr).unwrap();
+    connection.write_all(&data).unwrap();
+    connection.shutdown(Shutdown::Write).unwrap();
+
+    let mut echoed = Vec::new();
+    connection.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, data);
+}
+
+#[cfg(feature = "tunnel")]
+#[test]
+fn tunnel_server_speaks_first() {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use tunnel::*;
+
+    // A service that greets its connections before reading anything, like e.g. SMTP or SSH.
+    let service = TcpListener::bind("127.0.0.1:0").unwrap();
+    let service_addr = service.local_addr().unwrap();
+    thread::spawn(move || for connection in service.incoming() {
+                      let mut connection = connection.unwrap();
+                      connection.write_all(b"220 ready\r\n").unwrap();
+                      let mut data = Vec::new();
+                      connection.read_to_end(&mut data).unwrap();
+                      connection.write_all(&data).unwrap();
+                  });
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_config = Config {
+        role: Role::Server,
+        key: key.clone(),
+        connect: service_addr,
+        max_connections: 4,
+        handshake_timeout: Duration::from_secs(10),
+    };
+    let client = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client_config = Config {
+        role: Role::Client,
+        key,
+        connect: server.local_addr().unwrap(),
+        max_connections: 4,
+        handshake_timeout: Duration::from_secs(10),
+    };
+    let client_addr = client.local_addr().unwrap();
+    thread::spawn(move || run(server, server_config));
+    thread::spawn(move || run(client, client_config));
+
+    // The greeting arrives without the client sending anything first.
+    let mut connection = TcpStream::connect(client_addr).unwrap();
+    let mut greeting = [0u8; 11];
+    connection.read_exact(&mut greeting).unwrap();
+    assert_eq!(&greeting, b"220 ready\r\n");
+
+    connection.write_all(b"hello").unwrap();
+    connection.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    connection.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello");
+}
+
+#[cfg(feature = "tunnel")]
+#[test]
+fn tunnel_connection_limit() {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use tunnel::*;
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let config = Config {
+        role: Role::Server,
+        key: sodiumoxide::crypto::secretbox::gen_key(),
+        connect: "127.0.0.1:1".parse().unwrap(),
+        max_connections: 1,
+        handshake_timeout: Duration::from_secs(2),
+    };
+    thread::spawn(move || run(server, config));
+
+    // The first connection takes the only slot and receives the nonce of the server.
+    let mut first = TcpStream::connect(server_addr).unwrap();
+    let mut nonce = [0u8; 24];
+    first.read_exact(&mut nonce).unwrap();
+
+    // The second connection is closed right away.
+    let mut refused = TcpStream::connect(server_addr).unwrap();
+    let mut data = Vec::new();
+    let _ = refused.read_to_end(&mut data);
+    assert!(data.is_empty());
+
+    // The server gives up on the first connection after the handshake timeout, which frees the
+    // slot again.
+    first.read_to_end(&mut data).unwrap();
+    assert!(data.is_empty());
+
+    let mut served = false;
+    for _ in 0..100 {
+        let mut next = TcpStream::connect(server_addr).unwrap();
+        if next.read_exact(&mut nonce).is_ok() {
+            served = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(served);
+}
+
+#[cfg(feature = "tunnel")]
+#[test]
+fn tunnel_wrong_key() {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use tunnel::*;
+
+    // A service that reports every connection it receives.
+    let service = TcpListener::bind("127.0.0.1:0").unwrap();
+    let service_addr = service.local_addr().unwrap();
+    let (connected, connections) = channel();
+    thread::spawn(move || for connection in service.incoming() {
+                      let _ = connected.send(connection.is_ok());
+                  });
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_config = Config {
+        role: Role::Server,
+        key: sodiumoxide::crypto::secretbox::gen_key(),
+        connect: service_addr,
+        max_connections: 4,
+        handshake_timeout: Duration::from_secs(10),
+    };
+    let client = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client_config = Config {
+        role: Role::Client,
+        key: sodiumoxide::crypto::secretbox::gen_key(),
+        connect: server.local_addr().unwrap(),
+        max_connections: 4,
+        handshake_timeout: Duration::from_secs(10),
+    };
+    let client_addr = client.local_addr().unwrap();
+    thread::spawn(move || run(server, server_config));
+    thread::spawn(move || run(client, client_config));
+
+    let mut connection = TcpStream::connect(client_addr).unwrap();
+    connection.write_all(b"hello").unwrap();
+    connection.shutdown(Shutdown::Write).unwrap();
+
+    // The proofs of the handshake fail, so both connections are aborted without ever reaching the
+    // service.
+    let mut data = Vec::new();
+    let _ = connection.read_to_end(&mut data);
+    assert!(data.is_empty());
+    assert!(connections.try_recv().is_err());
+}
+
+#[test]
+fn recovering_reader() {
+    use std::io::Read;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    let cypher = seal_all(&data, &key, &nonce);
+
+    let recover = |cypher: &[u8]| {
+        let mut reader = RecoveringBoxReader::new(cypher, key.clone(), nonce.clone());
+        let mut plain = Vec::new();
+        reader.read_to_end(&mut plain).unwrap();
+        (plain, *reader.outcome())
+    };
+
+    let (plain, outcome) = recover(&cypher);
+    assert_eq!(plain, data);
+    assert_eq!(outcome,
+               RecoveryOutcome {
+                   packets: 3,
+                   bytes: 10000,
+                   final_header: true,
+                   damage: None,
+               });
+
+    // Truncated within the third packet.
+    let (plain, outcome) = recover(&cypher[..9000]);
+    assert_eq!(&plain[..], &data[..8192]);
+    assert_eq!(outcome,
+               RecoveryOutcome {
+                   packets: 2,
+                   bytes: 8192,
+                   final_header: false,
+                   damage: Some(Damage {
+                                    offset: 2 * (34 + 4096),
+                                    reason: UNAUTHENTICATED_EOF,
+                                }),
+               });
+
+    // Corrupted within the second packet.
+    let mut tampered = cypher.clone();
+    tampered[5000] ^= 1;
+    let (plain, outcome) = recover(&tampered);
+    assert_eq!(&plain[..], &data[..4096]);
+    assert_eq!(outcome.damage,
+               Some(Damage {
+                        offset: 34 + 4096,
+                        reason: UNAUTHENTICATED_PACKET,
+                    }));
+}
+
+#[test]
+fn reencrypt_stream() {
+    let old_key = sodiumoxide::crypto::secretbox::gen_key();
+    let old_nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let new_key = sodiumoxide::crypto::secretbox::gen_key();
+    let new_nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    let cypher = seal_all(&data, &old_key, &old_nonce);
+
+    let mut out = Vec::new();
+    assert_eq!(reencrypt(&cypher[..], &old_key, &old_nonce, &mut out, &new_key, &new_nonce)
+                   .unwrap(),
+               10000);
+    assert_eq!(out, seal_all(&data, &new_key, &new_nonce));
+
+    // The output of a failed re-encryption is not a complete box stream.
+    let mut out = Vec::new();
+    assert_eq!(reencrypt(&cypher[..cypher.len() - 1],
+                         &old_key,
+                         &old_nonce,
+                         &mut out,
+                         &new_key,
+                         &new_nonce)
+                       .unwrap_err()
+                       .to_string(),
+               UNAUTHENTICATED_EOF);
+    assert_eq!(open_all(&out, &new_key, &new_nonce)
+                   .unwrap_err()
+                   .to_string(),
+               UNAUTHENTICATED_EOF);
+}
+
+#[test]
+fn file_reencrypt() {
+    use std::fs;
+    use std::io::Read;
+    use file::*;
+
+    let (old_key, _, data) = golden_secrets();
+    let new_key = sodiumoxide::crypto::secretbox::gen_key();
+    let path = std::env::temp_dir().join(format!("box-stream-reencrypt-{}", std::process::id()));
+    fs::copy("test-data/file-v1.box", &path).unwrap();
+
+    reencrypt_file(&path, |_| Some(old_key.clone()), b"new", new_key.clone()).unwrap();
+
+    let mut reader = BoxFileReader::open(fs::File::open(&path).unwrap(),
+                                         |key_id| if key_id == b"new" {
+                                             Some(new_key.clone())
+                                         } else {
+                                             None
+                                         })
+            .unwrap();
+    let mut plain = Vec::new();
+    reader.read_to_end(&mut plain).unwrap();
+    assert_eq!(plain, data);
+
+    // A damaged file is left as it is.
+    let wrong_key = sodiumoxide::crypto::secretbox::gen_key();
+    let before = fs::read(&path).unwrap();
+    assert_eq!(reencrypt_file(&path, |_| Some(wrong_key), b"other", new_key.clone())
+                   .unwrap_err()
+                   .to_string(),
+               UNAUTHENTICATED_HEADER);
+    assert_eq!(fs::read(&path).unwrap(), before);
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    assert!(!std::path::Path::new(&tmp_path).exists());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn file_append() {
+    use std::fs::{self, OpenOptions};
+    use std::io::{Read, Write};
+    use file::*;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let keys = |_: &[u8]| Some(key.clone());
+    let path = std::env::temp_dir().join(format!("box-stream-append-{}", std::process::id()));
+    let open = || {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap()
+    };
+    let read_all = || {
+        let mut plain = Vec::new();
+        let mut reader = BoxFileReader::open(open(), &keys).unwrap();
+        reader.read_to_end(&mut plain).map(|_| plain)
+    };
+
+    let mut writer = BoxFileWriter::create(open(), b"log", key.clone()).unwrap();
+    writer.write_all(b"hello ").unwrap();
+    writer.close().unwrap();
+
+    let mut writer = BoxFileWriter::append(open(), &keys).unwrap();
+    writer.write_all(b"world").unwrap();
+    writer.close().unwrap();
+    assert_eq!(read_all().unwrap(), b"hello world");
+
+    // Interrupt an append within a packet.
+    let mut writer = BoxFileWriter::append(open(), &keys).unwrap();
+    writer.write_all(b"!!").unwrap();
+    writer.flush().unwrap();
+    let file = writer.into_inner();
+    let len = file.metadata().unwrap().len();
+    file.set_len(len - 1).unwrap();
+
+    assert_eq!(read_all().unwrap_err().to_string(), UNAUTHENTICATED_EOF);
+    assert_eq!(BoxFileWriter::append(open(), &keys).err().unwrap().to_string(),
+               UNAUTHENTICATED_EOF);
+
+    let (mut writer, removed) = BoxFileWriter::recover(open(), &keys).unwrap();
+    assert_eq!(removed, 34 + 2 - 1);
+    writer.write_all(b"?").unwrap();
+    writer.close().unwrap();
+    assert_eq!(read_all().unwrap(), b"hello world?");
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+// A box-stream file can be continued by a BoxWriter.
+fn file_append_async() {
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+    use futures_core::Poll;
+    use futures_core::Async::Ready;
+    use futures_core::task::Context;
+    use futures_io::AsyncWrite;
+    use file::*;
+
+    // Writes to a file, blocking.
+    struct BlockingFile(File);
+
+    impl AsyncWrite for BlockingFile {
+        fn poll_write(&mut self, _: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+            self.0.write(buf).map(Ready)
+        }
+
+        fn poll_flush(&mut self, _: &mut Context) -> Poll<(), io::Error> {
+            self.0.flush().map(Ready)
+        }
+
+        fn poll_close(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+            self.poll_flush(cx)
+        }
+    }
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let keys = |_: &[u8]| Some(key.clone());
+    let path = std::env::temp_dir().join(format!("box-stream-append-async-{}",
+                                                 std::process::id()));
+    let open = || {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap()
+    };
+
+    let mut writer = BoxFileWriter::create(open(), b"log", key.clone()).unwrap();
+    writer.write_all(b"hello ").unwrap();
+    writer.close().unwrap();
+
+    let writer = append_async(open(), &keys, BlockingFile).unwrap();
+    let (writer, _) = block_on(writer.write_all(b"world".to_vec())).unwrap();
+    let writer = block_on(writer.close()).unwrap();
+    drop(writer);
+
+    // Cut off the final header.
+    let len = open().metadata().unwrap().len();
+    open().set_len(len - 1).unwrap();
+    let (writer, removed) = recover_async(open(), &keys, BlockingFile).unwrap();
+    assert_eq!(removed, 33);
+    let (writer, _) = block_on(writer.write_all(b"!".to_vec())).unwrap();
+    block_on(writer.close()).unwrap();
+
+    let mut plain = Vec::new();
+    BoxFileReader::open(open(), &keys)
+        .unwrap()
+        .read_to_end(&mut plain)
+        .unwrap();
+    assert_eq!(plain, b"hello world!");
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+// The delays of a ThreadTimer elapse in order, and can be dropped before they elapse.
+fn thread_timer() {
+    use std::time::{Duration, Instant};
+    use futures::future::poll_fn;
+    use futures_core::Async;
+
+    let start = Instant::now();
+    let mut cancelled = ThreadTimer.delay(start + Duration::from_secs(3600));
+    let mut late = ThreadTimer.delay(start + Duration::from_millis(40));
+    let mut early = ThreadTimer.delay(start + Duration::from_millis(20));
+
+    block_on(poll_fn(|cx| {
+                         assert!(cancelled.poll_elapsed(cx).unwrap().is_pending());
+                         Ok::<_, io::Error>(Async::Ready(()))
+                     }))
+            .unwrap();
+    drop(cancelled);
+
+    block_on(poll_fn(|cx| early.poll_elapsed(cx))).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    block_on(poll_fn(|cx| late.poll_elapsed(cx))).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(40));
+}
+
+#[test]
+// Deadlines error with distinct timeout errors, and poison the stream.
+fn timeouts() {
+    use std::time::Duration;
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let timeouts = Timeouts {
+        idle_read: Some(Duration::from_millis(20)),
+        packet: Some(Duration::from_millis(20)),
+        close: Some(Duration::from_millis(20)),
+    };
+
+    // Nothing arrives.
+    let (_a, b) = duplex_pair(64);
+    let mut reader = BoxReader::new(b, key.clone(), nonce.clone());
+    reader.set_timeouts(timeouts, ThreadTimer);
+    let err = read_error(&mut reader);
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_eq!(err.to_string(), IDLE_TIMEOUT);
+
+    // The peer stalls in the middle of a packet. The reader stays poisoned after it continues.
+    let (mut a, b) = duplex_pair(256);
+    let cypher = seal_all(&[1; 100], &key, &nonce);
+    block_on((&mut a).write_all(cypher[..50].to_vec())).unwrap();
+    let mut reader = BoxReader::new(b, key.clone(), nonce.clone());
+    reader.set_timeouts(timeouts, ThreadTimer);
+    assert_eq!(read_error(&mut reader).to_string(), PACKET_TIMEOUT);
+    block_on((&mut a).write_all(cypher[50..].to_vec())).unwrap();
+    assert_eq!(read_error(&mut reader).to_string(), PACKET_TIMEOUT);
+
+    // Nobody reads, so writing out the first packet stalls.
+    let (a, _b) = duplex_pair(64);
+    let mut writer = BoxWriter::new(a, key.clone(), nonce.clone());
+    writer.set_timeouts(timeouts, ThreadTimer);
+    block_on((&mut writer).write_all(vec![2; 100])).unwrap();
+    let err = block_on((&mut writer).write_all(vec![3; 10]))
+        .map(|_| ())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_eq!(err.to_string(), PACKET_TIMEOUT);
+
+    // The packet fits, but the final header does not.
+    let (a, _b) = duplex_pair(64);
+    let mut writer = BoxWriter::new(a, key, nonce);
+    writer.set_timeouts(timeouts, ThreadTimer);
+    block_on((&mut writer).write_all(vec![2; 10])).unwrap();
+    let err = block_on((&mut writer).close()).map(|_| ()).unwrap_err();
+    assert_eq!(err.to_string(), CLOSE_TIMEOUT);
+    assert_eq!(block_on((&mut writer).flush())
+                   .map(|_| ())
+                   .unwrap_err()
+                   .to_string(),
+               CLOSE_TIMEOUT);
+}
+
+#[test]
+// Rate limiters delay whole packets, are shared between their clones, and can be adjusted while
+// streams wait for them.
+fn rate_limit() {
+    use std::io::Cursor;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let data: Vec<u8> = (0..3 * 4096).map(|i| i as u8).collect();
+
+    // The first packet uses up the burst, the other two take 8192 / 40000 seconds.
+    let limiter = RateLimiter::new(40_000, 4096, ThreadTimer);
+    let mut writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    writer.set_rate_limiter(limiter.clone(), Counting::Plaintext);
+    let start = Instant::now();
+    block_on((&mut writer).write_all(data.clone())).unwrap();
+    block_on((&mut writer).close()).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    let cypher = writer.into_inner().into_inner();
+    assert_eq!(cypher, seal_all(&data, &key, &nonce));
+
+    // A second writer sharing the limiter has to wait for the first one.
+    let mut other = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    other.set_rate_limiter(limiter, Counting::Plaintext);
+    let start = Instant::now();
+    block_on((&mut other).write_all(data[..4096].to_vec())).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // Counting the wire bytes, reading takes (3 * 34 + 2 * 4096 + 34) / 40000 seconds.
+    let limiter = RateLimiter::new(40_000, 4096, ThreadTimer);
+    let mut reader = BoxReader::new(Cursor::new(cypher.clone()), key.clone(), nonce.clone());
+    reader.set_rate_limiter(limiter, Counting::Wire);
+    let start = Instant::now();
+    let (_, plain) = block_on((&mut reader).read_to_end(Vec::new())).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert_eq!(plain, data);
+
+    // Raising the rate wakes a writer that would otherwise wait for hours.
+    let limiter = RateLimiter::new(1, 4096, ThreadTimer);
+    let mut writer = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    writer.set_rate_limiter(limiter.clone(), Counting::Plaintext);
+    let raise = thread::spawn(move || {
+                                  thread::sleep(Duration::from_millis(50));
+                                  limiter.set_rate(1_000_000, 4096);
+                                  limiter
+                              });
+    let start = Instant::now();
+    block_on((&mut writer).write_all(data.clone())).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(raise.join().unwrap().rate(), (1_000_000, 4096));
+}
+
+// Reads `reader` to the end, returning the error it fails with.
+fn read_error<R: AsyncRead>(reader: &mut R) -> io::Error {
+    block_on(reader.read_to_end(Vec::new()))
+        .map(|_| ())
+        .unwrap_err()
+}
+
+#[test]
+// A reader propagates io errors, and continues where it stopped afterwards.
+fn reader_io_error() {
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let cypher = seal_all(&[0, 1, 2, 3], &key, &nonce);
+
+    let r = FaultyReader::new(Cursor::new(cypher),
+                              vec![Fault::Err(ErrorKind::NotFound),
+                                   Fault::Limited(3),
+                                   Fault::Interrupted]);
+    let mut b = BoxReader::new(r, key, nonce);
+    b.get_mut().fail_at(20, ErrorKind::ConnectionReset);
+
+    assert_eq!(read_error(&mut b).kind(), ErrorKind::NotFound);
+    assert_eq!(read_error(&mut b).kind(), ErrorKind::Interrupted);
+    assert_eq!(read_error(&mut b).kind(), ErrorKind::ConnectionReset);
+    assert_eq!(b.get_ref().position(), 20);
+
+    let (_, data) = block_on((&mut b).read_to_end(Vec::new())).unwrap();
+    assert_eq!(data, vec![0, 1, 2, 3]);
+}
+
+#[test]
+// A reader whose inner reader reads 0 bytes errors with UnexpectedEof.
+fn reader_read0() {
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let cypher = seal_all(&[0; 5000], &key, &nonce);
+
+    let r = FaultyReader::new(Cursor::new(cypher.clone()), vec![Fault::Limited(0)]);
+    let mut b = BoxReader::new(r, key.clone(), nonce.clone());
+    let err = read_error(&mut b);
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(err.to_string(), UNAUTHENTICATED_EOF);
+
+    // Truncation in the middle of a header, of a packet, and right before the final header.
+    for &offset in [10, 100, cypher.len() - 34].iter() {
+        let mut r = FaultyReader::new(Cursor::new(cypher.clone()), vec![]);
+        r.truncate_at(offset as u64);
+        let mut b = BoxReader::new(r, key.clone(), nonce.clone());
+        let err = read_error(&mut b);
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(err.to_string(), UNAUTHENTICATED_EOF);
+    }
+}
+
+#[test]
+// A reader that reads a final header signals it via read returning Ok(0).
+fn reader_final_header() {
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let b = BoxWriter::new(Cursor::new(Vec::new()), key.clone(), nonce.clone());
+    let (b, _) = block_on(b.write_all(vec![0, 1, 2, 3])).unwrap();
+    let b = block_on(b.close()).unwrap();
+    let data = b.into_inner().into_inner();
+
+    let r = FaultyReader::new(Cursor::new(data), vec![Fault::Limited(1); 50]);
+    let b = BoxReader::new(r, key, nonce);
+
+    let (b, buf, read) = block_on(b.read(vec![10, 20, 30, 40])).unwrap();
+    assert_eq!((buf, read), (vec![0, 1, 2, 3], 4));
+    let (_, _, read) = block_on(b.read(vec![10, 20, 30, 40])).unwrap();
+    assert_eq!(read, 0);
+}
+
+#[test]
+// A reader rejects flipped bits in headers and packets.
+fn reader_bit_flips() {
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let cypher = seal_all(&[42; 100], &key, &nonce);
+
+    for &(offset, bit, error) in
+        [(0, 0, UNAUTHENTICATED_HEADER),
+         (20, 7, UNAUTHENTICATED_HEADER),
+         (33, 3, UNAUTHENTICATED_HEADER),
+         (34, 0, UNAUTHENTICATED_PACKET),
+         (133, 5, UNAUTHENTICATED_PACKET),
+         (150, 1, UNAUTHENTICATED_HEADER)]
+                .iter() {
+        let mut r = FaultyReader::new(Cursor::new(cypher.clone()), vec![]);
+        r.flip_bit(offset, bit);
+        let mut b = BoxReader::new(r, key.clone(), nonce.clone());
+        let err = read_error(&mut b);
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), error);
+    }
+}
+
+#[test]
+// A writer propagates io errors, and continues where it stopped afterwards.
+fn writer_io_error() {
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let w = FaultyWriter::new(Cursor::new(Vec::new()),
+                              vec![Fault::Err(ErrorKind::NotFound),
+                                   Fault::Limited(3),
+                                   Fault::Err(ErrorKind::UnexpectedEof)]);
+    let mut b = BoxWriter::new(w, key.clone(), nonce.clone());
+    b.get_mut().fail_at(20, ErrorKind::ConnectionReset);
+
+    block_on((&mut b).write_all(vec![0, 1, 2, 3])).unwrap();
+    let flush = |b: &mut BoxWriter<_>| block_on(b.flush()).map(|_| ()).unwrap_err().kind();
+    assert_eq!(flush(&mut b), ErrorKind::NotFound);
+    assert_eq!(flush(&mut b), ErrorKind::UnexpectedEof);
+    assert_eq!(flush(&mut b), ErrorKind::ConnectionReset);
+    assert_eq!(b.get_ref().position(), 20);
+
+    block_on((&mut b).close()).unwrap();
+    let cypher = b.into_inner().into_inner().into_inner();
+    assert_eq!(open_all(&cypher, &key, &nonce).unwrap(), vec![0, 1, 2, 3]);
+}
+
+#[test]
+// A writer errors WriteZero if writing to the underlying writer during flushing returns Ok(0).
+fn writer_write0_flush() {
+    for schedule in vec![vec![Fault::Limited(0)], vec![Fault::Limited(2), Fault::Limited(0)]] {
+        let key = sodiumoxide::crypto::secretbox::gen_key();
+        let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+        let w = FaultyWriter::new(Cursor::new(Vec::new()), schedule);
+        let mut b = BoxWriter::new(w, key, nonce);
+
+        block_on((&mut b).write_all(vec![0, 1, 2, 3])).unwrap();
+        assert_eq!(block_on((&mut b).flush())
+                       .map(|_| ())
+                       .unwrap_err()
+                       .kind(),
+                   ErrorKind::WriteZero);
+    }
+}
+
+#[test]
+// A writer errors WriteZero if writing to the underlying writer during closing returns Ok(0).
+fn writer_write0_close() {
+    for schedule in vec![vec![Fault::Limited(0)], vec![Fault::Limited(2), Fault::Limited(0)]] {
+        let key = sodiumoxide::crypto::secretbox::gen_key();
+        let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+        let w = FaultyWriter::new(Cursor::new(Vec::new()), schedule);
+        let mut b = BoxWriter::new(w, key, nonce);
+
+        assert_eq!(block_on((&mut b).close())
+                       .map(|_| ())
+                       .unwrap_err()
+                       .kind(),
+                   ErrorKind::WriteZero);
+    }
+}
+
+#[test]
+// Bit flips and truncation on the way to the underlying writer are detected by the reader.
+fn writer_corruption() {
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+
+    let corrupt = |flip: Option<u64>, truncate: Option<u64>| {
+        let mut w = FaultyWriter::new(Cursor::new(Vec::new()), vec![Fault::Limited(7); 100]);
+        if let Some(offset) = flip {
+            w.flip_bit(offset, 4);
+        }
+        if let Some(offset) = truncate {
+            w.truncate_at(offset);
+        }
+
+        let b = BoxWriter::new(w, key.clone(), nonce.clone());
+        let (b, _) = block_on(b.write_all(vec![1; 200])).unwrap();
+        let b = block_on(b.close()).unwrap();
+        assert_eq!(b.get_ref().position(), 200 + 2 * 34);
+
+        let cypher = b.into_inner().into_inner().into_inner();
+        open_all(&cypher, &key, &nonce).unwrap_err()
+    };
+
+    assert_eq!(corrupt(Some(40), None).to_string(), UNAUTHENTICATED_PACKET);
+    assert_eq!(corrupt(Some(240), None).to_string(), UNAUTHENTICATED_HEADER);
+    assert_eq!(corrupt(None, Some(234)).to_string(), UNAUTHENTICATED_EOF);
+}
+
+#[test]
+// Data survives arbitrary scheduling and partial reads and writes on both sides.
+fn faulty_duplex_roundtrip() {
+    let key = sodiumoxide::crypto::secretbox::gen_key();
+    let nonce = sodiumoxide::crypto::secretbox::gen_nonce();
+    let data: Vec<u8> = (0..10000).map(|i| (i * 7) as u8).collect();
+
+    let schedule = |seed: usize| -> Vec<Fault> {
+        (0..30000)
+            .map(|i| match (i * seed) % 5 {
+                     0 => Fault::Pending,
+                     1 => Fault::Limited(1),
+                     2 => Fault::Limited(33),
+                     3 => Fault::Limited(4200),
+                     _ => Fault::Unlimited,
+                 })
+            .collect()
+    };
+
+    let (a, b) = duplex_pair(100);
+    let writer = BoxWriter::new(FaultyWriter::new(a, schedule(3)), key.clone(), nonce.clone());
+    let reader = BoxReader::new(FaultyReader::new(b, schedule(7)), key.clone(), nonce.clone());
+
+    let write_all = writer
+        .write_all(data.clone())
+        .and_then(|(writer, _)| writer.close());
+    let read_all = reader.read_to_end(Vec::new()).map(|(_, read)| read);
+
+    let (_, read) = block_on(write_all.join(read_all)).unwrap();
+    assert_eq!(read, data);
+
+    // Dropping the other end without a final header is an unauthenticated eof.
+    let (a, b) = duplex_pair(100);
+    let mut reader = BoxReader::new(b, key, nonce);
+    drop(a);
+    assert_eq!(read_error(&mut reader).to_string(), UNAUTHENTICATED_EOF);
+}
@@ -0,0 +1,432 @@
+//! Utilities for testing code that uses box streams: an in-memory duplex
+//! connection, and adapters that inject faults into the reads and writes of
+//! the wrapped reader or writer.
+//!
+//! ```ignore
+//! // Deliver the cyphertext one byte at a time, interrupting every other read.
+//! let faults = vec![Fault::Limited(1), Fault::Interrupted].into_iter().cycle().take(100).collect();
+//! let reader = BoxReader::new(FaultyReader::new(inner, faults), key, nonce);
+//! ```
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures_core::{Async, Poll};
+use futures_core::task::{Context, Waker};
+use futures_io::{Error, ErrorKind, AsyncRead, AsyncWrite};
+
+/// The error value of all errors injected by a `FaultyReader` or `FaultyWriter`.
+pub const INJECTED_FAULT: &'static str = "injected fault";
+
+/// A fault to inject into a single call to `poll_read` or `poll_write`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Return `Async::Pending` without calling the wrapped reader or writer.
+    /// The task is woken immediately.
+    Pending,
+    /// Pass at most this many bytes through. `Limited(0)` returns `Ok(0)`
+    /// without calling the wrapped reader or writer.
+    Limited(usize),
+    /// Error with `ErrorKind::Interrupted`.
+    Interrupted,
+    /// Error with the given kind.
+    Err(ErrorKind),
+    /// Behave like the wrapped reader or writer.
+    Unlimited,
+}
+
+// The faults of a `FaultyReader` or `FaultyWriter`, shared by both.
+struct Faults {
+    schedule: VecDeque<Fault>,
+    // Number of bytes passed through so far.
+    position: u64,
+    fail_at: Option<(u64, ErrorKind)>,
+    truncate_at: Option<u64>,
+    flips: Vec<(u64, u8)>,
+}
+
+impl Faults {
+    fn new(schedule: Vec<Fault>) -> Faults {
+        Faults {
+            schedule: schedule.into_iter().collect(),
+            position: 0,
+            fail_at: None,
+            truncate_at: None,
+            flips: Vec::new(),
+        }
+    }
+
+    // Consume the next scheduled fault, returning `Ok(limit)` with the maximum
+    // number of bytes to pass through if the call should proceed.
+    fn next(&mut self, cx: &mut Context, len: usize) -> Poll<usize, Error> {
+        let mut limit = len;
+
+        match self.schedule.pop_front().unwrap_or(Fault::Unlimited) {
+            Fault::Pending => {
+                cx.waker().wake();
+                return Ok(Async::Pending);
+            }
+            Fault::Limited(n) => limit = min(limit, n),
+            Fault::Interrupted => return Err(Error::new(ErrorKind::Interrupted, INJECTED_FAULT)),
+            Fault::Err(kind) => return Err(Error::new(kind, INJECTED_FAULT)),
+            Fault::Unlimited => {}
+        }
+
+        if let Some((offset, kind)) = self.fail_at {
+            if self.position < offset {
+                limit = min(limit as u64, offset - self.position) as usize;
+            } else if len > 0 {
+                self.fail_at = None;
+                return Err(Error::new(kind, INJECTED_FAULT));
+            }
+        }
+
+        Ok(Async::Ready(limit))
+    }
+
+    // The number of the next `len` bytes that lie before the truncation point.
+    fn untruncated(&self, len: usize) -> usize {
+        match self.truncate_at {
+            Some(offset) if offset > self.position => {
+                min(len as u64, offset - self.position) as usize
+            }
+            Some(_) => 0,
+            None => len,
+        }
+    }
+
+    // Flip the scheduled bits of `buf`, which holds the bytes at the current
+    // position, and advance the position past them.
+    fn pass(&mut self, buf: &mut [u8]) {
+        for &(offset, bit) in self.flips.iter() {
+            if offset >= self.position && offset < self.position + buf.len() as u64 {
+                buf[(offset - self.position) as usize] ^= 1 << bit;
+            }
+        }
+        self.position += buf.len() as u64;
+    }
+}
+
+/// Wraps a reader, injecting faults into its reads.
+///
+/// Each call to `poll_read` consumes one `Fault` of the schedule passed to
+/// `new`. Once the schedule is exhausted, reads are passed through unchanged,
+/// except for the errors, bit flips and truncation configured by `fail_at`,
+/// `flip_bit` and `truncate_at`.
+pub struct FaultyReader<R> {
+    inner: R,
+    faults: Faults,
+}
+
+impl<R> FaultyReader<R> {
+    /// Create a new reader, wrapping `inner` and injecting the faults of
+    /// `schedule` into successive reads.
+    pub fn new(inner: R, schedule: Vec<Fault>) -> FaultyReader<R> {
+        FaultyReader {
+            inner,
+            faults: Faults::new(schedule),
+        }
+    }
+
+    /// Error with `kind` once, on the first read that reaches `offset`. Reads
+    /// never return data past `offset` before the error has been emitted.
+    pub fn fail_at(&mut self, offset: u64, kind: ErrorKind) {
+        self.faults.fail_at = Some((offset, kind));
+    }
+
+    /// Flip the bit with index `bit` (0 being the least significant) of the
+    /// byte at `offset`.
+    pub fn flip_bit(&mut self, offset: u64, bit: u8) {
+        assert!(bit < 8, "a byte has 8 bits");
+        self.faults.flips.push((offset, bit));
+    }
+
+    /// Signal end of stream by returning `Ok(0)` once `offset` bytes have been
+    /// read, regardless of the remaining data of the wrapped reader.
+    pub fn truncate_at(&mut self, offset: u64) {
+        self.faults.truncate_at = Some(offset);
+    }
+
+    /// Returns the number of bytes read so far.
+    pub fn position(&self) -> u64 {
+        self.faults.position
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Unwraps this `FaultyReader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for FaultyReader<R> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, Error> {
+        let limit = try_ready!(self.faults.next(cx, buf.len()));
+        let limit = self.faults.untruncated(limit);
+        if limit == 0 {
+            return Ok(Async::Ready(0));
+        }
+
+        let read = try_ready!(self.inner.poll_read(cx, &mut buf[..limit]));
+        self.faults.pass(&mut buf[..read]);
+        Ok(Async::Ready(read))
+    }
+}
+
+impl<R: AsyncWrite> AsyncWrite for FaultyReader<R> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, Error> {
+        self.inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.inner.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.inner.poll_close(cx)
+    }
+}
+
+/// Wraps a writer, injecting faults into its writes.
+///
+/// Each call to `poll_write` consumes one `Fault` of the schedule passed to
+/// `new`. Once the schedule is exhausted, writes are passed through unchanged,
+/// except for the errors, bit flips and truncation configured by `fail_at`,
+/// `flip_bit` and `truncate_at`. Flushing and closing are never faulty.
+pub struct FaultyWriter<W> {
+    inner: W,
+    faults: Faults,
+}
+
+impl<W> FaultyWriter<W> {
+    /// Create a new writer, wrapping `inner` and injecting the faults of
+    /// `schedule` into successive writes.
+    pub fn new(inner: W, schedule: Vec<Fault>) -> FaultyWriter<W> {
+        FaultyWriter {
+            inner,
+            faults: Faults::new(schedule),
+        }
+    }
+
+    /// Error with `kind` once, on the first write that reaches `offset`.
+    /// Writes never pass data past `offset` before the error has been emitted.
+    pub fn fail_at(&mut self, offset: u64, kind: ErrorKind) {
+        self.faults.fail_at = Some((offset, kind));
+    }
+
+    /// Flip the bit with index `bit` (0 being the least significant) of the
+    /// byte at `offset`.
+    pub fn flip_bit(&mut self, offset: u64, bit: u8) {
+        assert!(bit < 8, "a byte has 8 bits");
+        self.faults.flips.push((offset, bit));
+    }
+
+    /// Silently drop all bytes past `offset`: they are reported as written,
+    /// but never reach the wrapped writer.
+    pub fn truncate_at(&mut self, offset: u64) {
+        self.faults.truncate_at = Some(offset);
+    }
+
+    /// Returns the number of bytes written so far, including dropped bytes.
+    pub fn position(&self) -> u64 {
+        self.faults.position
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwraps this `FaultyWriter`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for FaultyWriter<W> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, Error> {
+        let limit = try_ready!(self.faults.next(cx, buf.len()));
+        if limit == 0 {
+            return Ok(Async::Ready(0));
+        }
+
+        let untruncated = self.faults.untruncated(limit);
+        if untruncated == 0 {
+            self.faults.position += limit as u64;
+            return Ok(Async::Ready(limit));
+        }
+
+        let mut data = buf[..untruncated].to_vec();
+        self.faults.pass(&mut data);
+        self.faults.position -= untruncated as u64;
+
+        let written = try_ready!(self.inner.poll_write(cx, &data));
+        self.faults.position += written as u64;
+        Ok(Async::Ready(written))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.inner.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.inner.poll_close(cx)
+    }
+}
+
+impl<W: AsyncRead> AsyncRead for FaultyWriter<W> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, Error> {
+        self.inner.poll_read(cx, buf)
+    }
+}
+
+// One direction of a `MemoryDuplex` pair.
+struct Pipe {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Arc<Mutex<Pipe>> {
+        Arc::new(Mutex::new(Pipe {
+                                buffer: VecDeque::with_capacity(capacity),
+                                capacity,
+                                closed: false,
+                                reader: None,
+                                writer: None,
+                            }))
+    }
+}
+
+/// One end of an in-memory duplex connection, created by `duplex_pair`.
+///
+/// Closing or dropping an end signals end of stream to the reads of the other
+/// end once all written data has been read. Writing after closing errors with
+/// `ErrorKind::BrokenPipe`.
+pub struct MemoryDuplex {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+}
+
+/// Create two connected `MemoryDuplex`es, each buffering up to `capacity`
+/// bytes that have been written but not read yet.
+///
+/// # Panics
+/// Panics if `capacity` is 0.
+pub fn duplex_pair(capacity: usize) -> (MemoryDuplex, MemoryDuplex) {
+    assert!(capacity > 0, "a duplex pair needs a nonzero capacity");
+
+    let a_to_b = Pipe::new(capacity);
+    let b_to_a = Pipe::new(capacity);
+
+    (MemoryDuplex {
+         incoming: b_to_a.clone(),
+         outgoing: a_to_b.clone(),
+     },
+     MemoryDuplex {
+         incoming: a_to_b,
+         outgoing: b_to_a,
+     })
+}
+
+impl AsyncRead for MemoryDuplex {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, Error> {
+        let mut pipe = self.incoming.lock().unwrap();
+
+        if buf.len() == 0 {
+            return Ok(Async::Ready(0));
+        }
+
+        if pipe.buffer.is_empty() {
+            if pipe.closed {
+                return Ok(Async::Ready(0));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Ok(Async::Pending);
+        }
+
+        let read = min(buf.len(), pipe.buffer.len());
+        for (byte, data) in buf.iter_mut().zip(pipe.buffer.drain(..read)) {
+            *byte = data;
+        }
+
+        if let Some(writer) = pipe.writer.take() {
+            writer.wake();
+        }
+        Ok(Async::Ready(read))
+    }
+}
+
+impl AsyncWrite for MemoryDuplex {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, Error> {
+        let mut pipe = self.outgoing.lock().unwrap();
+
+        if pipe.closed {
+            return Err(Error::new(ErrorKind::BrokenPipe, "connection closed"));
+        }
+
+        if buf.len() == 0 {
+            return Ok(Async::Ready(0));
+        }
+
+        let free = pipe.capacity - pipe.buffer.len();
+        if free == 0 {
+            pipe.writer = Some(cx.waker().clone());
+            return Ok(Async::Pending);
+        }
+
+        let written = min(buf.len(), free);
+        pipe.buffer.extend(buf[..written].iter());
+
+        if let Some(reader) = pipe.reader.take() {
+            reader.wake();
+        }
+        Ok(Async::Ready(written))
+    }
+
+    fn poll_flush(&mut self, _: &mut Context) -> Poll<(), Error> {
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_close(&mut self, _: &mut Context) -> Poll<(), Error> {
+        close(&self.outgoing);
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Dropping an end also fails all further writes of the other end.
+impl Drop for MemoryDuplex {
+    fn drop(&mut self) {
+        close(&self.outgoing);
+        close(&self.incoming);
+    }
+}
+
+fn close(pipe: &Arc<Mutex<Pipe>>) {
+    let mut pipe = pipe.lock().unwrap();
+    pipe.closed = true;
+    if let Some(reader) = pipe.reader.take() {
+        reader.wake();
+    }
+    if let Some(writer) = pipe.writer.take() {
+        writer.wake();
+    }
+}
@@ -0,0 +1,361 @@
+// Deadlines for the reads and writes of box streams, driven by a pluggable timer.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures_core::{Async, Poll};
+use futures_core::task::{Context, Waker};
+use futures_io::{Error, ErrorKind};
+#[cfg(feature = "tokio")]
+use futures01;
+#[cfg(feature = "tokio")]
+use tokio_timer;
+
+/// The error value signaling that a reader waited too long for the next packet
+/// to begin.
+pub const IDLE_TIMEOUT: &'static str = "timed out waiting for a packet";
+
+/// The error value signaling that reading or writing a single packet took too
+/// long.
+pub const PACKET_TIMEOUT: &'static str = "timed out in the middle of a packet";
+
+/// The error value signaling that writing the final header took too long.
+pub const CLOSE_TIMEOUT: &'static str = "timed out writing the final header";
+
+/// Optional deadlines for the reads and writes of a box stream.
+///
+/// Each deadline bounds the time spent waiting on the wrapped reader or writer
+/// during one phase of the stream. The clock starts when the stream first has
+/// to wait in that phase, so time in which the stream is not polled does not
+/// count. Once a deadline passes, the stream errors with
+/// `ErrorKind::TimedOut` and one of `IDLE_TIMEOUT`, `PACKET_TIMEOUT` or
+/// `CLOSE_TIMEOUT`, and keeps returning that error from then on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timeouts {
+    /// How long a read may wait for the first byte of the next packet (or of
+    /// the final header).
+    pub idle_read: Option<Duration>,
+    /// How long reading or writing a single packet, including its header, may
+    /// wait once it has begun.
+    pub packet: Option<Duration>,
+    /// How long closing may wait for buffered data and the final header to be
+    /// written.
+    pub close: Option<Duration>,
+}
+
+/// A single deadline, created by a `Timer`.
+pub trait Delay {
+    /// Returns `Ready` once the deadline has passed. Otherwise, arranges for
+    /// the current task to be woken once it passes.
+    fn poll_elapsed(&mut self, cx: &mut Context) -> Poll<(), Error>;
+}
+
+/// Creates the deadlines of box streams, which allows them to time out on any
+/// runtime.
+pub trait Timer {
+    /// Create a `Delay` that elapses at `deadline`.
+    fn delay(&self, deadline: Instant) -> Box<Delay + Send>;
+}
+
+/// A `Timer` that works with any executor, by waiting for all deadlines on a
+/// single thread shared by the whole process. The thread is started the first
+/// time a deadline is waited on. Dropping a `Delay` before it elapses cancels
+/// it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadTimer;
+
+impl Timer for ThreadTimer {
+    fn delay(&self, deadline: Instant) -> Box<Delay + Send> {
+        Box::new(ThreadDelay { deadline, id: None })
+    }
+}
+
+struct ThreadDelay {
+    deadline: Instant,
+    // The id under which the deadline is registered with the timer thread, once it is waited on.
+    id: Option<u64>,
+}
+
+impl Delay for ThreadDelay {
+    fn poll_elapsed(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        if Instant::now() >= self.deadline {
+            return Ok(Async::Ready(()));
+        }
+
+        let timer = timer_thread();
+        let mut queue = timer.queue.lock().unwrap();
+        match self.id {
+            Some(id) => {
+                match queue.wakers.get_mut(&id) {
+                    Some(waker) => *waker = cx.waker().clone(),
+                    // The timer thread has already woken the task for this deadline.
+                    None => return Ok(Async::Ready(())),
+                }
+            }
+            None => {
+                let id = queue.next_id;
+                queue.next_id += 1;
+                queue.wakers.insert(id, cx.waker().clone());
+                queue.deadlines.push(Reverse((self.deadline, id)));
+                self.id = Some(id);
+                timer.changed.notify_one();
+            }
+        }
+
+        Ok(Async::Pending)
+    }
+}
+
+impl Drop for ThreadDelay {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            timer_thread().queue.lock().unwrap().cancel(id);
+        }
+    }
+}
+
+// The thread serving the deadlines of all `ThreadDelay`s.
+struct TimerThread {
+    queue: Mutex<Queue>,
+    // Notified whenever a deadline is added.
+    changed: Condvar,
+}
+
+struct Queue {
+    // The registered deadlines, earliest first.
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    // The tasks to wake for the deadlines that have neither elapsed nor been cancelled.
+    wakers: HashMap<u64, Waker>,
+    next_id: u64,
+}
+
+impl Queue {
+    fn cancel(&mut self, id: u64) {
+        self.wakers.remove(&id);
+
+        // Cancelled deadlines stay in the heap until they elapse, so it is rebuilt once they
+        // outnumber the live ones.
+        if self.deadlines.len() > 2 * self.wakers.len() + 16 {
+            let wakers = &self.wakers;
+            let live = self.deadlines
+                .drain()
+                .filter(|&Reverse((_, id))| wakers.contains_key(&id))
+                .collect();
+            self.deadlines = live;
+        }
+    }
+}
+
+// Returns the timer thread, starting it on the first call.
+fn timer_thread() -> &'static TimerThread {
+    static START: Once = Once::new();
+    static mut TIMER_THREAD: Option<&'static TimerThread> = None;
+
+    unsafe {
+        START.call_once(|| {
+            let timer: &'static TimerThread = Box::leak(Box::new(TimerThread {
+                queue: Mutex::new(Queue {
+                                      deadlines: BinaryHeap::new(),
+                                      wakers: HashMap::new(),
+                                      next_id: 0,
+                                  }),
+                changed: Condvar::new(),
+            }));
+            thread::Builder::new()
+                .name("box-stream-timer".to_string())
+                .spawn(move || timer.run())
+                .expect("failed to spawn the timer thread");
+            TIMER_THREAD = Some(timer);
+        });
+        TIMER_THREAD.unwrap()
+    }
+}
+
+impl TimerThread {
+    fn run(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut elapsed = Vec::new();
+            while let Some(&Reverse((deadline, id))) = queue.deadlines.peek() {
+                if deadline > now {
+                    break;
+                }
+                queue.deadlines.pop();
+                if let Some(waker) = queue.wakers.remove(&id) {
+                    elapsed.push(waker);
+                }
+            }
+
+            // Wake without holding the lock, in case waking polls a delay right away.
+            if !elapsed.is_empty() {
+                drop(queue);
+                for waker in elapsed {
+                    waker.wake();
+                }
+                queue = self.queue.lock().unwrap();
+                continue;
+            }
+
+            let next = queue.deadlines.peek().map(|&Reverse((deadline, _))| deadline);
+            queue = match next {
+                Some(deadline) => self.changed.wait_timeout(queue, deadline - now).unwrap().0,
+                None => self.changed.wait(queue).unwrap(),
+            };
+        }
+    }
+}
+
+/// A `Timer` backed by the timer of a tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub struct TokioTimer(tokio_timer::timer::Handle);
+
+#[cfg(feature = "tokio")]
+impl TokioTimer {
+    /// Create a timer that uses the timer of the tokio runtime this is called
+    /// from. Deadlines of a timer created outside of a runtime error when
+    /// they are waited on.
+    pub fn current() -> TokioTimer {
+        TokioTimer(tokio_timer::timer::Handle::current())
+    }
+
+    /// Create a timer that uses the timer referenced by `handle`.
+    pub fn new(handle: tokio_timer::timer::Handle) -> TokioTimer {
+        TokioTimer(handle)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    fn delay(&self, deadline: Instant) -> Box<Delay + Send> {
+        Box::new(TokioDelay {
+                     delay: futures01::executor::spawn(self.0.delay(deadline)),
+                     notify: Arc::new(WakerNotify(Mutex::new(None))),
+                 })
+    }
+}
+
+#[cfg(feature = "tokio")]
+struct TokioDelay {
+    delay: futures01::executor::Spawn<tokio_timer::Delay>,
+    notify: Arc<WakerNotify>,
+}
+
+// Wakes the task that last polled a `TokioDelay` when the tokio timer fires.
+#[cfg(feature = "tokio")]
+struct WakerNotify(Mutex<Option<Waker>>);
+
+#[cfg(feature = "tokio")]
+impl futures01::executor::Notify for WakerNotify {
+    fn notify(&self, _: usize) {
+        if let Some(ref waker) = *self.0.lock().unwrap() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Delay for TokioDelay {
+    fn poll_elapsed(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        *self.notify.0.lock().unwrap() = Some(cx.waker().clone());
+        let notify = futures01::executor::NotifyHandle::from(self.notify.clone());
+
+        match self.delay.poll_future_notify(&notify, 0) {
+            Ok(futures01::Async::Ready(())) => Ok(Async::Ready(())),
+            Ok(futures01::Async::NotReady) => Ok(Async::Pending),
+            Err(e) => Err(Error::new(ErrorKind::Other, e)),
+        }
+    }
+}
+
+// The phase of a box stream a deadline applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    // Not waiting on the wrapped reader or writer for any packet.
+    Buffered,
+    // Waiting for the packet with the given index to begin.
+    Idle(u64),
+    // Reading or writing the packet with the given index.
+    Packet(u64),
+    // Closing the stream.
+    Close,
+}
+
+// The deadlines of an `Encryptor` or `Decryptor`.
+pub(crate) struct Deadlines {
+    timeouts: Timeouts,
+    timer: Arc<Timer + Send + Sync>,
+    phase: Phase,
+    delay: Option<Box<Delay + Send>>,
+    poisoned: Option<&'static str>,
+    // Whether closing has begun, which puts the stream in the `Close` phase.
+    pub closing: bool,
+}
+
+impl Deadlines {
+    pub fn new(timeouts: Timeouts, timer: Arc<Timer + Send + Sync>) -> Deadlines {
+        Deadlines {
+            timeouts,
+            timer,
+            phase: Phase::Buffered,
+            delay: None,
+            poisoned: None,
+            closing: false,
+        }
+    }
+
+    // Errors if a deadline has passed before.
+    pub fn check(&self) -> Result<(), Error> {
+        match self.poisoned {
+            Some(value) => Err(Error::new(ErrorKind::TimedOut, value)),
+            None => Ok(()),
+        }
+    }
+
+    // Takes the result of polling the stream, which is now in `phase`. If the stream has to wait,
+    // waits for the deadline of the phase as well, and errors if it has passed.
+    pub fn poll<T>(&mut self,
+                   cx: &mut Context,
+                   phase: Phase,
+                   result: Poll<T, Error>)
+                   -> Poll<T, Error> {
+        if phase != self.phase {
+            self.phase = phase;
+            self.delay = None;
+        }
+
+        match result {
+            Ok(Async::Pending) => {}
+            _ => return result,
+        }
+
+        let (timeout, value) = match phase {
+            Phase::Buffered => return result,
+            Phase::Idle(_) => (self.timeouts.idle_read, IDLE_TIMEOUT),
+            Phase::Packet(_) => (self.timeouts.packet, PACKET_TIMEOUT),
+            Phase::Close => (self.timeouts.close, CLOSE_TIMEOUT),
+        };
+
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return result,
+        };
+
+        if self.delay.is_none() {
+            self.delay = Some(self.timer.delay(Instant::now() + timeout));
+        }
+
+        match self.delay.as_mut().unwrap().poll_elapsed(cx)? {
+            Async::Ready(()) => {
+                self.delay = None;
+                self.poisoned = Some(value);
+                Err(Error::new(ErrorKind::TimedOut, value))
+            }
+            Async::Pending => result,
+        }
+    }
+}
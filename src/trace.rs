@@ -0,0 +1,17 @@
+// Emits tracing events from the encryption and decryption state machines if the `tracing`
+// feature is enabled, and expands to nothing otherwise.
+//
+// Usage: `state_event!(&self.span, LEVEL, fields..., "message")`. The arguments are not evaluated
+// without the feature, so they may compute values just for the event.
+
+#[cfg(feature = "tracing")]
+macro_rules! state_event {
+    ($span:expr, $level:ident, $($arg:tt)+) => {
+        event!(parent: $span, ::tracing::Level::$level, $($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! state_event {
+    ($($arg:tt)+) => {};
+}
@@ -0,0 +1,286 @@
+//! A tcp tunnel that wraps plaintext connections in box streams, keyed by a
+//! pre-shared key.
+//!
+//! A tunnel client accepts plaintext connections and forwards them, encrypted,
+//! to a tunnel server, which decrypts them and forwards them to the actual
+//! service. For each tunneled connection, both tunnel endpoints first send a
+//! random nonce, and then derive the keys for both directions via
+//! `DuplexKeys::from_pre_shared_key`.
+//!
+//! After the nonces, both tunnel endpoints send a proof of knowing the key,
+//! `hmac(key, "box-tunnel proof client" ++ client_nonce ++ server_nonce)` (or
+//! `"box-tunnel proof server"` for the server), and check the proof of the
+//! other endpoint. A tunnel server only connects to the service once the proof
+//! of the tunnel client has been checked, so a peer without the key never
+//! reaches the service, while services that speak first work as usual. The
+//! nonce and proof of the other endpoint have to arrive within
+//! `Config::handshake_timeout`.
+//!
+//! Each direction of a connection is forwarded by its own thread, which drives
+//! a `BoxDuplex` over a clone of the tunnel connection in that direction only.
+//! When one side of the plaintext connection shuts down writing, the tunnel
+//! writes the final header and shuts down writing as well, and the other
+//! tunnel endpoint forwards this half-close once it has authenticated the
+//! final header. If anything fails to authenticate, both connections are shut
+//! down completely.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use futures_io::{AsyncRead, AsyncWrite};
+use sodiumoxide::crypto::{auth, secretbox};
+use sodiumoxide::utils::memzero;
+
+use blocking::{Blocking, Buffer, poll_blocking};
+use box_duplex::BoxDuplex;
+use duplex_keys::DuplexKeys;
+
+/// The error value signaling that the other tunnel endpoint could not prove
+/// that it knows the key.
+pub const UNAUTHENTICATED_PEER: &'static str = "tunnel peer failed to prove knowledge of the key";
+
+// The box stream of one direction of a tunneled connection.
+type TunnelStream = BoxDuplex<Blocking<TcpStream>>;
+
+/// Which end of the tunnel to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Accept plaintext connections and connect to a tunnel server.
+    Client,
+    /// Accept connections from tunnel clients and connect to the service.
+    Server,
+}
+
+/// The configuration of a tunnel endpoint.
+#[derive(Clone)]
+pub struct Config {
+    /// Which end of the tunnel to run.
+    pub role: Role,
+    /// The key shared by the tunnel client and server.
+    pub key: secretbox::Key,
+    /// Where to forward accepted connections to.
+    pub connect: SocketAddr,
+    /// The maximum number of connections forwarded at the same time. Further
+    /// connections are closed right after accepting them.
+    pub max_connections: usize,
+    /// How long to wait for the nonce and the proof of the other tunnel
+    /// endpoint. Must not be zero.
+    pub handshake_timeout: Duration,
+}
+
+/// Accept connections on `listener` and forward them as described by `config`,
+/// each on its own threads.
+///
+/// This only returns if accepting a connection fails.
+pub fn run(listener: TcpListener, config: Config) -> Error {
+    let active = Arc::new(AtomicUsize::new(0));
+
+    loop {
+        let accepted = match listener.accept() {
+            Ok((accepted, _)) => accepted,
+            Err(e) => return e,
+        };
+
+        if active.fetch_add(1, Ordering::SeqCst) >= config.max_connections {
+            active.fetch_sub(1, Ordering::SeqCst);
+            let _ = accepted.shutdown(Shutdown::Both);
+            continue;
+        }
+
+        let slot = Slot(active.clone());
+        let config = config.clone();
+        thread::spawn(move || {
+                          let _slot = slot;
+                          let _ = forward(accepted, &config);
+                      });
+    }
+}
+
+// Frees a connection slot on dropping.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Set up the connection to the configured address and forward between it and `accepted` until
+// both directions are done.
+fn forward(accepted: TcpStream, config: &Config) -> Result<(), Error> {
+    accepted.set_nodelay(true)?;
+
+    let (plain, tunnel, keys) = match config.role {
+        Role::Client => {
+            let tunnel = TcpStream::connect(config.connect)?;
+            tunnel.set_nodelay(true)?;
+            match handshake(&tunnel, config) {
+                Ok(keys) => (accepted, tunnel, keys),
+                Err(e) => {
+                    abort(&accepted, &tunnel);
+                    return Err(e);
+                }
+            }
+        }
+        Role::Server => {
+            let keys = handshake(&accepted, config)?;
+            let plain = TcpStream::connect(config.connect)?;
+            plain.set_nodelay(true)?;
+            (plain, accepted, keys)
+        }
+    };
+
+    // A `BoxDuplex` can not be shared by the forwarding threads, so each gets its own, and only
+    // uses it in one direction.
+    let outgoing_stream = tunnel_stream(&tunnel, &keys)?;
+    let incoming_stream = tunnel_stream(&tunnel, &keys)?;
+    let (plain_in, plain_out) = (plain.try_clone()?, plain.try_clone()?);
+    let (tunnel_a, tunnel_b) = (tunnel.try_clone()?, tunnel);
+
+    let outgoing = thread::spawn(move || encrypt(plain_in, outgoing_stream, &tunnel_a));
+    let incoming = decrypt(incoming_stream, plain_out, &tunnel_b);
+
+    let outgoing = outgoing
+        .join()
+        .unwrap_or_else(|_| Err(Error::new(ErrorKind::Other, "forwarding thread panicked")));
+    incoming.and(outgoing)
+}
+
+fn tunnel_stream(tunnel: &TcpStream, keys: &DuplexKeys) -> Result<TunnelStream, Error> {
+    Ok(BoxDuplex::new(Blocking(tunnel.try_clone()?),
+                      keys.encryption_key.clone(),
+                      keys.decryption_key.clone(),
+                      keys.encryption_nonce.clone(),
+                      keys.decryption_nonce.clone()))
+}
+
+// Exchange nonces and proofs of knowing the key with the other tunnel endpoint, and derive the
+// keys. The handshake timeout applies while waiting for the other endpoint.
+fn handshake(mut tunnel: &TcpStream, config: &Config) -> Result<DuplexKeys, Error> {
+    tunnel.set_read_timeout(Some(config.handshake_timeout))?;
+
+    let local_nonce = secretbox::gen_nonce();
+    tunnel.write_all(&local_nonce.0)?;
+    let mut remote_nonce = [0u8; secretbox::NONCEBYTES];
+    tunnel.read_exact(&mut remote_nonce)?;
+    let remote_nonce = secretbox::Nonce(remote_nonce);
+
+    let client = config.role == Role::Client;
+    let (client_nonce, server_nonce) = if client {
+        (&local_nonce, &remote_nonce)
+    } else {
+        (&remote_nonce, &local_nonce)
+    };
+
+    let mut psk = auth::Key(config.key.0);
+    let (local_label, remote_label): (&[u8], &[u8]) = if client {
+        (b"box-tunnel proof client", b"box-tunnel proof server")
+    } else {
+        (b"box-tunnel proof server", b"box-tunnel proof client")
+    };
+    let local_proof = auth::authenticate(&proof_input(local_label, client_nonce, server_nonce),
+                                         &psk);
+    let remote_input = proof_input(remote_label, client_nonce, server_nonce);
+
+    let result = tunnel
+        .write_all(&local_proof.0)
+        .and_then(|()| {
+            let mut remote_proof = [0u8; auth::TAGBYTES];
+            tunnel.read_exact(&mut remote_proof)?;
+            if auth::verify(&auth::Tag(remote_proof), &remote_input, &psk) {
+                Ok(())
+            } else {
+                Err(Error::new(ErrorKind::PermissionDenied, UNAUTHENTICATED_PEER))
+            }
+        });
+    memzero(&mut psk.0);
+    result?;
+
+    tunnel.set_read_timeout(None)?;
+    Ok(DuplexKeys::from_pre_shared_key(&config.key, &local_nonce, &remote_nonce, client))
+}
+
+fn proof_input(label: &[u8],
+               client_nonce: &secretbox::Nonce,
+               server_nonce: &secretbox::Nonce)
+               -> Vec<u8> {
+    let mut input = Vec::with_capacity(label.len() + 2 * secretbox::NONCEBYTES);
+    input.extend_from_slice(label);
+    input.extend_from_slice(&client_nonce.0);
+    input.extend_from_slice(&server_nonce.0);
+    input
+}
+
+// Forward plaintext into the tunnel. A half-close of the plaintext connection is forwarded as the
+// final header.
+fn encrypt(mut plain: TcpStream, mut stream: TunnelStream, tunnel: &TcpStream) -> Result<(), Error> {
+    let mut buffer = Buffer::new();
+    let result = encrypt_with(&mut plain, &mut stream, &mut buffer.0);
+
+    match result {
+        Ok(()) => tunnel.shutdown(Shutdown::Write),
+        Err(e) => {
+            abort(&plain, tunnel);
+            Err(e)
+        }
+    }
+}
+
+fn encrypt_with(plain: &mut TcpStream,
+                stream: &mut TunnelStream,
+                buffer: &mut [u8])
+                -> Result<(), Error> {
+    loop {
+        let read = plain.read(buffer)?;
+        if read == 0 {
+            return poll_blocking(|cx| stream.poll_close(cx));
+        }
+
+        let mut offset = 0;
+        while offset < read {
+            let written = poll_blocking(|cx| stream.poll_write(cx, &buffer[offset..read]))?;
+            if written == 0 {
+                return Err(Error::new(ErrorKind::WriteZero, "failed to write to the tunnel"));
+            }
+            offset += written;
+        }
+        poll_blocking(|cx| stream.poll_flush(cx))?;
+    }
+}
+
+// Forward data from the tunnel to the plaintext connection. Only an authenticated final header is
+// forwarded as a half-close, anything else aborts both connections.
+fn decrypt(mut stream: TunnelStream, mut plain: TcpStream, tunnel: &TcpStream) -> Result<(), Error> {
+    let mut buffer = Buffer::new();
+    let result = decrypt_with(&mut stream, &mut plain, &mut buffer.0);
+
+    match result {
+        Ok(()) => plain.shutdown(Shutdown::Write),
+        Err(e) => {
+            abort(&plain, tunnel);
+            Err(e)
+        }
+    }
+}
+
+fn decrypt_with(stream: &mut TunnelStream,
+                plain: &mut TcpStream,
+                buffer: &mut [u8])
+                -> Result<(), Error> {
+    loop {
+        let read = poll_blocking(|cx| stream.poll_read(cx, &mut buffer[..]))?;
+        if read == 0 {
+            return Ok(());
+        }
+        plain.write_all(&buffer[..read])?;
+    }
+}
+
+fn abort(plain: &TcpStream, tunnel: &TcpStream) {
+    let _ = plain.shutdown(Shutdown::Both);
+    let _ = tunnel.shutdown(Shutdown::Both);
+}
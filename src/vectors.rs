@@ -0,0 +1,437 @@
+//! Test vectors for the box-stream protocol, and functions for replaying them.
+//!
+//! The corpus in `test-data/vectors.json` (available as `CORPUS`) is generated
+//! by `test-data/vectors.py` directly from libsodium, and covers single
+//! packets, packet boundaries, multi-packet streams, the final header and
+//! nonce overflow, as well as tampered streams. It can be checked against
+//! pull-box-stream with `test-data/check-pull-box-stream.js`, see the README.
+//! It is plain JSON so that other implementations can replay it as well:
+//!
+//! ```json
+//! {
+//!   "description": "...",
+//!   "vectors": [
+//!     {
+//!       "name": "multiple writes",
+//!       "description": "every write is sealed in its own packet",
+//!       "key": "<hex>",
+//!       "nonce": "<hex>",
+//!       "writes": ["68656c6c6f", "20", "776f726c64"],
+//!       "cypher": "<hex>",
+//!       "plaintext": "68656c6c6f20776f726c64",
+//!       "error": null
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Sealing each of the `writes` in turn (splitting them into packets of at
+//! most `MAX_PACKET_SIZE` bytes) and then the final header yields `cypher`.
+//! Decrypting `cypher` yields `plaintext`, and then fails with `error` unless
+//! it is `null`. `error` is the name of one of the error values of this crate
+//! (e.g. `"UNAUTHENTICATED_HEADER"`). Vectors with an error have no writes.
+
+use std::fmt;
+use std::io::Cursor;
+
+use futures_core::{Future, Poll};
+use futures_core::Async::{Pending, Ready};
+use futures_core::task::Context;
+use futures_io::{Error, ErrorKind, AsyncRead, AsyncWrite};
+use serde_json::{self, Value};
+use sodiumoxide::crypto::secretbox;
+
+use box_reader::BoxReader;
+use box_writer::BoxWriter;
+use crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_SIZE, MAX_PACKET_USIZE, PlainHeader, decrypt_header,
+             decrypt_packet, encrypt_packet, final_header};
+use decryptor::{INVALID_LENGTH, TRAILING_DATA, UNAUTHENTICATED_EOF, UNAUTHENTICATED_HEADER,
+                UNAUTHENTICATED_PACKET};
+
+/// The vector corpus of this crate, as JSON.
+pub const CORPUS: &'static str = include_str!("../test-data/vectors.json");
+
+/// The error value signaling that a vector does not follow the format of the
+/// corpus.
+pub const INVALID_VECTOR: &'static str = "invalid test vector";
+
+/// A single test vector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector {
+    /// A unique name for the vector.
+    pub name: String,
+    /// The key of the box stream.
+    pub key: secretbox::Key,
+    /// The initial nonce of the box stream.
+    pub nonce: secretbox::Nonce,
+    /// The plaintext chunks that are written to produce `cypher`. Empty for
+    /// vectors with an error.
+    pub writes: Vec<Vec<u8>>,
+    /// The box stream.
+    pub cypher: Vec<u8>,
+    /// The plaintext that is authenticated before reaching the end of the
+    /// stream or the error.
+    pub plaintext: Vec<u8>,
+    /// The error value that decrypting `cypher` fails with, if any.
+    pub error: Option<&'static str>,
+}
+
+/// Parses a corpus of vectors in the format of `CORPUS`.
+///
+/// # Errors
+/// Errors with `ErrorKind::InvalidData` if `json` is not valid JSON, or with
+/// `ErrorKind::InvalidData` and `INVALID_VECTOR` if it does not follow the
+/// format of the corpus.
+pub fn parse(json: &str) -> Result<Vec<Vector>, Error> {
+    let corpus: Value = serde_json::from_str(json)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    corpus["vectors"]
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(parse_vector)
+        .collect()
+}
+
+/// Returns the vectors of `CORPUS`.
+pub fn corpus() -> Vec<Vector> {
+    parse(CORPUS).expect("the corpus is valid")
+}
+
+fn parse_vector(vector: &Value) -> Result<Vector, Error> {
+    let key = secretbox::Key::from_slice(&hex_field(vector, "key")?).ok_or_else(invalid)?;
+    let nonce = secretbox::Nonce::from_slice(&hex_field(vector, "nonce")?)
+        .ok_or_else(invalid)?;
+
+    let writes = vector["writes"]
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|write| write.as_str().and_then(from_hex).ok_or_else(invalid))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let error = match vector["error"] {
+        Value::Null => None,
+        Value::String(ref name) => Some(error_value(name).ok_or_else(invalid)?),
+        _ => return Err(invalid()),
+    };
+
+    Ok(Vector {
+           name: vector["name"].as_str().ok_or_else(invalid)?.to_string(),
+           key,
+           nonce,
+           writes,
+           cypher: hex_field(vector, "cypher")?,
+           plaintext: hex_field(vector, "plaintext")?,
+           error,
+       })
+}
+
+fn hex_field(vector: &Value, field: &str) -> Result<Vec<u8>, Error> {
+    vector[field]
+        .as_str()
+        .and_then(from_hex)
+        .ok_or_else(invalid)
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len() / 2)
+        .map(|i| hex.get(2 * i..2 * i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+fn error_value(name: &str) -> Option<&'static str> {
+    match name {
+        "UNAUTHENTICATED_HEADER" => Some(UNAUTHENTICATED_HEADER),
+        "INVALID_LENGTH" => Some(INVALID_LENGTH),
+        "UNAUTHENTICATED_PACKET" => Some(UNAUTHENTICATED_PACKET),
+        "UNAUTHENTICATED_EOF" => Some(UNAUTHENTICATED_EOF),
+        "TRAILING_DATA" => Some(TRAILING_DATA),
+        _ => None,
+    }
+}
+
+fn invalid() -> Error {
+    Error::new(ErrorKind::InvalidData, INVALID_VECTOR)
+}
+
+/// A vector that was not reproduced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The name of the vector.
+    pub vector: String,
+    /// What replayed the vector, e.g. `"BoxReader"`.
+    pub replayed_by: &'static str,
+    /// The expected outcome.
+    pub expected: String,
+    /// The actual outcome.
+    pub actual: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{} does not reproduce vector \"{}\": expected {}, got {}",
+               self.replayed_by,
+               self.vector,
+               self.expected,
+               self.actual)
+    }
+}
+
+// Describes the outcome of decrypting a stream.
+fn outcome(plaintext: &[u8], error: Option<&str>) -> String {
+    format!("{} plaintext bytes, then {}",
+            plaintext.len(),
+            error.unwrap_or("the end of the stream"))
+}
+
+// Compares the outcome of decrypting the stream of `vector` to the expected outcome.
+fn check_outcome(vector: &Vector,
+                 replayed_by: &'static str,
+                 plaintext: &[u8],
+                 error: Option<&str>)
+                 -> Result<(), Mismatch> {
+    if plaintext == &vector.plaintext[..] && error == vector.error {
+        Ok(())
+    } else {
+        Err(Mismatch {
+                vector: vector.name.clone(),
+                replayed_by,
+                expected: outcome(&vector.plaintext, vector.error),
+                actual: outcome(plaintext, error),
+            })
+    }
+}
+
+// Compares a produced box stream to the stream of `vector`.
+fn check_cypher(vector: &Vector, replayed_by: &'static str, cypher: &[u8]) -> Result<(), Mismatch> {
+    if cypher == &vector.cypher[..] {
+        Ok(())
+    } else {
+        let differs_at = cypher
+            .iter()
+            .zip(vector.cypher.iter())
+            .take_while(|&(a, b)| a == b)
+            .count();
+
+        Err(Mismatch {
+                vector: vector.name.clone(),
+                replayed_by,
+                expected: format!("{} cypher bytes", vector.cypher.len()),
+                actual: format!("{} cypher bytes, differing at offset {}",
+                                cypher.len(),
+                                differs_at),
+            })
+    }
+}
+
+/// Replays `vector` using only the functions of the `crypto` module: seals
+/// its writes (if it has no error) and decrypts its stream.
+pub fn replay_crypto(vector: &Vector) -> Result<(), Mismatch> {
+    if vector.error.is_none() {
+        check_cypher(vector, "crypto", &seal_with_primitives(vector))?;
+    }
+
+    let (plaintext, error) = open_with_primitives(vector);
+    check_outcome(vector, "crypto", &plaintext, error)
+}
+
+fn seal_with_primitives(vector: &Vector) -> Vec<u8> {
+    let mut nonce = vector.nonce.0;
+    let mut cypher = Vec::new();
+    let mut buffer = [0u8; CYPHER_HEADER_SIZE + MAX_PACKET_USIZE];
+
+    for write in vector.writes.iter() {
+        for packet in write.chunks(MAX_PACKET_USIZE) {
+            unsafe {
+                encrypt_packet(buffer.as_mut_ptr(),
+                               packet.as_ptr(),
+                               packet.len() as u16,
+                               &vector.key.0,
+                               &mut nonce);
+            }
+            cypher.extend_from_slice(&buffer[..CYPHER_HEADER_SIZE + packet.len()]);
+        }
+    }
+
+    let mut header = [0u8; CYPHER_HEADER_SIZE];
+    unsafe {
+        final_header(&mut header, &vector.key.0, &nonce);
+    }
+    cypher.extend_from_slice(&header);
+    cypher
+}
+
+fn open_with_primitives(vector: &Vector) -> (Vec<u8>, Option<&'static str>) {
+    let mut nonce = vector.nonce.0;
+    let mut plaintext = Vec::new();
+    let mut cypher = &vector.cypher[..];
+    let mut buffer = [0u8; MAX_PACKET_USIZE];
+
+    loop {
+        if cypher.len() < CYPHER_HEADER_SIZE {
+            return (plaintext, Some(UNAUTHENTICATED_EOF));
+        }
+
+        let mut plain_header = PlainHeader::new();
+        if !unsafe {
+                decrypt_header(&mut plain_header,
+                               &*(cypher.as_ptr() as *const [u8; CYPHER_HEADER_SIZE]),
+                               &vector.key.0,
+                               &mut nonce)
+            } {
+            return (plaintext, Some(UNAUTHENTICATED_HEADER));
+        }
+        cypher = &cypher[CYPHER_HEADER_SIZE..];
+
+        if plain_header.is_final_header() {
+            let error = if cypher.is_empty() {
+                None
+            } else {
+                Some(TRAILING_DATA)
+            };
+            return (plaintext, error);
+        }
+
+        let len = plain_header.get_packet_len();
+        if len == 0 || len > MAX_PACKET_SIZE {
+            return (plaintext, Some(INVALID_LENGTH));
+        }
+        if cypher.len() < len as usize {
+            return (plaintext, Some(UNAUTHENTICATED_EOF));
+        }
+
+        if !unsafe {
+                decrypt_packet(buffer.as_mut_ptr(),
+                               cypher.as_ptr(),
+                               &plain_header,
+                               &vector.key.0,
+                               &mut nonce)
+            } {
+            return (plaintext, Some(UNAUTHENTICATED_PACKET));
+        }
+        plaintext.extend_from_slice(&buffer[..len as usize]);
+        cypher = &cypher[len as usize..];
+    }
+}
+
+/// Replays `vector` against a `BoxWriter`: writes each of its writes (if it
+/// has no error), closes the writer, and compares the result to its stream.
+pub fn replay_box_writer(vector: &Vector) -> ReplayBoxWriter {
+    ReplayBoxWriter {
+        writer: BoxWriter::new(Cursor::new(Vec::new()), vector.key.clone(), vector.nonce.clone()),
+        vector: vector.clone(),
+        write: 0,
+        offset: 0,
+    }
+}
+
+/// Future for the `replay_box_writer` function.
+pub struct ReplayBoxWriter {
+    writer: BoxWriter<Cursor<Vec<u8>>>,
+    vector: Vector,
+    // Index of the current write, and offset into it.
+    write: usize,
+    offset: usize,
+}
+
+impl Future for ReplayBoxWriter {
+    type Item = ();
+    type Error = Mismatch;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<(), Mismatch> {
+        if self.vector.error.is_some() {
+            return Ok(Ready(()));
+        }
+
+        while self.write < self.vector.writes.len() {
+            let write = &self.vector.writes[self.write];
+            if self.offset < write.len() {
+                match self.writer.poll_write(cx, &write[self.offset..]) {
+                    Ok(Ready(written)) => self.offset += written,
+                    Ok(Pending) => return Ok(Pending),
+                    Err(e) => return Err(self.failed(e)),
+                }
+            } else {
+                self.write += 1;
+                self.offset = 0;
+            }
+        }
+
+        match self.writer.poll_close(cx) {
+            Ok(Ready(())) => {
+                check_cypher(&self.vector, "BoxWriter", self.writer.get_ref().get_ref())
+                    .map(Ready)
+            }
+            Ok(Pending) => Ok(Pending),
+            Err(e) => Err(self.failed(e)),
+        }
+    }
+}
+
+impl ReplayBoxWriter {
+    fn failed(&self, e: Error) -> Mismatch {
+        Mismatch {
+            vector: self.vector.name.clone(),
+            replayed_by: "BoxWriter",
+            expected: format!("{} cypher bytes", self.vector.cypher.len()),
+            actual: e.to_string(),
+        }
+    }
+}
+
+/// Replays `vector` against a `BoxReader`: reads its stream until the end of
+/// the stream or an error, and compares the result to the expected outcome.
+pub fn replay_box_reader(vector: &Vector) -> ReplayBoxReader {
+    ReplayBoxReader {
+        reader: BoxReader::new(Cursor::new(vector.cypher.clone()),
+                               vector.key.clone(),
+                               vector.nonce.clone()),
+        vector: vector.clone(),
+        plaintext: Vec::new(),
+    }
+}
+
+/// Future for the `replay_box_reader` function.
+pub struct ReplayBoxReader {
+    reader: BoxReader<Cursor<Vec<u8>>>,
+    vector: Vector,
+    plaintext: Vec<u8>,
+}
+
+impl Future for ReplayBoxReader {
+    type Item = ();
+    type Error = Mismatch;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<(), Mismatch> {
+        let mut buffer = [0u8; MAX_PACKET_USIZE];
+
+        loop {
+            match self.reader.poll_read(cx, &mut buffer) {
+                Ok(Ready(0)) => {
+                    let inner = self.reader.get_ref();
+                    let error = if inner.position() < inner.get_ref().len() as u64 {
+                        Some(TRAILING_DATA)
+                    } else {
+                        None
+                    };
+                    return check_outcome(&self.vector, "BoxReader", &self.plaintext, error)
+                               .map(Ready);
+                }
+                Ok(Ready(read)) => self.plaintext.extend_from_slice(&buffer[..read]),
+                Ok(Pending) => return Ok(Pending),
+                Err(e) => {
+                    let value = e.to_string();
+                    return check_outcome(&self.vector, "BoxReader", &self.plaintext, Some(&value))
+                               .map(Ready);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// Verification of box streams without producing any plaintext.
+
+use std::io::Read;
+
+use futures_core::{Future, Poll};
+use futures_core::Async::{Pending, Ready};
+use futures_core::task::Context;
+use futures_io::{Error, ErrorKind, AsyncRead};
+use sodiumoxide::crypto::secretbox;
+
+use blocking::{Blocking, poll_blocking};
+use crypto::CYPHER_HEADER_SIZE;
+use decryptor::{Decryptor, TRAILING_DATA};
+
+/// What has been authenticated while verifying a box stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Verified {
+    /// The number of authenticated packets.
+    pub packets: u64,
+    /// The number of authenticated plaintext bytes.
+    pub bytes: u64,
+}
+
+impl Verified {
+    /// Returns the offset in the box stream directly after the authenticated
+    /// packets.
+    pub fn offset(&self) -> u64 {
+        self.packets * CYPHER_HEADER_SIZE as u64 + self.bytes
+    }
+}
+
+/// The first failure encountered while verifying a box stream.
+#[derive(Debug)]
+pub struct VerifyError {
+    /// The offset in the box stream of the packet (or final header) that
+    /// failed to authenticate, or of the data following the final header.
+    pub offset: u64,
+    /// What has been authenticated before the failure.
+    pub verified: Verified,
+    /// The error, the same one a `BoxReader` would have produced, or
+    /// `TRAILING_DATA` if there is data after the final header.
+    pub error: Error,
+}
+
+impl Verified {
+    fn fail(self, error: Error) -> VerifyError {
+        VerifyError {
+            offset: self.offset(),
+            verified: self,
+            error,
+        }
+    }
+}
+
+/// Authenticate every packet and the final header of the box stream read from
+/// `reader`, and check that the reader ends directly after the final header.
+///
+/// Plaintext is zeroed as soon as it has been authenticated, it is never
+/// handed out.
+///
+/// This runs the future of `verify_async` on the blocking reader, so both
+/// verify exactly the same way.
+pub fn verify<R: Read>(reader: R,
+                       key: &secretbox::Key,
+                       nonce: &secretbox::Nonce)
+                       -> Result<Verified, VerifyError> {
+    let mut verify = verify_async(Blocking(reader), key.clone(), nonce.clone());
+    poll_blocking(|cx| verify.poll(cx))
+}
+
+/// Create a future that authenticates the box stream read from `reader`, the
+/// same way `verify` does.
+///
+/// To check for trailing data, the future only resolves once `reader` has
+/// signalled end of stream.
+pub fn verify_async<R: AsyncRead>(reader: R,
+                                  key: secretbox::Key,
+                                  nonce: secretbox::Nonce)
+                                  -> Verify<R> {
+    Verify {
+        inner: reader,
+        key,
+        nonce,
+        decryptor: Decryptor::new(),
+        verified: Verified::default(),
+        done: false,
+    }
+}
+
+/// Future for the `verify_async` function.
+pub struct Verify<R> {
+    inner: R,
+    key: secretbox::Key,
+    nonce: secretbox::Nonce,
+    decryptor: Decryptor,
+    verified: Verified,
+    // Whether the final header has been authenticated.
+    done: bool,
+}
+
+impl<R> Verify<R> {
+    /// Returns what has been authenticated so far.
+    pub fn verified(&self) -> Verified {
+        self.verified
+    }
+
+    /// Unwraps this `Verify`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead> Verify<R> {
+    fn poll_verify(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        while !self.done {
+            let len = try_ready!(self.decryptor
+                                     .poll_skip_packet(cx,
+                                                       &mut self.inner,
+                                                       &self.key,
+                                                       &mut self.nonce));
+            if len == 0 {
+                self.done = true;
+            } else {
+                self.verified.packets += 1;
+                self.verified.bytes += len as u64;
+            }
+        }
+        Ok(Ready(()))
+    }
+}
+
+impl<R: AsyncRead> Future for Verify<R> {
+    type Item = Verified;
+    type Error = VerifyError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Verified, VerifyError> {
+        match self.poll_verify(cx) {
+            Ok(Ready(())) => {}
+            Ok(Pending) => return Ok(Pending),
+            Err(e) => return Err(self.verified.fail(e)),
+        }
+
+        let mut trailing = [0u8; 1];
+        match self.inner.poll_read(cx, &mut trailing) {
+            Ok(Ready(0)) => Ok(Ready(self.verified)),
+            Ok(Ready(_)) => {
+                Err(VerifyError {
+                        offset: self.verified.offset() + CYPHER_HEADER_SIZE as u64,
+                        verified: self.verified,
+                        error: Error::new(ErrorKind::InvalidData, TRAILING_DATA),
+                    })
+            }
+            Ok(Pending) => Ok(Pending),
+            Err(e) => Err(self.verified.fail(e)),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+#!/usr/bin/env node
+// Replays vectors.json against pull-box-stream, the reference implementation of the protocol.
+//
+// Usage:
+//   cd test-data && npm install pull-stream pull-box-stream && node check-pull-box-stream.js
+//
+// For valid vectors, sealing the writes must yield the cypher, and decrypting the cypher must
+// yield the plaintext. For vectors with an error, decrypting the cypher must yield the plaintext
+// and then fail. pull-box-stream has its own error messages, so only the failure itself is
+// checked, not which error value of this crate it corresponds to. Exits with a nonzero status if
+// any vector disagrees.
+
+'use strict'
+
+var fs = require('fs')
+var path = require('path')
+var pull = require('pull-stream')
+var boxes = require('pull-box-stream')
+
+var corpus = JSON.parse(fs.readFileSync(path.join(__dirname, 'vectors.json'), 'utf8'))
+
+function hex (s) {
+  return Buffer.from(s, 'hex')
+}
+
+// Run `chunks` through `through`, and call `cb` with the error (null on a clean end) and the
+// concatenation of everything emitted before it.
+function run (chunks, through, cb) {
+  var out = []
+  pull(
+    pull.values(chunks),
+    through,
+    pull.drain(function (chunk) {
+      out.push(chunk)
+    }, function (err) {
+      cb(err || null, Buffer.concat(out))
+    })
+  )
+}
+
+// Describe how `actual` differs from `expected`.
+function difference (actual, expected) {
+  var i = 0
+  while (i < actual.length && i < expected.length && actual[i] === expected[i]) i++
+  return actual.length + ' bytes, expected ' + expected.length + ', first difference at ' + i
+}
+
+function check (vector, cb) {
+  var failures = []
+  var key = hex(vector.key)
+  var cypher = hex(vector.cypher)
+  var plaintext = hex(vector.plaintext)
+
+  function unbox () {
+    run([cypher], boxes.createUnboxStream(key, hex(vector.nonce)), function (err, plain) {
+      if (!plain.equals(plaintext)) {
+        failures.push('decrypted ' + difference(plain, plaintext))
+      }
+      if (vector.error === null && err) {
+        failures.push('decrypting failed: ' + (err.message || err))
+      }
+      if (vector.error !== null && !err) {
+        failures.push('decrypting succeeded, expected ' + vector.error)
+      }
+      cb(failures)
+    })
+  }
+
+  if (vector.error !== null) return unbox()
+
+  var writes = vector.writes.map(hex)
+  run(writes, boxes.createBoxStream(key, hex(vector.nonce)), function (err, sealed) {
+    if (err) {
+      failures.push('sealing failed: ' + (err.message || err))
+    } else if (!sealed.equals(cypher)) {
+      failures.push('sealed ' + difference(sealed, cypher))
+    }
+    unbox()
+  })
+}
+
+var failed = 0
+var vectors = corpus.vectors.slice()
+
+;(function next () {
+  var vector = vectors.shift()
+  if (!vector) {
+    console.log(failed ? failed + ' of ' + corpus.vectors.length + ' vectors failed'
+                       : 'all ' + corpus.vectors.length + ' vectors passed')
+    process.exit(failed ? 1 : 0)
+  }
+  check(vector, function (failures) {
+    if (failures.length) {
+      failed++
+      failures.forEach(function (failure) {
+        console.log(vector.name + ': ' + failure)
+      })
+    }
+    next()
+  })
+})()
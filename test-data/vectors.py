@@ -0,0 +1,142 @@
+#!/usr/bin/env python3
+# Generates vectors.json from libsodium, independently of the Rust implementation.
+#
+# Usage: python3 vectors.py > vectors.json
+#
+# After regenerating the corpus, check it against pull-box-stream with check-pull-box-stream.js.
+
+import ctypes
+import ctypes.util
+import json
+
+sodium = ctypes.CDLL(ctypes.util.find_library('sodium'))
+assert sodium.sodium_init() >= 0
+
+MAX_PACKET_SIZE = 4096
+
+
+def secretbox(msg, nonce, key):
+    out = ctypes.create_string_buffer(len(msg) + 16)
+    assert sodium.crypto_secretbox_easy(out, msg, ctypes.c_ulonglong(len(msg)), nonce, key) == 0
+    return out.raw
+
+
+def inc(nonce, by=1):
+    n = int.from_bytes(nonce, 'big') + by
+    return (n % (1 << 192)).to_bytes(24, 'big')
+
+
+def seal_packet(plain, key, nonce, length=None):
+    body = secretbox(plain, inc(nonce), key)
+    mac, cypher_body = body[:16], body[16:]
+    length = len(plain) if length is None else length
+    header = secretbox(length.to_bytes(2, 'big') + mac, nonce, key)
+    return header + cypher_body, inc(nonce, 2)
+
+
+def seal(writes, key, nonce):
+    packets = []
+    for write in writes:
+        for i in range(0, len(write), MAX_PACKET_SIZE):
+            packet, nonce = seal_packet(write[i:i + MAX_PACKET_SIZE], key, nonce)
+            packets.append(packet)
+    packets.append(secretbox(bytes(18), nonce, key))
+    return packets
+
+
+KEY = bytes(range(1, 33))
+NONCE = bytes(range(100, 124))
+vectors = []
+
+
+def valid(name, description, writes, nonce=NONCE):
+    vectors.append({
+        'name': name,
+        'description': description,
+        'key': KEY.hex(),
+        'nonce': nonce.hex(),
+        'writes': [w.hex() for w in writes],
+        'cypher': b''.join(seal(writes, KEY, nonce)).hex(),
+        'plaintext': b''.join(writes).hex(),
+        'error': None,
+    })
+
+
+def invalid(name, description, cypher, plaintext, error, key=KEY):
+    vectors.append({
+        'name': name,
+        'description': description,
+        'key': key.hex(),
+        'nonce': NONCE.hex(),
+        'writes': [],
+        'cypher': cypher.hex(),
+        'plaintext': plaintext.hex(),
+        'error': error,
+    })
+
+
+def flip(data, offset, bit=0):
+    data = bytearray(data)
+    data[offset] ^= 1 << bit
+    return bytes(data)
+
+
+pattern = lambda n: bytes(i % 251 for i in range(n))
+
+valid('goodbye', 'a stream without any data, consisting only of the final header', [])
+valid('single byte', 'a single packet of one byte', [b'\x00'])
+valid('single packet', 'a single packet', [b'hello world'])
+valid('full packet', 'a single packet of the maximum size', [pattern(4096)])
+valid('packet boundary', 'a write that is one byte longer than a packet', [pattern(4097)])
+valid('multiple writes', 'every write is sealed in its own packet', [b'hello', b' ', b'world'])
+valid('multiple packets', 'a write that is split into three packets', [pattern(10000)])
+valid('nonce carry', 'incrementing the nonce carries into higher bytes',
+      [b'a', b'b', b'c'], nonce=bytes(21) + b'\xff\xff\xfe')
+valid('nonce wraparound', 'the nonce wraps around to zero', [b'a', b'b'],
+      nonce=b'\xff' * 24)
+
+packets = seal([b'hello', b' ', b'world'], KEY, NONCE)
+stream = b''.join(packets)
+# Offsets of the second and the final header.
+second = len(packets[0])
+last = len(stream) - 34
+
+invalid('flipped header bit', 'a bit of the first header is flipped',
+        flip(stream, 0), b'', 'UNAUTHENTICATED_HEADER')
+invalid('flipped header mac bit', 'a bit of the encrypted packet mac in the first header is flipped',
+        flip(stream, 33, 7), b'', 'UNAUTHENTICATED_HEADER')
+invalid('flipped packet bit', 'a bit of the first packet body is flipped',
+        flip(stream, 34, 3), b'', 'UNAUTHENTICATED_PACKET')
+invalid('flipped second header bit', 'the first packet is delivered before the second header fails',
+        flip(stream, second + 5), b'hello', 'UNAUTHENTICATED_HEADER')
+invalid('flipped final header bit', 'all packets are delivered before the final header fails',
+        flip(stream, last + 20, 1), b'hello world', 'UNAUTHENTICATED_HEADER')
+invalid('reordered packets', 'the second and third packets are swapped',
+        packets[0] + packets[2] + packets[1] + packets[3], b'hello', 'UNAUTHENTICATED_HEADER')
+invalid('wrong key', 'the stream is decrypted with a different key',
+        stream, b'', 'UNAUTHENTICATED_HEADER', key=bytes(32))
+invalid('empty', 'a stream without a final header or any data', b'', b'', 'UNAUTHENTICATED_EOF')
+invalid('missing goodbye', 'the final header is missing',
+        stream[:last], b'hello world', 'UNAUTHENTICATED_EOF')
+invalid('truncated header', 'the stream ends in the middle of a header',
+        stream[:second + 10], b'hello', 'UNAUTHENTICATED_EOF')
+invalid('truncated packet', 'the stream ends in the middle of a packet body',
+        stream[:36], b'', 'UNAUTHENTICATED_EOF')
+invalid('trailing data', 'the stream continues after the final header',
+        stream + b'\x00', b'hello world', 'TRAILING_DATA')
+
+packet, nonce = seal_packet(b'hello', KEY, NONCE, length=0)
+invalid('zero length', 'an authenticated header that is not the final header claims a length of 0',
+        packet + secretbox(bytes(18), nonce, KEY), b'', 'INVALID_LENGTH')
+packet, nonce = seal_packet(pattern(4097), KEY, NONCE)
+invalid('oversized packet', 'an authenticated header claims a length of 4097',
+        packet + secretbox(bytes(18), nonce, KEY), b'', 'INVALID_LENGTH')
+
+print(json.dumps({
+    'description': 'Test vectors for the box-stream protocol. '
+                   'All byte strings are hex encoded. Each write is sealed into packets of at '
+                   'most 4096 bytes, the stream ends with the final header. Decrypting "cypher" '
+                   'yields "plaintext", then fails with "error" unless it is null. Vectors '
+                   'with an error have no writes.',
+    'vectors': vectors,
+}, indent=2))